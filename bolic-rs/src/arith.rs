@@ -0,0 +1,18 @@
+//! 四則演算の桁あふれと除算の扱い
+//!
+//! 実装はruntime-rsにあり、whitespace-rs、starry-rsと共有する
+
+pub use runtime_rs::arith::{Arith, Division, Overflow};
+
+use crate::ast;
+
+impl From<&ast::BinOp> for runtime_rs::arith::BinOp {
+    fn from(op: &ast::BinOp) -> Self {
+        match op {
+            ast::BinOp::Add => Self::Add,
+            ast::BinOp::Sub => Self::Sub,
+            ast::BinOp::Mul => Self::Mul,
+            ast::BinOp::Div => Self::Div,
+        }
+    }
+}
@@ -31,12 +31,13 @@ pub enum Expr {
 impl Expr {
     pub fn binop(op: BinOp, l: Expr, r: Expr) -> Self {
         Self::BinOp {
-            op: op,
+            op,
             l: Box::new(l),
             r: Box::new(r),
         }
     }
 
+    #[cfg(test)]
     pub fn int(i: i64) -> Self {
         Self::Var(Variable::Int(i))
     }
@@ -76,7 +77,7 @@ pub enum Variable {
 impl Variable {
     pub fn assign(var: char, expr: Expr) -> Self {
         Self::Assign {
-            var: var,
+            var,
             expr: Box::new(expr),
         }
     }
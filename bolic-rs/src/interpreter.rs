@@ -1,11 +1,20 @@
 use std::{
     collections::HashMap,
     io::{self, BufWriter, Write},
+    time::Instant,
 };
 
 use anyhow::{Context, Result};
+use runtime_rs::io::encode_char;
 
-use crate::{ast::*, parser, token};
+use crate::{
+    arith::Arith,
+    ast::*,
+    limits::{LimitExceeded, Limits},
+    parser, token,
+};
+
+pub use runtime_rs::io::Encoding;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RetVal {
@@ -26,15 +35,48 @@ impl RetVal {
 pub struct Interpreter {
     // Bolicの変数はすべてグローバル変数
     sym_table: HashMap<char, i64>,
+    arith: Arith,
+    encoding: Encoding,
+    limits: Limits,
+    /// 評価した文と式の数
+    steps: u64,
+    /// 最初の文を評価した時刻
+    started: Option<Instant>,
+    /// 評価中の文と式の入れ子の深さ
+    depth: usize,
+    /// 出力したバイト数
+    written: u64,
 }
 
 impl Interpreter {
     pub fn new() -> Self {
         Self {
             sym_table: HashMap::new(),
+            arith: Arith::default(),
+            encoding: Encoding::default(),
+            limits: Limits::default(),
+            steps: 0,
+            started: None,
+            depth: 0,
+            written: 0,
         }
     }
 
+    pub fn with_arith(mut self, arith: Arith) -> Self {
+        self.arith = arith;
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     pub fn run(&mut self, code: &str) -> Result<()> {
         let tokens = token::lex(code)?;
         let ast = parser::parse(tokens)?;
@@ -43,7 +85,7 @@ impl Interpreter {
     }
 
     fn eval(&mut self, ast: &Ast) -> Result<()> {
-        self.e_stmts(&ast)?;
+        self.e_stmts(ast)?;
         Ok(())
     }
 
@@ -61,6 +103,13 @@ impl Interpreter {
     }
 
     fn e_stmt(&mut self, stmt: &Stmt) -> Result<RetVal> {
+        self.enter()?;
+        let res = self.e_stmt_inner(stmt);
+        self.depth -= 1;
+        res
+    }
+
+    fn e_stmt_inner(&mut self, stmt: &Stmt) -> Result<RetVal> {
         match stmt {
             Stmt::Expr(expr) => {
                 let res = self.e_expr(expr)?;
@@ -72,16 +121,18 @@ impl Interpreter {
             }
             Stmt::NumOut(expr) => {
                 let x = self.e_expr(expr)?.to_i()?.to_string();
+                self.count_output(x.len())?;
                 let mut writer = BufWriter::new(io::stdout());
-                writer.write(x.as_bytes())?;
+                writer.write_all(x.as_bytes())?;
                 writer.flush()?;
                 Ok(RetVal::Void)
             }
             Stmt::CharOut(expr) => {
                 let x = self.e_expr(expr)?.to_i()?;
+                let buf = encode_char(x, self.encoding)?;
+                self.count_output(buf.len())?;
                 let mut writer = BufWriter::new(io::stdout());
-                // ASCIIコードとみなす
-                writer.write(&[x as u8])?;
+                writer.write_all(&buf)?;
                 writer.flush()?;
                 Ok(RetVal::Void)
             }
@@ -89,6 +140,13 @@ impl Interpreter {
     }
 
     fn e_expr(&mut self, expr: &Expr) -> Result<RetVal> {
+        self.enter()?;
+        let res = self.e_expr_inner(expr);
+        self.depth -= 1;
+        res
+    }
+
+    fn e_expr_inner(&mut self, expr: &Expr) -> Result<RetVal> {
         match expr {
             Expr::Var(Variable::Int(i)) => Ok(RetVal::Int(*i)),
             Expr::Var(Variable::Var(var)) => self
@@ -98,7 +156,7 @@ impl Interpreter {
                     let msg = format!("interpreter error: <{}> is undelared variable.", var);
                     anyhow::anyhow!(msg)
                 })
-                .and_then(|value| Ok(RetVal::Int(*value))),
+                .map(|value| RetVal::Int(*value)),
             Expr::Var(Variable::Assign { var, expr }) => {
                 let value = self.e_expr(expr)?;
                 // 名前が重複する変数の場合は上書き
@@ -108,12 +166,7 @@ impl Interpreter {
             Expr::BinOp { op, l, r } => {
                 let l = self.e_expr(l)?.to_i()?;
                 let r = self.e_expr(r)?.to_i()?;
-                match op {
-                    BinOp::Add => Ok(RetVal::Int(l + r)),
-                    BinOp::Sub => Ok(RetVal::Int(l - r)),
-                    BinOp::Mul => Ok(RetVal::Int(l * r)),
-                    BinOp::Div => Ok(RetVal::Int(l / r)),
-                }
+                Ok(RetVal::Int(self.arith.apply_i64(op.into(), l, r)?))
             }
             Expr::If { cond, conseq, alt } => {
                 // 0: false, other num: true
@@ -134,6 +187,40 @@ impl Interpreter {
         }
     }
 
+    /// 文か式を1つ評価する前に上限を調べ、入れ子を1段深くする
+    /// 時刻の取得は重いので、実行時間は一定の数ごとに調べる
+    fn enter(&mut self) -> Result<()> {
+        self.steps += 1;
+        if let Some(max) = self.limits.steps {
+            if self.steps > max {
+                return Err(LimitExceeded::Steps(max).into());
+            }
+        }
+        if let Some(max) = self.limits.time {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if self.steps.is_multiple_of(1024) && started.elapsed() > max {
+                return Err(LimitExceeded::Time(max).into());
+            }
+        }
+        if let Some(max) = self.limits.depth {
+            if self.depth >= max {
+                return Err(LimitExceeded::Depth(max).into());
+            }
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn count_output(&mut self, len: usize) -> Result<()> {
+        if let Some(max) = self.limits.output {
+            if self.written + len as u64 > max {
+                return Err(LimitExceeded::Output(max).into());
+            }
+        }
+        self.written += len as u64;
+        Ok(())
+    }
+
     fn e_while(&mut self, wblock: &Stmt) -> Result<RetVal> {
         match wblock {
             Stmt::While { cond, body } => {
@@ -160,7 +247,8 @@ impl Interpreter {
 
 #[cfg(test)]
 mod tests {
-    use super::Interpreter;
+    use super::*;
+    use crate::arith::{Division, Overflow};
 
     #[test]
     fn assgin() {
@@ -181,4 +269,59 @@ mod tests {
         let expect = 3;
         assert_eq!(expect, *actual);
     }
+
+    fn exceed(code: &str, limits: Limits) -> LimitExceeded {
+        let err = Interpreter::new()
+            .with_limits(limits)
+            .run(code)
+            .unwrap_err();
+        *err.downcast_ref::<LimitExceeded>().unwrap()
+    }
+
+    #[test]
+    fn limits() {
+        let forever = "♺ ① ☞ ✩ ☜ ① ♘";
+        let limits = Limits {
+            steps: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(LimitExceeded::Steps(100), exceed(forever, limits));
+
+        let limits = Limits {
+            time: Some(std::time::Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        assert!(matches!(exceed(forever, limits), LimitExceeded::Time(_)));
+
+        let limits = Limits {
+            depth: Some(3),
+            ..Limits::default()
+        };
+        assert_eq!(LimitExceeded::Depth(3), exceed("✩ ☜ ① ＋ ② ＋ ③", limits));
+
+        // 出力する前に止まる
+        let limits = Limits {
+            output: Some(0),
+            ..Limits::default()
+        };
+        assert_eq!(LimitExceeded::Output(0), exceed("✍ ①⓪", limits));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let code = "✪ ☜ ⓪ − ⑦\n✩ ☜ ✪ ÷ ②";
+        // 既定は0方向の丸めで、導入前と同じ結果になる
+        let mut interpreter = Interpreter::new();
+        interpreter.run(code).unwrap();
+        assert_eq!(-3, interpreter.sym_table[&'✩']);
+        let mut interpreter = Interpreter::new().with_arith(Arith {
+            overflow: Overflow::Trap,
+            division: Division::Floor,
+        });
+        interpreter.run(code).unwrap();
+        assert_eq!(-4, interpreter.sym_table[&'✩']);
+
+        let err = Interpreter::new().run("✍ ① ÷ ⓪").unwrap_err();
+        assert_eq!("division by zero.", err.to_string());
+    }
 }
@@ -1,10 +1,18 @@
-use std::{fs, path::PathBuf};
+use std::{fs, path::PathBuf, process, time::Duration};
 
 use anyhow::Result;
 use clap::Clap;
 
+use crate::{
+    arith::{Arith, Division, Overflow},
+    interpreter::Encoding,
+    limits::{parse_timeout, LimitExceeded, Limits},
+};
+
+mod arith;
 mod ast;
 mod interpreter;
+mod limits;
 mod parser;
 mod token;
 
@@ -13,12 +21,71 @@ mod token;
 struct Opts {
     #[clap(name = "Bolic code file path")]
     src_path: PathBuf,
+    /// Character encoding of character output: utf-8 (Unicode code points) or bytes
+    #[clap(long, default_value = "utf-8")]
+    encoding: Encoding,
+    /// Behavior on arithmetic overflow: trap, wrap or saturate
+    #[clap(long, default_value = "trap")]
+    overflow: Overflow,
+    /// Rounding of division: truncate (toward zero) or floor (as in the book's Ruby)
+    #[clap(long, default_value = "truncate")]
+    division: Division,
+    /// Use the default limits for untrusted programs (each can be overridden)
+    #[clap(long)]
+    sandbox: bool,
+    /// Maximum number of evaluated statements and expressions
+    #[clap(long)]
+    max_steps: Option<u64>,
+    /// Maximum execution time in seconds
+    #[clap(long, parse(try_from_str = parse_timeout))]
+    timeout: Option<Duration>,
+    /// Maximum nesting depth of statements and expressions
+    #[clap(long)]
+    max_depth: Option<usize>,
+    /// Maximum number of output bytes
+    #[clap(long)]
+    max_output: Option<u64>,
+}
+
+impl Opts {
+    fn limits(&self) -> Limits {
+        let base = if self.sandbox {
+            Limits::sandbox()
+        } else {
+            Limits::default()
+        };
+        Limits {
+            steps: self.max_steps.or(base.steps),
+            time: self.timeout.or(base.time),
+            depth: self.max_depth.or(base.depth),
+            output: self.max_output.or(base.output),
+        }
+    }
+}
+
+/// 上限を超えた場合は種類ごとの終了コードで終える
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        let code = e
+            .downcast_ref::<LimitExceeded>()
+            .map_or(1, |limit| limit.exit_code());
+        process::exit(code);
+    }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let opts = Opts::parse();
+    let limits = opts.limits();
     let code = fs::read_to_string(opts.src_path)?;
-    let mut interpreter = interpreter::Interpreter::new();
+    let arith = Arith {
+        overflow: opts.overflow,
+        division: opts.division,
+    };
+    let mut interpreter = interpreter::Interpreter::new()
+        .with_arith(arith)
+        .with_encoding(opts.encoding)
+        .with_limits(limits);
     interpreter.run(&code)?;
 
     Ok(())
@@ -229,8 +229,7 @@ where
         let res = Expr::if_without_alt(cond, conseq);
         Ok(res)
     } else {
-        let msg = format!("the if block has not end token.");
-        Err(anyhow::anyhow!(msg))
+        Err(anyhow::anyhow!("the if block has not end token."))
     }
 }
 
@@ -458,10 +457,7 @@ mod tests {
         let body = Expr::binop(BinOp::Sub, cond.clone(), Expr::int(1));
         let body = Expr::Var(Variable::assign('✪', body));
         let body = vec![Stmt::Expr(body)];
-        let expect = Ast::Stmts(vec![Stmt::While {
-            cond: cond,
-            body: body,
-        }]);
+        let expect = Ast::Stmts(vec![Stmt::While { cond, body }]);
         assert_eq!(expect, ast);
     }
 }
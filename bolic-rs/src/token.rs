@@ -22,7 +22,7 @@ pub enum Token {
 }
 
 // 10はLFのASCIIコード
-pub static NUMBERS: Lazy<String> = Lazy::new(|| format!("⓪①②③④⑤⑥⑦⑧⑨⑩"));
+pub static NUMBERS: Lazy<String> = Lazy::new(|| "⓪①②③④⑤⑥⑦⑧⑨⑩".to_owned());
 
 pub fn lex(code: &str) -> Result<Vec<Token>> {
     let mut tokens = vec![];
@@ -0,0 +1,203 @@
+//! 四則演算の桁あふれと除算の扱い
+//!
+//! 除算は既定でRustの``/``、``%``と同じく0方向に丸める
+//! 本書のRubyによる参照実装と同じ床除算は``Division::Floor``で選ぶ
+
+use std::{fmt, str::FromStr};
+
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Mod => "%",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/// 結果がi64に収まらない場合の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// 実行時エラーにする
+    #[default]
+    Trap,
+    /// 2の補数で折り返す
+    Wrap,
+    /// i64の最大値・最小値に丸める
+    Saturate,
+}
+
+impl FromStr for Overflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "trap" => Ok(Self::Trap),
+            "wrap" => Ok(Self::Wrap),
+            "saturate" => Ok(Self::Saturate),
+            _ => Err(anyhow::anyhow!(
+                "unknown overflow policy: {}. expected trap, wrap or saturate.",
+                s
+            )),
+        }
+    }
+}
+
+/// 割り切れない除算の丸め方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Division {
+    /// 負の無限大方向（Ruby、Haskellと同じ）。剰余は除数と同じ符号になる
+    Floor,
+    /// 0方向（Rust、Cと同じ）。剰余は被除数と同じ符号になる
+    #[default]
+    Truncate,
+}
+
+impl FromStr for Division {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "floor" => Ok(Self::Floor),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(anyhow::anyhow!(
+                "unknown division mode: {}. expected floor or truncate.",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Arith {
+    pub overflow: Overflow,
+    pub division: Division,
+}
+
+impl Arith {
+    /// 0での除算は方針によらずエラーにする
+    pub fn apply_i64(&self, op: BinOp, l: i64, r: i64) -> Result<i64> {
+        let (checked, wrapping, saturating) = match op {
+            BinOp::Add => (l.checked_add(r), l.wrapping_add(r), l.saturating_add(r)),
+            BinOp::Sub => (l.checked_sub(r), l.wrapping_sub(r), l.saturating_sub(r)),
+            BinOp::Mul => (l.checked_mul(r), l.wrapping_mul(r), l.saturating_mul(r)),
+            BinOp::Div => {
+                if r == 0 {
+                    return Err(anyhow::anyhow!("division by zero."));
+                }
+                // i64::MIN / -1 だけが桁あふれする
+                let q = (l.checked_div(r), l.wrapping_div(r), l.saturating_div(r));
+                if self.division == Division::Floor && (l < 0) != (r < 0) && l % r != 0 {
+                    // 符号が異なるなら桁あふれは起きていない
+                    return Ok(q.1 - 1);
+                }
+                q
+            }
+            BinOp::Mod => {
+                if r == 0 {
+                    return Err(anyhow::anyhow!("division by zero."));
+                }
+                // i64::MIN % -1 は0で、桁あふれしない
+                let m = l.wrapping_rem(r);
+                if self.division == Division::Floor && m != 0 && (m < 0) != (r < 0) {
+                    return Ok(m + r);
+                }
+                return Ok(m);
+            }
+        };
+        match self.overflow {
+            Overflow::Trap => {
+                checked.ok_or_else(|| anyhow::anyhow!("arithmetic overflow: {} {} {}.", l, op, r))
+            }
+            Overflow::Wrap => Ok(wrapping),
+            Overflow::Saturate => Ok(saturating),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(overflow: Overflow, division: Division, op: BinOp, l: i64, r: i64) -> Result<i64> {
+        Arith { overflow, division }.apply_i64(op, l, r)
+    }
+
+    #[test]
+    fn division() {
+        let cases = [
+            (7, 2, (3, 1), (3, 1)),
+            (-7, 2, (-4, 1), (-3, -1)),
+            (7, -2, (-4, -1), (-3, 1)),
+            (-7, -2, (3, -1), (3, -1)),
+            (-8, 2, (-4, 0), (-4, 0)),
+        ];
+        for (l, r, floor, trunc) in cases.iter() {
+            let f = |division, op| apply(Overflow::Trap, division, op, *l, *r).unwrap();
+            assert_eq!(
+                *floor,
+                (
+                    f(Division::Floor, BinOp::Div),
+                    f(Division::Floor, BinOp::Mod)
+                )
+            );
+            assert_eq!(
+                *trunc,
+                (
+                    f(Division::Truncate, BinOp::Div),
+                    f(Division::Truncate, BinOp::Mod)
+                )
+            );
+        }
+        let err = apply(Overflow::Wrap, Division::Floor, BinOp::Mod, 1, 0).unwrap_err();
+        assert_eq!("division by zero.", err.to_string());
+    }
+
+    #[test]
+    fn overflow() {
+        let f = |overflow, op, l, r| apply(overflow, Division::Floor, op, l, r);
+        let err = f(Overflow::Trap, BinOp::Add, i64::MAX, 1).unwrap_err();
+        assert_eq!(
+            "arithmetic overflow: 9223372036854775807 + 1.",
+            err.to_string()
+        );
+        assert_eq!(
+            i64::MIN,
+            f(Overflow::Wrap, BinOp::Add, i64::MAX, 1).unwrap()
+        );
+        assert_eq!(
+            i64::MAX,
+            f(Overflow::Saturate, BinOp::Mul, i64::MAX, 2).unwrap()
+        );
+        assert!(f(Overflow::Trap, BinOp::Div, i64::MIN, -1).is_err());
+        assert_eq!(
+            i64::MIN,
+            f(Overflow::Wrap, BinOp::Div, i64::MIN, -1).unwrap()
+        );
+        assert_eq!(
+            i64::MAX,
+            f(Overflow::Saturate, BinOp::Div, i64::MIN, -1).unwrap()
+        );
+        assert_eq!(0, f(Overflow::Trap, BinOp::Mod, i64::MIN, -1).unwrap());
+        assert!(f(Overflow::Trap, BinOp::Mul, i64::MAX, 2).is_err());
+        assert_eq!(-2, f(Overflow::Wrap, BinOp::Mul, i64::MAX, 2).unwrap());
+        assert_eq!(
+            i64::MIN,
+            f(Overflow::Saturate, BinOp::Sub, i64::MIN, 1).unwrap()
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! 文字と数値の入出力
+//!
+//! 文字入力と数値入力は同じ``BufRead``から読むので、読み残しはそのまま次の入力になる
+
+use std::{
+    convert::TryFrom,
+    io::{self, BufRead},
+    str::FromStr,
+};
+
+use anyhow::Result;
+
+/// 入力が尽きた後の文字入力・数値入力の動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eof {
+    /// -1を読んだことにする
+    MinusOne,
+    /// 0を読んだことにする
+    Zero,
+    /// 何も読まなかったことにする（スタックにもヒープにも書かない）
+    Keep,
+    /// 実行時エラーにする
+    #[default]
+    Error,
+}
+
+impl FromStr for Eof {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "minus-one" | "-1" => Ok(Self::MinusOne),
+            "zero" | "0" => Ok(Self::Zero),
+            "keep" => Ok(Self::Keep),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow::anyhow!(
+                "unknown EOF policy: {}. expected minus-one, zero, keep or error.",
+                s
+            )),
+        }
+    }
+}
+
+/// 文字入出力で扱う文字の表し方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Unicodeのコードポイント。入出力はUTF-8
+    #[default]
+    Utf8,
+    /// 1バイトをそのまま読み書きする。出力は下位8bitに切り捨てる
+    Bytes,
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utf-8" | "utf8" => Ok(Self::Utf8),
+            "bytes" | "byte" => Ok(Self::Bytes),
+            _ => Err(anyhow::anyhow!(
+                "unknown encoding: {}. expected utf-8 or bytes.",
+                s
+            )),
+        }
+    }
+}
+
+/// 1文字分のバイト列を読む。入力が尽きていれば空
+/// トレースなどに読んだバイト列をそのまま残せるよう、コードポイントにするのは``decode_char``で行う
+pub fn read_char<R: BufRead>(reader: &mut R, encoding: Encoding) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    let b = match reader.fill_buf()?.first().copied() {
+        Some(b) => b,
+        None => return Ok(buf),
+    };
+    reader.consume(1);
+    buf.push(b);
+    if encoding == Encoding::Utf8 {
+        // 続きのバイトでなければ読まずに残し、次の文字にする
+        for _ in 1..utf8_len(b) {
+            match reader.fill_buf()?.first() {
+                Some(b) if b & 0xc0 == 0x80 => buf.push(*b),
+                _ => break,
+            }
+            reader.consume(1);
+        }
+    }
+    Ok(buf)
+}
+
+/// ``read_char``で読んだバイト列のコードポイント。空ならNone
+pub fn decode_char(buf: &[u8], encoding: Encoding) -> Result<Option<u32>> {
+    match (encoding, buf) {
+        (_, []) => Ok(None),
+        (Encoding::Bytes, [b]) => Ok(Some(*b as u32)),
+        _ => match std::str::from_utf8(buf) {
+            Ok(s) => Ok(s.chars().next().map(u32::from)),
+            Err(_) => Err(anyhow::anyhow!("invalid UTF-8 input: {:02x?}", buf)),
+        },
+    }
+}
+
+/// 先頭のバイトから分かるUTF-8の1文字のバイト数。不正なバイトは1とする
+fn utf8_len(b: u8) -> usize {
+    match b {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => 1,
+    }
+}
+
+/// Unicodeのスカラー値でなければ実行時エラー
+pub fn to_char(n: i64) -> Result<char> {
+    u32::try_from(n)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| anyhow::anyhow!("invalid character code: {}.", n))
+}
+
+/// 文字出力するバイト列
+pub fn encode_char(n: i64, encoding: Encoding) -> Result<Vec<u8>> {
+    match encoding {
+        Encoding::Utf8 => Ok(to_char(n)?.to_string().into_bytes()),
+        Encoding::Bytes => Ok(vec![n as u8]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read() {
+        // 不正なバイトと途中で切れた文字は1文字ずつエラーにし、続きは次の文字として読む
+        let mut input = "あa".as_bytes().to_vec();
+        input.extend_from_slice(&[0xff, 0xe3, 0x81, b'b']);
+        let mut reader = &input[..];
+        let mut chars = vec![];
+        loop {
+            let buf = read_char(&mut reader, Encoding::Utf8).unwrap();
+            if buf.is_empty() {
+                break;
+            }
+            chars.push(decode_char(&buf, Encoding::Utf8).ok().flatten());
+        }
+        let expect = vec![Some(0x3042), Some(0x61), None, None, Some(0x62)];
+        assert_eq!(expect, chars);
+
+        let mut reader = "あ".as_bytes();
+        let buf = read_char(&mut reader, Encoding::Bytes).unwrap();
+        assert_eq!(Some(0xe3), decode_char(&buf, Encoding::Bytes).unwrap());
+        let err = decode_char(&[0xff], Encoding::Utf8).unwrap_err();
+        assert_eq!("invalid UTF-8 input: [ff]", err.to_string());
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            "あ".as_bytes(),
+            &encode_char(0x3042, Encoding::Utf8).unwrap()[..]
+        );
+        assert_eq!(vec![0x42], encode_char(0x3042, Encoding::Bytes).unwrap());
+        let err = encode_char(-1, Encoding::Utf8).unwrap_err();
+        assert_eq!("invalid character code: -1.", err.to_string());
+        assert!(encode_char(0xd800, Encoding::Utf8).is_err());
+    }
+}
@@ -0,0 +1,7 @@
+//! whitespace-rs、starry-rs、bolic-rsで共有する実行時の部品
+//!
+//! 言語ごとに挙動を変えないものだけを置く
+
+pub mod arith;
+pub mod io;
+pub mod limits;
@@ -0,0 +1,91 @@
+//! 実行の上限を超えたときのエラー
+//!
+//! 上限そのもの（``Limits``）は言語ごとに項目が異なるので各クレートで定義する
+
+use std::{fmt, time::Duration};
+
+use anyhow::Result;
+
+/// 上限を超えたときのエラー
+/// 種類ごとに終了コードを分け、どの言語でも同じ種類には同じ終了コードを返す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Steps(u64),
+    Time(Duration),
+    Stack(usize),
+    CallStack(usize),
+    Heap(usize),
+    Output(u64),
+    /// 文と式の入れ子の深さ（呼び出しの深さと同じ終了コード）
+    Depth(usize),
+}
+
+impl LimitExceeded {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Steps(_) => 10,
+            Self::Time(_) => 11,
+            Self::Stack(_) => 12,
+            Self::CallStack(_) | Self::Depth(_) => 13,
+            Self::Heap(_) => 14,
+            Self::Output(_) => 15,
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Steps(n) => write!(f, "step limit exceeded: {} steps.", n),
+            Self::Time(d) => write!(f, "time limit exceeded: {:?}.", d),
+            Self::Stack(n) => write!(f, "stack limit exceeded: {} items.", n),
+            Self::CallStack(n) => write!(f, "call stack limit exceeded: {} calls.", n),
+            Self::Heap(n) => write!(f, "heap limit exceeded: {} addresses.", n),
+            Self::Output(n) => write!(f, "output limit exceeded: {} bytes.", n),
+            Self::Depth(n) => write!(f, "nesting depth limit exceeded: {} levels.", n),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// ``--timeout``の秒数を読む
+/// 負の数やNaNはDurationにできないのでエラーにする
+pub fn parse_timeout(s: &str) -> Result<Duration> {
+    let secs: f64 = s
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid timeout: {}. expected seconds.", s))?;
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| anyhow::anyhow!("invalid timeout: {}. expected non-negative seconds.", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_code() {
+        let d = Duration::from_secs(1);
+        assert_eq!(10, LimitExceeded::Steps(0).exit_code());
+        assert_eq!(11, LimitExceeded::Time(d).exit_code());
+        assert_eq!(12, LimitExceeded::Stack(0).exit_code());
+        assert_eq!(13, LimitExceeded::CallStack(0).exit_code());
+        assert_eq!(13, LimitExceeded::Depth(0).exit_code());
+        assert_eq!(14, LimitExceeded::Heap(0).exit_code());
+        assert_eq!(15, LimitExceeded::Output(0).exit_code());
+    }
+
+    #[test]
+    fn timeout() {
+        assert_eq!(Duration::from_millis(1500), parse_timeout("1.5").unwrap());
+        assert_eq!(Duration::ZERO, parse_timeout("0").unwrap());
+        let err = parse_timeout("-1").unwrap_err();
+        assert_eq!(
+            "invalid timeout: -1. expected non-negative seconds.",
+            err.to_string()
+        );
+        assert!(parse_timeout("NaN").is_err());
+        assert!(parse_timeout("inf").is_err());
+        assert!(parse_timeout("1s").is_err());
+    }
+}
@@ -0,0 +1,5 @@
+//! 四則演算の桁あふれと除算の扱い
+//!
+//! 実装はruntime-rsにあり、whitespace-rs、bolic-rsと共有する
+
+pub use runtime_rs::arith::{Arith, BinOp, Division, Overflow};
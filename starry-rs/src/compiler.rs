@@ -7,7 +7,7 @@ pub struct Compiler {
     src_code: String,
 }
 
-static OP_CALC: &'static [Instruction] = &[
+static OP_CALC: &[Instruction] = &[
     Instruction::Add,
     Instruction::Sub,
     Instruction::Mul,
@@ -15,11 +15,11 @@ static OP_CALC: &'static [Instruction] = &[
     Instruction::Mod,
 ];
 
-static OP_OUTPUT: &'static [Instruction] = &[Instruction::NumOut, Instruction::CharOut];
+static OP_OUTPUT: &[Instruction] = &[Instruction::NumOut, Instruction::CharOut];
 
-static OP_INPUT: &'static [Instruction] = &[Instruction::NumIn, Instruction::CharIn];
+static OP_INPUT: &[Instruction] = &[Instruction::NumIn, Instruction::CharIn];
 
-static OP_STACK: &'static [Instruction] = &[
+static OP_STACK: &[Instruction] = &[
     Instruction::Dummy,
     Instruction::Dup,
     Instruction::Swap,
@@ -29,7 +29,7 @@ static OP_STACK: &'static [Instruction] = &[
 
 impl Compiler {
     pub fn new(src_code: String) -> Self {
-        Self { src_code: src_code }
+        Self { src_code }
     }
 
     pub fn compile(&self) -> Result<Vec<Instruction>> {
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, Clone)]
 pub enum Instruction {
     Push(i64),
@@ -18,3 +20,27 @@ pub enum Instruction {
     JumpNonZero(i64),
     Dummy,
 }
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Push(x) => write!(f, "push {}", x),
+            Self::Dup => write!(f, "dup"),
+            Self::Swap => write!(f, "swap"),
+            Self::Rotate => write!(f, "rotate"),
+            Self::Pop => write!(f, "pop"),
+            Self::Add => write!(f, "add"),
+            Self::Sub => write!(f, "sub"),
+            Self::Mul => write!(f, "mul"),
+            Self::Div => write!(f, "div"),
+            Self::Mod => write!(f, "mod"),
+            Self::CharIn => write!(f, "charin"),
+            Self::CharOut => write!(f, "charout"),
+            Self::NumIn => write!(f, "numin"),
+            Self::NumOut => write!(f, "numout"),
+            Self::Label(l) => write!(f, "label {}", l),
+            Self::JumpNonZero(l) => write!(f, "jnz {}", l),
+            Self::Dummy => write!(f, "dummy"),
+        }
+    }
+}
@@ -1,12 +1,21 @@
-use std::{fs, path::PathBuf};
+use std::{fs, io, path::PathBuf, process, time::Duration};
 
-use crate::{compiler::Compiler, vm::VM};
-use anyhow::Result;
+use crate::{
+    arith::{Arith, Division, Overflow},
+    compiler::Compiler,
+    limits::{parse_timeout, LimitExceeded, Limits},
+    trace::Tracer,
+    vm::{Encoding, Eof, VM},
+};
+use anyhow::{Context, Result};
 use clap::Clap;
 
+mod arith;
 mod compiler;
 mod instruction;
+mod limits;
 mod token;
+mod trace;
 mod vm;
 
 #[derive(Debug, Clap)]
@@ -14,13 +23,88 @@ mod vm;
 struct Opts {
     #[clap(name = "Starry code file path")]
     src_path: PathBuf,
+    /// Behavior of input commands at the end of input: minus-one, zero, keep or error
+    #[clap(long, default_value = "error")]
+    eof: Eof,
+    /// Character encoding of character input/output: utf-8 (Unicode code points) or bytes
+    #[clap(long, default_value = "utf-8")]
+    encoding: Encoding,
+    /// Behavior on arithmetic overflow: trap, wrap or saturate
+    #[clap(long, default_value = "trap")]
+    overflow: Overflow,
+    /// Rounding of division and sign of modulo: truncate (toward zero) or floor (as in the book's Ruby)
+    #[clap(long, default_value = "truncate")]
+    division: Division,
+    /// Use the default limits for untrusted programs (each can be overridden)
+    #[clap(long)]
+    sandbox: bool,
+    /// Maximum number of executed instructions
+    #[clap(long)]
+    max_steps: Option<u64>,
+    /// Maximum execution time in seconds
+    #[clap(long, parse(try_from_str = parse_timeout))]
+    timeout: Option<Duration>,
+    /// Maximum number of items on the stack
+    #[clap(long)]
+    max_stack: Option<usize>,
+    /// Maximum number of output bytes
+    #[clap(long)]
+    max_output: Option<u64>,
+    /// Write one JSON object per executed instruction to this file
+    #[clap(long)]
+    trace: Option<PathBuf>,
+    /// Number of stack items from the top to include in each trace record (default: all)
+    #[clap(long)]
+    trace_stack: Option<usize>,
 }
 
-fn main() -> Result<()> {
+impl Opts {
+    fn limits(&self) -> Limits {
+        let base = if self.sandbox {
+            Limits::sandbox()
+        } else {
+            Limits::default()
+        };
+        Limits {
+            steps: self.max_steps.or(base.steps),
+            time: self.timeout.or(base.time),
+            stack: self.max_stack.or(base.stack),
+            output: self.max_output.or(base.output),
+        }
+    }
+}
+
+/// 上限を超えた場合は種類ごとの終了コードで終える
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        let code = e
+            .downcast_ref::<LimitExceeded>()
+            .map_or(1, |limit| limit.exit_code());
+        process::exit(code);
+    }
+}
+
+fn run() -> Result<()> {
     let opts = Opts::parse();
-    let code = fs::read_to_string(opts.src_path)?;
+    let limits = opts.limits();
+    let code = fs::read_to_string(&opts.src_path)?;
     let insts = Compiler::new(code).compile()?;
-    VM::new(insts)?.run()?;
+    let stdin = io::stdin();
+    let mut vm = VM::new(insts, stdin.lock(), io::stdout())?
+        .with_eof(opts.eof)
+        .with_encoding(opts.encoding)
+        .with_arith(Arith {
+            overflow: opts.overflow,
+            division: opts.division,
+        })
+        .with_limits(limits);
+    if let Some(path) = &opts.trace {
+        let file = fs::File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        vm = vm.with_trace(Tracer::new(file, opts.trace_stack));
+    }
+    vm.run()?;
 
     Ok(())
 }
@@ -0,0 +1,169 @@
+//! 実行した命令を1行に1つのJSONで書き出す（JSON Lines）
+//!
+//! ```text
+//! {"step":1,"pc":0,"inst":"push 72","depth":1,"stack":[72]}
+//! {"step":2,"pc":1,"inst":"charout","depth":0,"stack":[],"io":{"out":"H"}}
+//! ```
+//!
+//! - stack: 実行後のスタック（底から順）。上からの個数を指定した場合はその分だけ
+//! - depth: 実行後のスタックの要素数
+//! - io: 入出力した場合のみ``{"out":".."}``、``{"in":".."}``、入力が尽きていれば``{"eof":true}``
+//! - error: 実行時エラーになった場合のみ
+//!
+//! 実行しながら書き出すので、長く動くプログラムでもメモリに溜めない
+
+use std::io::{self, BufWriter, Write};
+
+use crate::instruction::Instruction;
+
+pub struct Tracer {
+    writer: BufWriter<Box<dyn Write>>,
+    /// スタックを上から何個書き出すか。Noneなら全部
+    top: Option<usize>,
+    /// 実行中の命令で起きた入出力
+    io: Option<Io>,
+}
+
+enum Io {
+    Out(Vec<u8>),
+    In(Vec<u8>),
+    Eof,
+}
+
+impl Tracer {
+    pub fn new(writer: impl Write + 'static, top: Option<usize>) -> Self {
+        Self {
+            writer: BufWriter::new(Box::new(writer)),
+            top,
+            io: None,
+        }
+    }
+
+    pub fn output(&mut self, buf: &[u8]) {
+        self.io = Some(Io::Out(buf.to_vec()));
+    }
+
+    /// Noneなら入力が尽きている
+    pub fn input(&mut self, buf: Option<&[u8]>) {
+        self.io = Some(buf.map_or(Io::Eof, |buf| Io::In(buf.to_vec())));
+    }
+
+    /// 1命令分の記録を書き出す
+    pub fn record(
+        &mut self,
+        step: u64,
+        pc: usize,
+        inst: &Instruction,
+        stack: &[i64],
+        error: Option<&anyhow::Error>,
+    ) -> io::Result<()> {
+        let skip = self.top.map_or(0, |top| stack.len().saturating_sub(top));
+        let items: Vec<String> = stack[skip..].iter().map(|x| x.to_string()).collect();
+        let mut line = format!(
+            "{{\"step\":{},\"pc\":{},\"inst\":{},\"depth\":{},\"stack\":[{}]",
+            step,
+            pc,
+            string(&inst.to_string()),
+            stack.len(),
+            items.join(",")
+        );
+        match self.io.take() {
+            Some(Io::Out(buf)) => line += &format!(",\"io\":{{\"out\":{}}}", bytes(&buf)),
+            Some(Io::In(buf)) => line += &format!(",\"io\":{{\"in\":{}}}", bytes(&buf)),
+            Some(Io::Eof) => line += ",\"io\":{\"eof\":true}",
+            None => (),
+        }
+        if let Some(e) = error {
+            line += &format!(",\"error\":{}", string(&e.to_string()));
+        }
+        writeln!(self.writer, "{}}}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// UTF-8として不正なバイトは置き換える
+fn bytes(buf: &[u8]) -> String {
+    string(&String::from_utf8_lossy(buf))
+}
+
+/// JSONの文字列リテラル
+fn string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::vm::VM;
+
+    /// テストから中身を読めるよう共有する出力
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records() {
+        let insts = vec![
+            Instruction::CharIn,
+            Instruction::Label(1),
+            Instruction::Dup,
+            Instruction::CharOut,
+            Instruction::Push(0),
+            Instruction::JumpNonZero(1),
+            Instruction::Pop,
+            Instruction::Pop,
+        ];
+        let shared = Shared::default();
+        let mut output = vec![];
+        let err = VM::new(insts, "\n".as_bytes(), &mut output)
+            .unwrap()
+            .with_trace(Tracer::new(shared.clone(), Some(1)))
+            .run()
+            .unwrap_err();
+        assert_eq!("cannot pop from the empty stack.", err.to_string());
+
+        let buf = shared.0.borrow();
+        let lines: Vec<&str> = std::str::from_utf8(&buf).unwrap().lines().collect();
+        assert_eq!(
+            vec![
+                r#"{"step":1,"pc":0,"inst":"charin","depth":1,"stack":[10],"io":{"in":"\n"}}"#,
+                r#"{"step":2,"pc":1,"inst":"label 1","depth":1,"stack":[10]}"#,
+                r#"{"step":3,"pc":2,"inst":"dup","depth":2,"stack":[10]}"#,
+                r#"{"step":4,"pc":3,"inst":"charout","depth":1,"stack":[10],"io":{"out":"\n"}}"#,
+                r#"{"step":5,"pc":4,"inst":"push 0","depth":2,"stack":[0]}"#,
+                r#"{"step":6,"pc":5,"inst":"jnz 1","depth":1,"stack":[10]}"#,
+                r#"{"step":7,"pc":6,"inst":"pop","depth":0,"stack":[]}"#,
+                r#"{"step":8,"pc":7,"inst":"pop","depth":0,"stack":[],"error":"cannot pop from the empty stack."}"#,
+            ],
+            lines
+        );
+    }
+}
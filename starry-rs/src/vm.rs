@@ -1,160 +1,304 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
-    io::{self, BufWriter, Write},
+    io::{BufRead, BufWriter, Write},
+    time::Instant,
 };
 
 use anyhow::{Context, Result};
+use runtime_rs::io;
 
-use crate::instruction::Instruction;
+use crate::{
+    arith::{Arith, BinOp},
+    instruction::Instruction,
+    limits::{LimitExceeded, Limits},
+    trace::Tracer,
+};
+
+pub use runtime_rs::io::{Encoding, Eof};
 
-pub struct VM {
+pub struct VM<R: BufRead, W: Write> {
     insts: Vec<Instruction>,
     stack: Vec<i64>,
     labels: HashMap<i64, i64>,
+    eof: Eof,
+    encoding: Encoding,
+    arith: Arith,
+    limits: Limits,
+    /// 実行した命令数
+    steps: u64,
+    /// 最初の命令を実行した時刻
+    started: Option<Instant>,
+    /// 出力したバイト数
+    written: u64,
+    reader: R,
+    writer: BufWriter<W>,
+    trace: Option<Tracer>,
 }
 
-impl VM {
-    pub fn new(insts: Vec<Instruction>) -> Result<Self> {
+impl<R: BufRead, W: Write> VM<R, W> {
+    pub fn new(insts: Vec<Instruction>, input: R, output: W) -> Result<Self> {
         let labels = Self::find_labels(&insts)?;
         Ok(Self {
-            insts: insts,
+            insts,
             stack: vec![],
-            labels: labels,
+            labels,
+            eof: Eof::default(),
+            encoding: Encoding::default(),
+            arith: Arith::default(),
+            limits: Limits::default(),
+            steps: 0,
+            started: None,
+            written: 0,
+            reader: input,
+            writer: BufWriter::new(output),
+            trace: None,
         })
     }
 
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_arith(mut self, arith: Arith) -> Self {
+        self.arith = arith;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// 実行した命令を1つずつ書き出す
+    pub fn with_trace(mut self, trace: Tracer) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
     pub fn run(&mut self) -> Result<()> {
+        let res = self.exec();
+        // エラーで止まった場合もそれまでの出力は書き出しておく
+        self.writer.flush()?;
+        if let Some(trace) = &mut self.trace {
+            trace.flush()?;
+        }
+        res
+    }
+
+    fn exec(&mut self) -> Result<()> {
         let mut pc = 0;
         while pc < self.insts.len() {
-            match self.insts[pc] {
-                Instruction::Push(x) => {
-                    self.stack.push(x);
-                }
-                Instruction::Dup => {
-                    let x = self.stack[self.stack.len() - 1];
-                    self.stack.push(x);
-                }
-                Instruction::Swap => {
-                    // |-> x y
-                    // ↓
-                    // |-> y x
-                    let y = self.pop()?;
-                    let x = self.pop()?;
-                    self.stack.push(y);
-                    self.stack.push(x);
-                }
-                Instruction::Rotate => {
-                    // |-> x y z
-                    // ↓
-                    // |-> z x y
-                    let z = self.pop()?;
-                    let y = self.pop()?;
-                    let x = self.pop()?;
-                    self.stack.push(z);
-                    self.stack.push(x);
-                    self.stack.push(y);
-                }
-                Instruction::Pop => {
-                    let _ = self.pop()?;
-                }
-                Instruction::Add => {
-                    let y = self.pop()?;
-                    let x = self.pop()?;
-                    self.stack.push(x + y);
-                }
-                Instruction::Sub => {
-                    let y = self.pop()?;
-                    let x = self.pop()?;
-                    self.stack.push(x - y);
-                }
-                Instruction::Mul => {
-                    let y = self.pop()?;
-                    let x = self.pop()?;
-                    self.stack.push(x * y);
-                }
-                Instruction::Div => {
-                    let y = self.pop()?;
-                    let x = self.pop()?;
-                    self.stack.push(x / y);
-                }
-                Instruction::Mod => {
-                    let y = self.pop()?;
-                    let x = self.pop()?;
-                    self.stack.push(x % y);
-                }
-                Instruction::NumOut => {
-                    let x = self.pop()?;
-                    let x = x.to_string();
-                    let mut writer = BufWriter::new(io::stdout());
-                    writer.write(x.as_bytes())?;
-                    writer.flush()?;
-                }
-                Instruction::CharOut => {
-                    let x = self.pop()?;
-                    let mut writer = BufWriter::new(io::stdout());
-                    // ASCIIコードとみなす
-                    let x = x as u8;
-                    writer.write(&[x])?;
-                    writer.flush()?;
-                }
-                Instruction::NumIn => {
-                    let mut buf = String::new();
-                    io::stdin().read_line(&mut buf)?;
-                    let buf = buf.trim_end(); // 末尾の改行を除去
-                    let x = buf.parse()?;
-                    self.stack.push(x);
-                }
-                Instruction::CharIn => {
-                    let mut buf = String::new();
-                    io::stdin().read_line(&mut buf)?;
-                    let buf = buf.trim_end(); // 末尾の改行を除去
-                    let buf = buf.as_bytes();
-                    let x = buf[0] as i64;
+            self.tick()?;
+            let res = self.step(pc);
+            if let Some(trace) = &mut self.trace {
+                let error = res.as_ref().err();
+                trace.record(self.steps, pc, &self.insts[pc], &self.stack, error)?;
+            }
+            pc = res? + 1;
+        }
+
+        Ok(())
+    }
+
+    /// pcの命令を実行する。分岐した場合は分岐先のラベルの位置を返す
+    fn step(&mut self, mut pc: usize) -> Result<usize> {
+        match self.insts[pc] {
+            Instruction::Push(x) => {
+                self.reserve()?;
+                self.stack.push(x);
+            }
+            Instruction::Dup => {
+                self.reserve()?;
+                let x = self
+                    .stack
+                    .last()
+                    .copied()
+                    .context("cannot dup from the empty stack.")?;
+                self.stack.push(x);
+            }
+            Instruction::Swap => {
+                // |-> x y
+                // ↓
+                // |-> y x
+                let y = self.pop()?;
+                let x = self.pop()?;
+                self.stack.push(y);
+                self.stack.push(x);
+            }
+            Instruction::Rotate => {
+                // |-> x y z
+                // ↓
+                // |-> z x y
+                let z = self.pop()?;
+                let y = self.pop()?;
+                let x = self.pop()?;
+                self.stack.push(z);
+                self.stack.push(x);
+                self.stack.push(y);
+            }
+            Instruction::Pop => {
+                let _ = self.pop()?;
+            }
+            Instruction::Add => self.binop(BinOp::Add)?,
+            Instruction::Sub => self.binop(BinOp::Sub)?,
+            Instruction::Mul => self.binop(BinOp::Mul)?,
+            Instruction::Div => self.binop(BinOp::Div)?,
+            Instruction::Mod => self.binop(BinOp::Mod)?,
+            Instruction::NumOut => {
+                let x = self.pop()?;
+                self.write(x.to_string().as_bytes())?;
+            }
+            Instruction::CharOut => {
+                let x = self.pop()?;
+                self.write(&io::encode_char(x, self.encoding)?)?;
+            }
+            Instruction::NumIn => match self.read_line()? {
+                Some(buf) => {
+                    // 前後の空白と末尾の改行を除去
+                    let buf = buf.trim();
+                    let x = buf
+                        .parse()
+                        .with_context(|| format!("invalid number input: {:?}", buf))?;
+                    self.reserve()?;
                     self.stack.push(x);
                 }
-                // ラベルの位置はすでに調べているので何もしない
-                Instruction::Label(_) => (),
-                Instruction::JumpNonZero(label) => {
-                    let x = self.pop()?;
-                    if x != 0 {
-                        pc = self.resolve_label(label)?;
-                    }
+                None => self.push_eof()?,
+            },
+            Instruction::CharIn => match self.read_char()? {
+                Some(c) => {
+                    self.reserve()?;
+                    self.stack.push(c as i64);
                 }
-                Instruction::Dummy => {
-                    return Err(anyhow::anyhow!("dummy instruction."));
+                None => self.push_eof()?,
+            },
+            // ラベルの位置はすでに調べているので何もしない
+            Instruction::Label(_) => (),
+            Instruction::JumpNonZero(label) => {
+                let x = self.pop()?;
+                if x != 0 {
+                    pc = self.resolve_label(label)?;
                 }
             }
-
-            pc += 1;
+            Instruction::Dummy => {
+                return Err(anyhow::anyhow!("dummy instruction."));
+            }
         }
 
-        Ok(())
+        Ok(pc)
     }
 
-    fn find_labels(insts: &Vec<Instruction>) -> Result<HashMap<i64, i64>> {
+    fn find_labels(insts: &[Instruction]) -> Result<HashMap<i64, i64>> {
         let mut labels = HashMap::new();
         for (i, inst) in insts.iter().enumerate() {
-            match inst {
-                Instruction::Label(label) => {
-                    let label = *label;
-                    let e = labels.entry(label);
-                    match e {
-                        Entry::Occupied(_) => {
-                            let msg = format!("label <{}> is duplicate.", label);
-                            return Err(anyhow::anyhow!(msg));
-                        }
-                        Entry::Vacant(_) => {
-                            e.or_insert(i as i64);
-                        }
+            if let Instruction::Label(label) = inst {
+                let label = *label;
+                let e = labels.entry(label);
+                match e {
+                    Entry::Occupied(_) => {
+                        let msg = format!("label <{}> is duplicate.", label);
+                        return Err(anyhow::anyhow!(msg));
+                    }
+                    Entry::Vacant(_) => {
+                        e.or_insert(i as i64);
                     }
                 }
-                _ => (),
             }
         }
 
         Ok(labels)
     }
 
+    /// 1文字読んでコードポイントを返す。入力が尽きていればNone
+    /// 入力を促す出力が見えるよう、読み込む前に書き出しておく
+    fn read_char(&mut self) -> Result<Option<u32>> {
+        self.writer.flush()?;
+        let buf = io::read_char(&mut self.reader, self.encoding)?;
+        if let Some(trace) = &mut self.trace {
+            trace.input(if buf.is_empty() { None } else { Some(&buf) });
+        }
+        io::decode_char(&buf, self.encoding)
+    }
+
+    /// 改行まで読む。CharInと同じ入力から読むので、読み残しはそのまま次の入力になる
+    fn read_line(&mut self) -> Result<Option<String>> {
+        self.writer.flush()?;
+        let mut buf = String::new();
+        let eof = self.reader.read_line(&mut buf)? == 0;
+        if let Some(trace) = &mut self.trace {
+            trace.input(if eof { None } else { Some(buf.as_bytes()) });
+        }
+        Ok(if eof { None } else { Some(buf) })
+    }
+
+    fn push_eof(&mut self) -> Result<()> {
+        if self.eof != Eof::Keep {
+            self.reserve()?;
+        }
+        match self.eof {
+            Eof::MinusOne => self.stack.push(-1),
+            Eof::Zero => self.stack.push(0),
+            Eof::Keep => (),
+            Eof::Error => return Err(anyhow::anyhow!("reached the end of input.")),
+        }
+        Ok(())
+    }
+
+    /// 命令数と実行時間の上限を調べる
+    /// 時刻の取得は重いので、実行時間は一定の命令数ごとに調べる
+    fn tick(&mut self) -> Result<()> {
+        self.steps += 1;
+        if let Some(max) = self.limits.steps {
+            if self.steps > max {
+                return Err(LimitExceeded::Steps(max).into());
+            }
+        }
+        if let Some(max) = self.limits.time {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if self.steps.is_multiple_of(1024) && started.elapsed() > max {
+                return Err(LimitExceeded::Time(max).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// スタックに1つ積めるか調べる
+    fn reserve(&self) -> Result<()> {
+        if let Some(max) = self.limits.stack {
+            if self.stack.len() >= max {
+                return Err(LimitExceeded::Stack(max).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(max) = self.limits.output {
+            if self.written + buf.len() as u64 > max {
+                return Err(LimitExceeded::Output(max).into());
+            }
+        }
+        self.written += buf.len() as u64;
+        self.writer.write_all(buf)?;
+        if let Some(trace) = &mut self.trace {
+            trace.output(buf);
+        }
+        Ok(())
+    }
+
+    fn binop(&mut self, op: BinOp) -> Result<()> {
+        let y = self.pop()?;
+        let x = self.pop()?;
+        let z = self.arith.apply_i64(op, x, y)?;
+        self.stack.push(z);
+        Ok(())
+    }
+
     fn pop(&mut self) -> Result<i64> {
         let x = self
             .stack
@@ -171,3 +315,208 @@ impl VM {
         Ok(*pc as usize)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::arith::{Division, Overflow};
+    use crate::compiler::Compiler;
+
+    #[test]
+    fn examples() {
+        let cases: HashMap<&str, (&str, &str)> = vec![
+            ("hello.sta", ("", "Hello, world!")),
+            ("fibn.sta", ("10\n", "1\n2\n3\n5\n8\n13\n21\n34\n55\n89\n")),
+        ]
+        .into_iter()
+        .collect();
+
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let (input, expect) = cases
+                .get(name)
+                .unwrap_or_else(|| panic!("no expected output for {}", name));
+
+            let code = fs::read_to_string(&path).unwrap();
+            let insts = Compiler::new(code).compile().unwrap();
+            let mut output = vec![];
+            VM::new(insts, input.as_bytes(), &mut output)
+                .unwrap()
+                .run()
+                .unwrap();
+            assert_eq!(*expect, String::from_utf8(output).unwrap(), "{}", name);
+        }
+    }
+
+    fn run(insts: Vec<Instruction>, input: &str, eof: Eof) -> Result<String> {
+        let mut output = vec![];
+        VM::new(insts, input.as_bytes(), &mut output)?
+            .with_eof(eof)
+            .run()?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn char_in() {
+        // 3文字読んで逆順に数値で出力する
+        let insts = vec![
+            Instruction::CharIn,
+            Instruction::CharIn,
+            Instruction::CharIn,
+            Instruction::NumOut,
+            Instruction::NumOut,
+            Instruction::NumOut,
+        ];
+        assert_eq!("101097", run(insts, "a\n\n", Eof::Error).unwrap());
+    }
+
+    #[test]
+    fn encodings() {
+        let run_with = |insts: Vec<Instruction>, input: &[u8], encoding| {
+            let mut output = vec![];
+            let res = VM::new(insts, input, &mut output)
+                .unwrap()
+                .with_encoding(encoding)
+                .run();
+            res.map(|_| output).map_err(|e| e.to_string())
+        };
+        // 2文字読んで、逆順に文字で出力する
+        let insts = vec![
+            Instruction::CharIn,
+            Instruction::CharIn,
+            Instruction::CharOut,
+            Instruction::CharOut,
+        ];
+        let input = "é𝄞".as_bytes();
+        assert_eq!(
+            Ok("𝄞é".as_bytes().to_vec()),
+            run_with(insts.clone(), input, Encoding::Utf8)
+        );
+        assert_eq!(
+            Ok(vec![0xa9, 0xc3]),
+            run_with(insts.clone(), input, Encoding::Bytes)
+        );
+        assert_eq!(
+            Err("invalid UTF-8 input: [ff]".to_owned()),
+            run_with(insts, &[0xff, b'a'], Encoding::Utf8)
+        );
+
+        let insts = vec![Instruction::Push(0x110000), Instruction::CharOut];
+        assert_eq!(
+            Err("invalid character code: 1114112.".to_owned()),
+            run_with(insts.clone(), b"", Encoding::Utf8)
+        );
+        assert_eq!(Ok(vec![0]), run_with(insts, b"", Encoding::Bytes));
+    }
+
+    #[test]
+    fn eof_policies() {
+        let insts = vec![
+            Instruction::Push(7),
+            Instruction::NumIn,
+            Instruction::CharIn,
+            Instruction::Add,
+            Instruction::NumOut,
+        ];
+        assert_eq!("-2", run(insts.clone(), "", Eof::MinusOne).unwrap());
+        assert_eq!("0", run(insts.clone(), "", Eof::Zero).unwrap());
+        assert_eq!("4", run(insts.clone(), "-3", Eof::Keep).unwrap());
+        let err = run(insts, "", Eof::Error).unwrap_err();
+        assert_eq!("reached the end of input.", err.to_string());
+    }
+
+    fn exceed(insts: Vec<Instruction>, limits: Limits) -> LimitExceeded {
+        let mut output = vec![];
+        let err = VM::new(insts, "".as_bytes(), &mut output)
+            .unwrap()
+            .with_limits(limits)
+            .run()
+            .unwrap_err();
+        *err.downcast_ref::<LimitExceeded>().unwrap()
+    }
+
+    #[test]
+    fn limits() {
+        // 1を積み続ける
+        let grow = vec![
+            Instruction::Label(0),
+            Instruction::Push(1),
+            Instruction::Dup,
+            Instruction::JumpNonZero(0),
+        ];
+        let limits = Limits {
+            steps: Some(10),
+            ..Limits::default()
+        };
+        assert_eq!(LimitExceeded::Steps(10), exceed(grow.clone(), limits));
+
+        let limits = Limits {
+            stack: Some(5),
+            ..Limits::default()
+        };
+        assert_eq!(LimitExceeded::Stack(5), exceed(grow.clone(), limits));
+
+        let limits = Limits {
+            time: Some(std::time::Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        let forever = vec![
+            Instruction::Label(0),
+            Instruction::Push(1),
+            Instruction::JumpNonZero(0),
+        ];
+        assert!(matches!(exceed(forever, limits), LimitExceeded::Time(_)));
+
+        let limits = Limits {
+            output: Some(3),
+            ..Limits::default()
+        };
+        let output = vec![
+            Instruction::Label(0),
+            Instruction::Push(42),
+            Instruction::NumOut,
+            Instruction::Push(1),
+            Instruction::JumpNonZero(0),
+        ];
+        assert_eq!(LimitExceeded::Output(3), exceed(output, limits));
+    }
+
+    #[test]
+    fn dup_empty() {
+        let err = run(vec![Instruction::Dup], "", Eof::Error).unwrap_err();
+        assert_eq!("cannot dup from the empty stack.", err.to_string());
+    }
+
+    #[test]
+    fn arithmetic() {
+        let insts = vec![
+            Instruction::Push(-7),
+            Instruction::Push(2),
+            Instruction::Div,
+            Instruction::NumOut,
+            Instruction::Push(i64::MAX),
+            Instruction::Push(1),
+            Instruction::Add,
+            Instruction::NumOut,
+        ];
+        let run = |arith| {
+            let mut output = vec![];
+            let res = VM::new(insts.clone(), "".as_bytes(), &mut output)
+                .unwrap()
+                .with_arith(arith)
+                .run();
+            (res.is_ok(), String::from_utf8(output).unwrap())
+        };
+        // 既定は0方向の丸めで、導入前と同じ結果になる
+        assert_eq!((false, "-3".to_owned()), run(Arith::default()));
+        let arith = Arith {
+            overflow: Overflow::Wrap,
+            division: Division::Floor,
+        };
+        assert_eq!((true, "-4-9223372036854775808".to_owned()), run(arith));
+    }
+}
@@ -0,0 +1,177 @@
+//! 比較用に残した、リンク前のVMのディスパッチループ
+//!
+//! 命令を1つずつcloneし、ジャンプのたびにラベル名をHashMapで引く
+//! 算術は``Number::apply``を使い、差がディスパッチだけに出るようにする
+
+use std::{collections::HashMap, io::Write};
+
+use anyhow::{self, Context, Result};
+use whitespace_rs::{
+    arith::{Arith, BinOp},
+    instruction::Instruction,
+    number::Number,
+};
+
+pub struct VM<W: Write> {
+    insts: Vec<Instruction>,
+    stack: Vec<Number>,
+    /// K: address, V: value
+    heap: HashMap<Number, Number>,
+    /// K: label, V: position
+    labels: HashMap<String, u64>,
+    /// 末尾はサブルーチンの戻り先
+    call_stack: Vec<usize>,
+    arith: Arith,
+    writer: W,
+}
+
+impl<W: Write> VM<W> {
+    pub fn new(insts: Vec<Instruction>, writer: W) -> Self {
+        let labels = Self::find_labels(&insts);
+        Self {
+            insts,
+            stack: Vec::new(),
+            heap: HashMap::new(),
+            labels,
+            call_stack: vec![],
+            arith: Arith::default(),
+            writer,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let mut pc = 0;
+        while pc < self.insts.len() {
+            let inst = self.insts[pc].clone();
+            match inst {
+                Instruction::Push(n) => {
+                    self.stack.push(n);
+                }
+                Instruction::Dup => {
+                    let x = self.stack.last().context("cannot dup the empty stack.")?;
+                    self.stack.push(x.clone());
+                }
+                Instruction::Copy(n) => {
+                    // ケツからn番目（0 indexed）
+                    let v = self.stack[self.stack.len() - (n as usize + 1)].clone();
+                    self.stack.push(v);
+                }
+                Instruction::Swap => {
+                    let x = self.pop()?;
+                    let y = self.pop()?;
+                    self.stack.push(x);
+                    self.stack.push(y);
+                }
+                Instruction::Discard => {
+                    self.pop()?;
+                }
+                Instruction::Slide(n) => {
+                    let x = self.pop()?;
+                    for _ in 0..(n as usize) {
+                        self.pop()?;
+                    }
+                    self.stack.push(x);
+                }
+                Instruction::Add => self.binop(BinOp::Add)?,
+                Instruction::Sub => self.binop(BinOp::Sub)?,
+                Instruction::Mul => self.binop(BinOp::Mul)?,
+                Instruction::Div => self.binop(BinOp::Div)?,
+                Instruction::Mod => self.binop(BinOp::Mod)?,
+                Instruction::HeapWrite => {
+                    let value = self.pop()?;
+                    let address = self.pop()?;
+                    self.heap.insert(address, value);
+                }
+                Instruction::HeapRead => {
+                    let address = self.pop()?;
+                    let value = self
+                        .heap
+                        .get(&address)
+                        .context("cannot read an uninitialized heap position.")?;
+                    self.stack.push(value.clone());
+                }
+                // ラベルの位置はすでに調べているので何もしない
+                Instruction::Label(_) => (),
+                Instruction::Call(label) => {
+                    self.call_stack.push(pc);
+                    pc = self.resolve_label(&label)?;
+                }
+                Instruction::Jump(label) => {
+                    pc = self.resolve_label(&label)?;
+                }
+                Instruction::JumpZero(label) => {
+                    let x = self.pop()?;
+                    if x.is_zero() {
+                        pc = self.resolve_label(&label)?;
+                    }
+                }
+                Instruction::JumpNeg(label) => {
+                    let x = self.pop()?;
+                    if x.is_negative() {
+                        pc = self.resolve_label(&label)?;
+                    }
+                }
+                Instruction::Return => match self.call_stack.pop() {
+                    Some(x) => pc = x,
+                    _ => return Err(anyhow::anyhow!("cannot return from the out of subroutine.")),
+                },
+                Instruction::Exit => {
+                    return Ok(());
+                }
+                Instruction::CharOut => {
+                    let x = self.pop()?;
+                    self.writer.write_all(&[x.low_byte()])?;
+                }
+                Instruction::NumOut => {
+                    let x = self.pop()?;
+                    self.writer.write_all(x.to_string().as_bytes())?;
+                }
+                // 入力はベンチマークの前に定数に置き換えておく
+                inst => {
+                    return Err(anyhow::anyhow!("unsupported instruction: {:?}", inst));
+                }
+            }
+
+            pc += 1;
+        }
+
+        Err(anyhow::anyhow!(
+            "exit command must be done in the last of Whitespace program."
+        ))
+    }
+
+    fn find_labels(insts: &[Instruction]) -> HashMap<String, u64> {
+        let mut labels = HashMap::new();
+        for (i, inst) in insts.iter().enumerate() {
+            if let Instruction::Label(name) = inst {
+                // ラベル名がだぶった場合は先に登録したほうを優先する
+                labels.entry(name.clone()).or_insert(i as u64);
+            }
+        }
+        labels
+    }
+
+    fn binop(&mut self, op: BinOp) -> Result<()> {
+        let r = self.pop()?;
+        let l = self.pop()?;
+        let n = Number::apply(op, &l, &r, &self.arith)?;
+        self.stack.push(n);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Number> {
+        let x = self
+            .stack
+            .pop()
+            .context("cannot pop from the empty stack.")?;
+        Ok(x)
+    }
+
+    fn resolve_label(&self, label: &str) -> Result<usize> {
+        let pc = self
+            .labels
+            .get(label)
+            .with_context(|| format!("label is not found. label name: {}", label))?;
+        Ok(*pc as usize)
+    }
+}
@@ -0,0 +1,141 @@
+//! VM::runの実行時間を計測する
+//!
+//! ``baseline``はリンク前のVM（命令のclone、ラベル名の検索）で、同じプログラムを比べる
+//!
+//! プログラムの出力は捨て、計測結果は標準エラー出力に書き出す
+//!
+//! ```bash
+//! $ cargo bench
+//! ```
+
+use std::{
+    fs, io,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+mod baseline;
+
+use whitespace_rs::{
+    arith::Arith, assembler, compiler::Compiler, instruction::Instruction, number::Number,
+    optimizer, vm::VM,
+};
+
+const ITERATIONS: u32 = 2000;
+
+/// 標準入力を使わずに済むよう、NumInを定数の書き込みに置き換える
+fn feed_input(insts: Vec<Instruction>, input: i64) -> Vec<Instruction> {
+    let mut res = vec![];
+    for inst in insts.into_iter() {
+        match inst {
+            Instruction::NumIn => {
+                res.push(Instruction::Push(Number::from(input)));
+                res.push(Instruction::HeapWrite);
+            }
+            _ => res.push(inst),
+        }
+    }
+    res
+}
+
+/// 出力のシステムコールを除いてディスパッチだけを計測するため、出力命令を捨てる
+fn mute_output(insts: Vec<Instruction>) -> Vec<Instruction> {
+    insts
+        .into_iter()
+        .map(|inst| match inst {
+            Instruction::CharOut | Instruction::NumOut => Instruction::Discard,
+            _ => inst,
+        })
+        .collect()
+}
+
+/// 命令のリンクなどVMを作る時間は含めず、runだけを計測する
+fn bench(insts: &[Instruction]) -> Result<Duration> {
+    let vms = (0..ITERATIONS).map(|_| VM::new(insts.to_vec(), io::empty(), io::sink()));
+    measure(vms.collect(), |vm| vm.run())
+}
+
+fn bench_baseline(insts: &[Instruction]) -> Result<Duration> {
+    let vms = (0..ITERATIONS).map(|_| baseline::VM::new(insts.to_vec(), io::sink()));
+    measure(vms.collect(), |vm| vm.run())
+}
+
+/// 最適化の時間は含めない
+fn bench_optimized(insts: &[Instruction]) -> Result<Duration> {
+    let insts = optimizer::optimize(insts, &Arith::default()).insts;
+    let vms =
+        (0..ITERATIONS).map(|_| VM::new(insts.clone(), io::empty(), io::sink()).with_fusion());
+    measure(vms.collect(), |vm| vm.run())
+}
+
+fn measure<T>(vms: Vec<T>, mut run: impl FnMut(&mut T) -> Result<()>) -> Result<Duration> {
+    let start = Instant::now();
+    for mut vm in vms.into_iter() {
+        run(&mut vm)?;
+    }
+    Ok(start.elapsed() / ITERATIONS)
+}
+
+/// 計算の多いプログラムで、インタプリタとJITコンパイルを比べる
+/// `jit` featureが無効なら両方ともインタプリタになる
+fn bench_loop() -> Result<()> {
+    let insts = assembler::parse(LOOP)?;
+    for jit in [false, true].iter() {
+        let start = Instant::now();
+        let mut vm = VM::new(insts.clone(), io::empty(), io::sink());
+        if *jit {
+            vm.run_jit()?;
+        } else {
+            vm.run()?;
+        }
+        eprintln!("loop (jit: {}): {:?} / run", jit, start.elapsed());
+    }
+    Ok(())
+}
+
+/// 0から9999999までの和を求める
+const LOOP: &str = "
+    push 0
+    push 10000000
+loop:
+    dup
+    jz done
+    push 1
+    sub
+    swap
+    copy 1
+    add
+    swap
+    jmp loop
+done:
+    discard
+    outn
+    exit
+";
+
+fn main() -> Result<()> {
+    let cases = [("examples/fib.ws", 90), ("examples/fact.ws", 20)];
+    for (path, input) in cases.iter() {
+        let code = fs::read_to_string(path)?;
+        let insts = Compiler::new(code).compile()?;
+        let insts = feed_input(insts, *input);
+
+        let elapsed = bench(&insts)?;
+        eprintln!("{} (input: {}): {:?} / run", path, input, elapsed);
+        let insts = mute_output(insts);
+        let elapsed = bench_baseline(&insts)?;
+        eprintln!(
+            "{} (input: {}, muted, baseline): {:?} / run",
+            path, input, elapsed
+        );
+        let elapsed = bench(&insts)?;
+        eprintln!("{} (input: {}, muted): {:?} / run", path, input, elapsed);
+        let elapsed = bench_optimized(&insts)?;
+        eprintln!(
+            "{} (input: {}, muted, optimized): {:?} / run",
+            path, input, elapsed
+        );
+    }
+    bench_loop()
+}
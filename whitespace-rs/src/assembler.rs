@@ -0,0 +1,353 @@
+//! ニーモニックで書かれたアセンブリをWhitespaceに変換する
+//!
+//! ```text
+//! ; 1から10まで出力する
+//!     push 1
+//! loop:
+//!     dup
+//!     outn
+//!     push '\n'
+//!     outc
+//!     push 1
+//!     add
+//!     dup
+//!     push 11
+//!     sub
+//!     jz end
+//!     jmp loop
+//! end:
+//!     exit
+//! ```
+//!
+//! - 1行に1命令、`;`または`#`から行末まではコメント
+//! - `name:`は`label name`と同じ
+//! - 数値は10進数か`'A'`のような文字リテラル
+//! - `s`と`t`だけからなるラベル名はそのままのs/t表記として扱い、
+//!   それ以外の名前には重複しない短いs/t表記を割り当てる
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+
+use crate::instruction::{self, Instruction};
+use crate::number::Number;
+
+/// アセンブリを命令列に変換する
+pub fn parse(src: &str) -> Result<Vec<Instruction>> {
+    let lines: Vec<Vec<String>> = src
+        .lines()
+        .enumerate()
+        .map(|(i, line)| split_words(line).with_context(|| format!("line {}", i + 1)))
+        .collect::<Result<_>>()?;
+
+    let mut labels = Labels::new(&lines);
+    let mut insts = vec![];
+    for (i, words) in lines.iter().enumerate() {
+        let mut words = &words[..];
+        while let Some(name) = words.first().and_then(|w| w.strip_suffix(':')) {
+            insts.push(Instruction::Label(labels.resolve(name)));
+            words = &words[1..];
+        }
+        if words.is_empty() {
+            continue;
+        }
+        let inst = p_inst(words, &mut labels).with_context(|| format!("line {}", i + 1))?;
+        insts.push(inst);
+    }
+    Ok(insts)
+}
+
+/// アセンブリをWhitespaceのコードに変換する
+pub fn assemble(src: &str) -> Result<String> {
+    let insts = parse(src)?;
+    Ok(instruction::to_ws(&insts))
+}
+
+/// 短い順に並べたi番目のs/t表記
+/// s, t, ss, st, ts, tt, sss, ...
+pub fn label_name(i: usize) -> String {
+    let mut len = 1;
+    let mut i = i;
+    while i >= 1 << len {
+        i -= 1 << len;
+        len += 1;
+    }
+    (0..len)
+        .rev()
+        .map(|bit| if i >> bit & 1 == 1 { 't' } else { 's' })
+        .collect()
+}
+
+fn is_raw_label(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c == 's' || c == 't')
+}
+
+/// ラベル名とs/t表記の対応
+struct Labels {
+    names: HashMap<String, String>,
+    /// そのまま使われるs/t表記
+    raw: HashSet<String>,
+    next: usize,
+}
+
+impl Labels {
+    fn new(lines: &[Vec<String>]) -> Self {
+        let raw = lines
+            .iter()
+            .flatten()
+            .map(|w| w.strip_suffix(':').unwrap_or(w))
+            .filter(|w| is_raw_label(w))
+            .map(|w| w.to_owned())
+            .collect();
+        Self {
+            names: HashMap::new(),
+            raw,
+            next: 0,
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> String {
+        if is_raw_label(name) {
+            return name.to_owned();
+        }
+        if let Some(label) = self.names.get(name) {
+            return label.clone();
+        }
+
+        let label = loop {
+            let label = label_name(self.next);
+            self.next += 1;
+            if !self.raw.contains(&label) {
+                break label;
+            }
+        };
+        self.names.insert(name.to_owned(), label.clone());
+        label
+    }
+}
+
+fn p_inst(words: &[String], labels: &mut Labels) -> Result<Instruction> {
+    let mnemonic = words[0].to_lowercase();
+    let args = &words[1..];
+
+    let inst = match mnemonic.as_str() {
+        "push" => Instruction::Push(p_num(args)?),
+        "dup" => Instruction::Dup,
+        "copy" => Instruction::Copy(p_index(args)?),
+        "swap" => Instruction::Swap,
+        "discard" | "drop" => Instruction::Discard,
+        "slide" => Instruction::Slide(p_index(args)?),
+        "add" => Instruction::Add,
+        "sub" => Instruction::Sub,
+        "mul" => Instruction::Mul,
+        "div" => Instruction::Div,
+        "mod" => Instruction::Mod,
+        "store" | "heapwrite" => Instruction::HeapWrite,
+        "load" | "heapread" => Instruction::HeapRead,
+        "label" => Instruction::Label(p_label(args, labels)?),
+        "call" => Instruction::Call(p_label(args, labels)?),
+        "jmp" | "jump" => Instruction::Jump(p_label(args, labels)?),
+        "jz" | "jumpzero" => Instruction::JumpZero(p_label(args, labels)?),
+        "jn" | "jumpneg" => Instruction::JumpNeg(p_label(args, labels)?),
+        "ret" | "return" => Instruction::Return,
+        "exit" => Instruction::Exit,
+        "outc" | "charout" => Instruction::CharOut,
+        "outn" | "numout" => Instruction::NumOut,
+        "inc" | "charin" => Instruction::CharIn,
+        "inn" | "numin" => Instruction::NumIn,
+        "dumps" | "dumpstack" => Instruction::DumpStack,
+        "dumph" | "dumpheap" => Instruction::DumpHeap,
+        "host" | "syscall" => Instruction::HostCall(p_host(args)?),
+        _ => return Err(anyhow::anyhow!("unknown mnemonic: {}", words[0])),
+    };
+
+    let arity = match inst {
+        Instruction::Push(_)
+        | Instruction::Copy(_)
+        | Instruction::Slide(_)
+        | Instruction::HostCall(_)
+        | Instruction::Label(_)
+        | Instruction::Call(_)
+        | Instruction::Jump(_)
+        | Instruction::JumpZero(_)
+        | Instruction::JumpNeg(_) => 1,
+        _ => 0,
+    };
+    if args.len() != arity {
+        return Err(anyhow::anyhow!(
+            "{} takes {} argument(s), but {} given.",
+            mnemonic,
+            arity,
+            args.len()
+        ));
+    }
+
+    Ok(inst)
+}
+
+fn p_num(args: &[String]) -> Result<Number> {
+    let arg = args.first().context("missing a number argument.")?;
+    if let Some(c) = arg.strip_prefix('\'').and_then(|a| a.strip_suffix('\'')) {
+        let c = unescape(c)?;
+        return Ok(Number::from(c as i64));
+    }
+    arg.parse()
+        .with_context(|| format!("invalid number: {}", arg))
+}
+
+fn p_index(args: &[String]) -> Result<i64> {
+    let n = p_num(args)?;
+    n.to_i64()
+        .with_context(|| format!("the stack index is too large: {}", n))
+}
+
+fn p_host(args: &[String]) -> Result<i64> {
+    let n = p_num(args)?;
+    n.to_i64()
+        .with_context(|| format!("the host function number is too large: {}", n))
+}
+
+fn p_label(args: &[String], labels: &mut Labels) -> Result<String> {
+    let arg = args.first().context("missing a label argument.")?;
+    Ok(labels.resolve(arg))
+}
+
+/// 文字リテラルの中身
+fn unescape(s: &str) -> Result<char> {
+    let c = match s {
+        "\\n" => '\n',
+        "\\t" => '\t',
+        "\\s" => ' ',
+        "\\\\" => '\\',
+        "\\'" => '\'',
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(anyhow::anyhow!("invalid character literal: '{}'", s)),
+            }
+        }
+    };
+    Ok(c)
+}
+
+/// 行を単語に分割し、コメントを除く
+/// 文字リテラルの中の空白や`;`は区切りとみなさない
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' | '#' => break,
+            '\'' => {
+                word.push(c);
+                loop {
+                    let c = chars.next().context("unterminated character literal.")?;
+                    word.push(c);
+                    if c == '\\' {
+                        let c = chars.next().context("unterminated character literal.")?;
+                        word.push(c);
+                    } else if c == '\'' {
+                        break;
+                    }
+                }
+            }
+            _ if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    #[test]
+    fn label_names() {
+        let names: Vec<_> = (0..7).map(label_name).collect();
+        assert_eq!(vec!["s", "t", "ss", "st", "ts", "tt", "sss"], names);
+    }
+
+    #[test]
+    fn mnemonics() {
+        let src = "
+            push -3   ; comment
+            push ' '  # another comment
+            loop: dup
+            copy 1
+            jz loop
+            label st
+            call s
+            exit
+        ";
+        let insts = parse(src).unwrap();
+        let expect = vec![
+            Instruction::Push(Number::from(-3)),
+            Instruction::Push(Number::from(32)),
+            Instruction::Label("t".to_owned()),
+            Instruction::Dup,
+            Instruction::Copy(1),
+            Instruction::JumpZero("t".to_owned()),
+            Instruction::Label("st".to_owned()),
+            Instruction::Call("s".to_owned()),
+            Instruction::Exit,
+        ];
+        assert_eq!(expect, insts);
+    }
+
+    #[test]
+    fn round_trip() {
+        let src = "
+            push 0
+            push 1
+            push -1
+            push 'H'
+            dup
+            copy 2
+            swap
+            discard
+            slide 1
+            add
+            sub
+            mul
+            div
+            mod
+            store
+            load
+            label a
+            call b
+            jmp a
+            jz b
+            jn a
+            ret
+            outc
+            outn
+            inc
+            inn
+            b:
+            exit
+        ";
+        let insts = parse(src).unwrap();
+        let code = assemble(src).unwrap();
+        let compiled = Compiler::new(code).compile().unwrap();
+        assert_eq!(insts, compiled);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("push").is_err());
+        assert!(parse("dup 1").is_err());
+        assert!(parse("nop").is_err());
+        assert!(parse("push 'ab'").is_err());
+    }
+}
@@ -0,0 +1,494 @@
+//! コンパイル済みの命令列を保存するバイナリ形式
+//!
+//! 数値はすべてリトルエンディアン
+//!
+//! ```text
+//! magic     b"WSBC"
+//! version   u16
+//! flags     u16            bit 0: ソースマップあり, bit 1: ソースコードが見える表記
+//! labels    u32 個数, 各ラベルは (u32 長さ, s/t表記, u32 定義位置。未定義ならu32::MAX)
+//! insts     u32 個数, 各命令は (u8 opcode, 引数)
+//!             Push: u32 長さ + 2の補数のリトルエンディアン
+//!             Copy, Slide, HostCall: i64
+//!             ラベルを取る命令: u32 ラベル表の添字
+//! source    flags bit 0が立っている場合のみ
+//!             u32 長さ + UTF-8のソースコード, 各命令の範囲 (start, end) × (offset, line, column) の u32
+//! checksum  u64            ここまでのFNV-1a
+//! ```
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    instruction::Instruction, linker, number::Number, source::Pos, source::Span, token::Notation,
+};
+
+pub const MAGIC: &[u8; 4] = b"WSBC";
+pub const VERSION: u16 = 1;
+
+const FLAG_SOURCE: u16 = 1;
+const FLAG_VISIBLE: u16 = 2;
+const UNDEFINED: u32 = u32::MAX;
+
+/// 読み込んだバイトコード
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    pub insts: Vec<Instruction>,
+    /// 実行時エラーの表示に使うソースコードとその表記、各命令に対応する範囲
+    pub source: Option<(String, Notation, Vec<Span>)>,
+}
+
+/// 先頭がマジックナンバーならバイトコードとみなす
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// sourceを渡すと、実行時エラーで元のコードの位置を示せるようにソースマップを含める
+pub fn encode(insts: &[Instruction], source: Option<(&str, Notation, &[Span])>) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes(MAGIC);
+    w.u16(VERSION);
+    w.u16(match source {
+        Some((_, Notation::Whitespace, _)) => FLAG_SOURCE,
+        Some((_, Notation::Visible, _)) => FLAG_SOURCE | FLAG_VISIBLE,
+        None => 0,
+    });
+
+    // 出現順にラベル表を作る
+    let mut labels: Vec<&str> = vec![];
+    let mut index: HashMap<&str, u32> = HashMap::new();
+    for inst in insts.iter() {
+        if let Some(label) = label_of(inst) {
+            index.entry(label).or_insert_with(|| {
+                labels.push(label);
+                (labels.len() - 1) as u32
+            });
+        }
+    }
+    let program = linker::link(insts);
+    w.u32(labels.len() as u32);
+    for label in labels.iter() {
+        w.str(label);
+        w.u32(program.labels.get(*label).map_or(UNDEFINED, |i| *i as u32));
+    }
+
+    w.u32(insts.len() as u32);
+    for inst in insts.iter() {
+        w.u8(opcode(inst));
+        match inst {
+            Instruction::Push(n) => w.number(n),
+            Instruction::Copy(n) | Instruction::Slide(n) | Instruction::HostCall(n) => w.i64(*n),
+            _ => {
+                if let Some(label) = label_of(inst) {
+                    w.u32(index[label]);
+                }
+            }
+        }
+    }
+
+    if let Some((src, _, spans)) = source {
+        w.str(src);
+        for span in spans.iter() {
+            for pos in [span.start, span.end].iter() {
+                w.u32(pos.offset as u32);
+                w.u32(pos.line as u32);
+                w.u32(pos.column as u32);
+            }
+        }
+    }
+
+    let checksum = fnv1a(&w.buf);
+    w.u64(checksum);
+    w.buf
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Bytecode> {
+    if !is_bytecode(bytes) {
+        return Err(anyhow::anyhow!(
+            "not a Whitespace bytecode file (bad magic number)."
+        ));
+    }
+    let mut r = Reader::new(bytes, "bytecode");
+    r.pos = MAGIC.len();
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(anyhow::anyhow!(
+            "unsupported bytecode version: {} (supported: {}).",
+            version,
+            VERSION
+        ));
+    }
+    if bytes.len() < 8 {
+        return Err(corrupted("unexpected end of data"));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 8);
+    let mut buf = [0; 8];
+    buf.copy_from_slice(checksum);
+    if fnv1a(body) != u64::from_le_bytes(buf) {
+        return Err(corrupted("checksum mismatch"));
+    }
+    r.bytes = body;
+
+    let flags = r.u16()?;
+    if flags & !(FLAG_SOURCE | FLAG_VISIBLE) != 0 {
+        return Err(corrupted("unknown flags"));
+    }
+
+    let label_count = r.u32()? as usize;
+    let mut labels = Vec::with_capacity(label_count.min(body.len()));
+    for _ in 0..label_count {
+        let label = r.str()?;
+        if label.is_empty() || !label.chars().all(|c| c == 's' || c == 't') {
+            return Err(corrupted("invalid label name"));
+        }
+        let position = r.u32()?;
+        labels.push((label, position));
+    }
+
+    let inst_count = r.u32()? as usize;
+    let mut insts = Vec::with_capacity(inst_count.min(body.len()));
+    for _ in 0..inst_count {
+        insts.push(r.inst(&labels)?);
+    }
+
+    // 保存したラベル表が命令列と食い違っていないか
+    let program = linker::link(&insts);
+    for (label, position) in labels.iter() {
+        let actual = program.labels.get(label).map_or(UNDEFINED, |i| *i as u32);
+        if actual != *position {
+            return Err(corrupted("the label table does not match the instructions"));
+        }
+    }
+
+    let source = if flags & FLAG_SOURCE != 0 {
+        let src = r.str()?;
+        let mut spans = Vec::with_capacity(insts.len());
+        for _ in 0..insts.len() {
+            let span = Span {
+                start: r.pos()?,
+                end: r.pos()?,
+            };
+            let valid = span.start.offset <= span.end.offset
+                && span.end.offset <= src.len()
+                && src.is_char_boundary(span.start.offset)
+                && src.is_char_boundary(span.end.offset);
+            if !valid {
+                return Err(corrupted("invalid source map"));
+            }
+            spans.push(span);
+        }
+        let notation = if flags & FLAG_VISIBLE != 0 {
+            Notation::Visible
+        } else {
+            Notation::Whitespace
+        };
+        Some((src, notation, spans))
+    } else {
+        None
+    };
+
+    if r.pos != r.bytes.len() {
+        return Err(corrupted("trailing data"));
+    }
+    Ok(Bytecode { insts, source })
+}
+
+fn corrupted(reason: &str) -> anyhow::Error {
+    anyhow::anyhow!("corrupted bytecode file: {}.", reason)
+}
+
+fn opcode(inst: &Instruction) -> u8 {
+    match inst {
+        Instruction::Push(_) => 0,
+        Instruction::Dup => 1,
+        Instruction::Copy(_) => 2,
+        Instruction::Swap => 3,
+        Instruction::Discard => 4,
+        Instruction::Slide(_) => 5,
+        Instruction::Add => 6,
+        Instruction::Sub => 7,
+        Instruction::Mul => 8,
+        Instruction::Div => 9,
+        Instruction::Mod => 10,
+        Instruction::HeapWrite => 11,
+        Instruction::HeapRead => 12,
+        Instruction::Label(_) => 13,
+        Instruction::Call(_) => 14,
+        Instruction::Jump(_) => 15,
+        Instruction::JumpZero(_) => 16,
+        Instruction::JumpNeg(_) => 17,
+        Instruction::Return => 18,
+        Instruction::Exit => 19,
+        Instruction::CharOut => 20,
+        Instruction::NumOut => 21,
+        Instruction::CharIn => 22,
+        Instruction::NumIn => 23,
+        Instruction::DumpStack => 24,
+        Instruction::DumpHeap => 25,
+        Instruction::HostCall(_) => 26,
+    }
+}
+
+fn label_of(inst: &Instruction) -> Option<&str> {
+    match inst {
+        Instruction::Label(l)
+        | Instruction::Call(l)
+        | Instruction::Jump(l)
+        | Instruction::JumpZero(l)
+        | Instruction::JumpNeg(l) => Some(l),
+        _ => None,
+    }
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// 命令列を識別するハッシュ。ソースコードの書き方には依らない
+pub fn fingerprint(insts: &[Instruction]) -> u64 {
+    fnv1a(&encode(insts, None))
+}
+
+#[derive(Default)]
+pub(crate) struct Writer {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    pub(crate) fn u16(&mut self, n: u16) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, n: u32) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, n: u64) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    pub(crate) fn i64(&mut self, n: i64) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    pub(crate) fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes(s.as_bytes());
+    }
+
+    /// u32 長さ + 2の補数のリトルエンディアン
+    pub(crate) fn number(&mut self, n: &Number) {
+        let bytes = n.to_signed_bytes_le();
+        self.u32(bytes.len() as u32);
+        self.bytes(&bytes);
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+    /// エラーメッセージに使うファイルの種類
+    kind: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], kind: &'static str) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            kind,
+        }
+    }
+
+    pub(crate) fn corrupted(&self, reason: &str) -> anyhow::Error {
+        anyhow::anyhow!("corrupted {} file: {}.", self.kind, reason)
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| self.corrupted("unexpected end of data"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        buf.copy_from_slice(self.take(N)?);
+        Ok(buf)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn str(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        let s = std::str::from_utf8(bytes).map_err(|_| self.corrupted("invalid UTF-8"))?;
+        Ok(s.to_owned())
+    }
+
+    pub(crate) fn number(&mut self) -> Result<Number> {
+        let len = self.u32()? as usize;
+        Number::from_signed_bytes_le(self.take(len)?).context("cannot load a number")
+    }
+
+    fn pos(&mut self) -> Result<Pos> {
+        Ok(Pos {
+            offset: self.u32()? as usize,
+            line: self.u32()? as usize,
+            column: self.u32()? as usize,
+        })
+    }
+
+    fn inst(&mut self, labels: &[(String, u32)]) -> Result<Instruction> {
+        let op = self.u8()?;
+        let mut label = || -> Result<String> {
+            let i = self.u32()? as usize;
+            let (label, _) = labels
+                .get(i)
+                .ok_or_else(|| corrupted("label index out of range"))?;
+            Ok(label.clone())
+        };
+        let inst = match op {
+            0 => Instruction::Push(self.number()?),
+            1 => Instruction::Dup,
+            2 => Instruction::Copy(self.i64()?),
+            3 => Instruction::Swap,
+            4 => Instruction::Discard,
+            5 => Instruction::Slide(self.i64()?),
+            6 => Instruction::Add,
+            7 => Instruction::Sub,
+            8 => Instruction::Mul,
+            9 => Instruction::Div,
+            10 => Instruction::Mod,
+            11 => Instruction::HeapWrite,
+            12 => Instruction::HeapRead,
+            13 => Instruction::Label(label()?),
+            14 => Instruction::Call(label()?),
+            15 => Instruction::Jump(label()?),
+            16 => Instruction::JumpZero(label()?),
+            17 => Instruction::JumpNeg(label()?),
+            18 => Instruction::Return,
+            19 => Instruction::Exit,
+            20 => Instruction::CharOut,
+            21 => Instruction::NumOut,
+            22 => Instruction::CharIn,
+            23 => Instruction::NumIn,
+            24 => Instruction::DumpStack,
+            25 => Instruction::DumpHeap,
+            26 => Instruction::HostCall(self.i64()?),
+            _ => return Err(corrupted(&format!("unknown opcode {}", op))),
+        };
+        Ok(inst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, compiler::Compiler, notation};
+
+    const PROGRAM: &str = "
+        push -300
+        push 'A'
+        copy 1
+        slide 1
+    loop:
+        call sub
+        jz loop
+        jn nowhere
+        exit
+    sub:
+        ret
+    ";
+
+    #[test]
+    fn round_trip() {
+        let insts = assembler::parse(PROGRAM).unwrap();
+        let bytes = encode(&insts, None);
+        assert!(is_bytecode(&bytes));
+        let expect = Bytecode {
+            insts,
+            source: None,
+        };
+        assert_eq!(expect, decode(&bytes).unwrap());
+    }
+
+    #[test]
+    fn source_map() {
+        let code = assembler::assemble(PROGRAM).unwrap();
+        let (insts, spans) = Compiler::new(code.clone()).compile_with_spans().unwrap();
+        let bytes = encode(&insts, Some((&code, Notation::Whitespace, &spans)));
+        let bytecode = decode(&bytes).unwrap();
+        assert_eq!(insts, bytecode.insts);
+        let visible = notation::to_visible(&code);
+        assert_eq!(Some((code, Notation::Whitespace, spans)), bytecode.source);
+
+        let code = visible;
+        let (insts, spans) = Compiler::new(code.clone())
+            .with_notation(Notation::Visible)
+            .compile_with_spans()
+            .unwrap();
+        let bytes = encode(&insts, Some((&code, Notation::Visible, &spans)));
+        let expect = Some((code, Notation::Visible, spans));
+        assert_eq!(expect, decode(&bytes).unwrap().source);
+    }
+
+    #[test]
+    fn errors() {
+        let insts = assembler::parse(PROGRAM).unwrap();
+        let bytes = encode(&insts, None);
+        let err = |bytes: &[u8]| decode(bytes).unwrap_err().to_string();
+
+        assert_eq!(
+            "not a Whitespace bytecode file (bad magic number).",
+            err(b"   \t\n")
+        );
+        let mut future = bytes.clone();
+        future[4] = 2;
+        assert_eq!(
+            "unsupported bytecode version: 2 (supported: 1).",
+            err(&future)
+        );
+        let mut flipped = bytes.clone();
+        flipped[12] ^= 1;
+        assert_eq!("corrupted bytecode file: checksum mismatch.", err(&flipped));
+
+        // どこで切れてもpanicせずにエラーになる
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err());
+        }
+    }
+}
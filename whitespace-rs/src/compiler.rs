@@ -1,68 +1,157 @@
+use std::fmt;
+
 use anyhow::Result;
 
 use crate::instruction::Instruction;
-use crate::token::{self, Token};
+use crate::number::Number;
+use crate::source::{self, Diagnostic, Span};
+use crate::token::{self, Notation, Spanned, Token};
 
 #[derive(Debug)]
 pub struct Compiler {
     src_code: String,
+    /// ソースコードの表記
+    notation: Notation,
+    /// 拡張命令（LLS、LLT、TLL）を受け付けるか
+    extensions: bool,
+}
+
+/// 構文エラー
+/// atはエラーを検出したトークンの位置で、トークン列の長さに等しければプログラム末尾
+#[derive(Debug)]
+struct ParseError {
+    msg: String,
+    at: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+macro_rules! parse_error {
+    ($at:expr, $($arg:tt)*) => {
+        anyhow::Error::new(ParseError {
+            msg: format!($($arg)*),
+            at: $at,
+        })
+    };
 }
 
 impl Compiler {
     pub fn new(src_code: String) -> Self {
-        Self { src_code: src_code }
+        Self {
+            src_code,
+            notation: Notation::Whitespace,
+            extensions: false,
+        }
+    }
+
+    /// 見える表記のまま読み、エラーや命令の範囲はその中の位置で示す
+    pub fn with_notation(mut self, notation: Notation) -> Self {
+        self.notation = notation;
+        self
+    }
+
+    /// 仕様に無い拡張命令を受け付ける
+    /// - LLS: スタックの中身を書き出す
+    /// - LLT: ヒープの中身を書き出す
+    /// - TLL n: VMに登録したn番のホスト関数を呼ぶ
+    pub fn with_extensions(mut self) -> Self {
+        self.extensions = true;
+        self
     }
 
     pub fn compile(&self) -> Result<Vec<Instruction>> {
-        let tokens = token::tokenize(&self.src_code)?;
+        let (insts, _) = self.compile_with_spans()?;
+        Ok(insts)
+    }
+
+    /// 各命令に対応するソースコード上の範囲もあわせて返す
+    pub fn compile_with_spans(&self) -> Result<(Vec<Instruction>, Vec<Span>)> {
+        let tokens = token::tokenize(&self.src_code, self.notation)?;
         let mut pos = 0;
         let mut insts = vec![];
+        let mut spans = vec![];
 
-        macro_rules! parse {
-            ($lexpr:expr) => {{
-                let (inst, p) = $lexpr?;
-                insts.push(inst);
-                pos = p;
-            }};
+        while pos < tokens.len() {
+            let start = pos;
+            let res = match tokens[pos].token {
+                Token::Space => Self::p_s(pos + 1, &tokens),
+                Token::Tab => self.p_t(pos + 1, &tokens),
+                Token::Lf => self.p_l(pos + 1, &tokens),
+            };
+            match res {
+                Ok((inst, p)) => {
+                    insts.push(inst);
+                    spans.push(self.span(start, p - 1, &tokens));
+                    pos = p;
+                }
+                Err(e) => return Err(self.diagnose(e, start, &tokens)),
+            }
         }
 
-        while pos < tokens.len() {
-            match tokens[pos] {
-                Token::Space => parse!(Self::p_s(pos + 1, &tokens)),
-                Token::Tab => parse!(Self::p_t(pos + 1, &tokens)),
-                Token::Lf => parse!(Self::p_l(pos + 1, &tokens)),
+        Ok((insts, spans))
+    }
+
+    /// first番目からlast番目までのトークンを含む範囲
+    fn span(&self, first: usize, last: usize, tokens: &[Spanned]) -> Span {
+        let start = tokens[first].pos;
+        let end = match tokens.get(last) {
+            Some(tok) => tok.end,
+            None => source::end_pos(&self.src_code),
+        };
+        Span { start, end }
+    }
+
+    fn diagnose(&self, e: anyhow::Error, start: usize, tokens: &[Spanned]) -> anyhow::Error {
+        match e.downcast::<ParseError>() {
+            Ok(e) => {
+                let span = self.span(start, e.at, tokens);
+                let diag = Diagnostic::new(e.msg, span).with_notation(self.notation);
+                anyhow::anyhow!(diag.render(&self.src_code))
             }
+            Err(e) => e,
         }
+    }
 
-        Ok(insts)
+    /// pos番目のトークン
+    fn at(pos: usize, tokens: &[Spanned]) -> Result<Token> {
+        match tokens.get(pos) {
+            Some(tok) => Ok(tok.token),
+            None => Err(parse_error!(pos, "unexpected end of program.")),
+        }
     }
 
-    fn p_s(pos: usize, tokens: &[Token]) -> Result<(Instruction, usize)> {
-        match tokens[pos] {
+    fn p_s(pos: usize, tokens: &[Spanned]) -> Result<(Instruction, usize)> {
+        match Self::at(pos, tokens)? {
             // SS
             Token::Space => {
                 let (n, p) = Self::p_num(pos + 1, tokens)?;
                 Ok((Instruction::Push(n), p))
             }
             Token::Tab => {
-                let (inst, p) = match tokens[pos + 1] {
+                let (inst, p) = match Self::at(pos + 1, tokens)? {
                     // STS n
                     Token::Space => {
-                        let (n, p) = Self::p_num(pos + 2, tokens)?;
+                        let (n, p) = Self::p_index(pos + 2, tokens)?;
                         (Instruction::Copy(n), p)
                     }
                     // STL n
                     Token::Lf => {
-                        let (n, p) = Self::p_num(pos + 2, tokens)?;
+                        let (n, p) = Self::p_index(pos + 2, tokens)?;
                         (Instruction::Slide(n), p)
                     }
                     // STT
-                    Token::Tab => return Err(anyhow::anyhow!("[STT] is grammar error.")),
+                    Token::Tab => return Err(parse_error!(pos + 1, "[STT] is grammar error.")),
                 };
                 Ok((inst, p))
             }
             Token::Lf => {
-                let inst = match tokens[pos + 1] {
+                let inst = match Self::at(pos + 1, tokens)? {
                     // SLS
                     Token::Space => Instruction::Dup,
                     // SLT
@@ -75,21 +164,32 @@ impl Compiler {
         }
     }
 
-    fn p_num(pos: usize, tokens: &[Token]) -> Result<(i64, usize)> {
+    /// スタック上の位置を指す引数はi64に収まらなければならない
+    fn p_index(pos: usize, tokens: &[Spanned]) -> Result<(i64, usize)> {
+        let (n, p) = Self::p_num(pos, tokens)?;
+        match n.to_i64() {
+            Some(n) => Ok((n, p)),
+            None => Err(parse_error!(p - 1, "the stack index is too large: {}", n)),
+        }
+    }
+
+    fn p_num(pos: usize, tokens: &[Spanned]) -> Result<(Number, usize)> {
         let mut bin = String::new();
-        match tokens[pos] {
+        match Self::at(pos, tokens)? {
             Token::Space => bin.push('+'),
             Token::Tab => bin.push('-'),
             Token::Lf => {
-                return Err(anyhow::anyhow!(
+                return Err(parse_error!(
+                    pos,
                     "numbers must start with space or tab at least one."
                 ))
             }
         }
 
+        // 本書の例にならい、末尾のLFが無くてもプログラムの終わりで数値を終える
         let mut pos = pos + 1;
         while pos < tokens.len() {
-            match tokens[pos] {
+            match tokens[pos].token {
                 Token::Space => bin.push('0'),
                 Token::Tab => bin.push('1'),
                 Token::Lf => {
@@ -99,21 +199,22 @@ impl Compiler {
             }
             pos += 1;
         }
-        let n = i64::from_str_radix(&bin, 2)?;
+        let n = Number::from_str_radix(&bin, 2)
+            .map_err(|e| parse_error!(pos - 1, "invalid number: {}", e))?;
         Ok((n, pos))
     }
 
-    fn p_t(pos: usize, tokens: &[Token]) -> Result<(Instruction, usize)> {
-        match tokens[pos] {
+    fn p_t(&self, pos: usize, tokens: &[Spanned]) -> Result<(Instruction, usize)> {
+        match Self::at(pos, tokens)? {
             Token::Space => Self::p_ts(pos + 1, tokens),
             Token::Tab => Self::p_tt(pos + 1, tokens),
-            Token::Lf => Self::p_tl(pos + 1, tokens),
+            Token::Lf => self.p_tl(pos + 1, tokens),
         }
     }
 
-    fn p_ts(pos: usize, tokens: &[Token]) -> Result<(Instruction, usize)> {
-        let inst = match tokens[pos] {
-            Token::Space => match tokens[pos + 1] {
+    fn p_ts(pos: usize, tokens: &[Spanned]) -> Result<(Instruction, usize)> {
+        let inst = match Self::at(pos, tokens)? {
+            Token::Space => match Self::at(pos + 1, tokens)? {
                 // TSSS
                 Token::Space => Instruction::Add,
                 // TSST
@@ -121,54 +222,66 @@ impl Compiler {
                 // TSSL
                 Token::Lf => Instruction::Mul,
             },
-            Token::Tab => match tokens[pos + 1] {
+            Token::Tab => match Self::at(pos + 1, tokens)? {
                 // TSTS
                 Token::Space => Instruction::Div,
                 // TSTT
                 Token::Tab => Instruction::Mod,
-                Token::Lf => return Err(anyhow::anyhow!("TSTL is grammar error.")),
+                Token::Lf => return Err(parse_error!(pos + 1, "TSTL is grammar error.")),
             },
-            Token::Lf => return Err(anyhow::anyhow!("TSL is grammar error.")),
+            Token::Lf => return Err(parse_error!(pos, "TSL is grammar error.")),
         };
         Ok((inst, pos + 2))
     }
 
-    fn p_tt(pos: usize, tokens: &[Token]) -> Result<(Instruction, usize)> {
-        let inst = match tokens[pos] {
+    fn p_tt(pos: usize, tokens: &[Spanned]) -> Result<(Instruction, usize)> {
+        let inst = match Self::at(pos, tokens)? {
             // TTS
             Token::Space => Instruction::HeapWrite,
             // TTT
             Token::Tab => Instruction::HeapRead,
-            Token::Lf => return Err(anyhow::anyhow!("TTL is grammar error.")),
+            Token::Lf => return Err(parse_error!(pos, "TTL is grammar error.")),
         };
         Ok((inst, pos + 1))
     }
 
-    fn p_tl(pos: usize, tokens: &[Token]) -> Result<(Instruction, usize)> {
-        let inst = match tokens[pos] {
-            Token::Space => match tokens[pos + 1] {
+    fn p_tl(&self, pos: usize, tokens: &[Spanned]) -> Result<(Instruction, usize)> {
+        let inst = match Self::at(pos, tokens)? {
+            Token::Space => match Self::at(pos + 1, tokens)? {
                 // TLSS
                 Token::Space => Instruction::CharOut,
                 // TLST
                 Token::Tab => Instruction::NumOut,
-                Token::Lf => return Err(anyhow::anyhow!("TLSL is grammar error.")),
+                Token::Lf => return Err(parse_error!(pos + 1, "TLSL is grammar error.")),
             },
-            Token::Tab => match tokens[pos + 1] {
+            Token::Tab => match Self::at(pos + 1, tokens)? {
                 // TLTS
                 Token::Space => Instruction::CharIn,
                 // TLTT
                 Token::Tab => Instruction::NumIn,
-                Token::Lf => return Err(anyhow::anyhow!("TLTL is grammar error.")),
+                Token::Lf => return Err(parse_error!(pos + 1, "TLTL is grammar error.")),
             },
-            Token::Lf => return Err(anyhow::anyhow!("TLL is grammar error.")),
+            // TLL n
+            Token::Lf if self.extensions => {
+                let (n, p) = Self::p_num(pos + 1, tokens)?;
+                return match n.to_i64() {
+                    Some(n) => Ok((Instruction::HostCall(n), p)),
+                    None => Err(parse_error!(
+                        p - 1,
+                        "the host function number is too large: {}",
+                        n
+                    )),
+                };
+            }
+            Token::Lf => return Err(parse_error!(pos, "TLL is grammar error.")),
         };
         Ok((inst, pos + 2))
     }
 
-    fn p_l(pos: usize, tokens: &[Token]) -> Result<(Instruction, usize)> {
-        match tokens[pos] {
+    fn p_l(&self, pos: usize, tokens: &[Spanned]) -> Result<(Instruction, usize)> {
+        match Self::at(pos, tokens)? {
             Token::Space => {
-                let (inst, p) = match tokens[pos + 1] {
+                let (inst, p) = match Self::at(pos + 1, tokens)? {
                     // LSS l
                     Token::Space => {
                         let (label, p) = Self::p_label(pos + 2, tokens)?;
@@ -188,7 +301,7 @@ impl Compiler {
                 Ok((inst, p))
             }
             Token::Tab => {
-                let (inst, p) = match tokens[pos + 1] {
+                let (inst, p) = match Self::at(pos + 1, tokens)? {
                     // LTS l
                     Token::Space => {
                         let (label, p) = Self::p_label(pos + 2, tokens)?;
@@ -205,30 +318,33 @@ impl Compiler {
                 Ok((inst, p))
             }
             Token::Lf => {
-                let inst = match tokens[pos + 1] {
+                let inst = match Self::at(pos + 1, tokens)? {
                     // LLL
                     Token::Lf => Instruction::Exit,
-                    _ => return Err(anyhow::anyhow!("LLS and LLT are grammar error.")),
+                    // LLS
+                    Token::Space if self.extensions => Instruction::DumpStack,
+                    // LLT
+                    Token::Tab if self.extensions => Instruction::DumpHeap,
+                    _ => return Err(parse_error!(pos + 1, "LLS and LLT are grammar error.")),
                 };
                 Ok((inst, pos + 2))
             }
         }
     }
 
-    fn p_label(pos: usize, tokens: &[Token]) -> Result<(String, usize)> {
-        match tokens[pos] {
-            Token::Lf => {
-                return Err(anyhow::anyhow!(
-                    "labels must start with space or tag at least one."
-                ))
-            }
-            _ => (),
+    fn p_label(pos: usize, tokens: &[Spanned]) -> Result<(String, usize)> {
+        if let Token::Lf = Self::at(pos, tokens)? {
+            return Err(parse_error!(
+                pos,
+                "labels must start with space or tag at least one."
+            ));
         }
 
         let mut label = String::new();
-        let mut pos = pos + 1;
+        // 本書の例（forever_a.ws）にならい、末尾のLFが無くてもプログラムの終わりでラベルを終える
+        let mut pos = pos;
         while pos < tokens.len() {
-            match tokens[pos] {
+            match tokens[pos].token {
                 Token::Space => label.push('s'),
                 Token::Tab => label.push('t'),
                 Token::Lf => {
@@ -241,3 +357,95 @@ impl Compiler {
         Ok((label, pos))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans() {
+        // push 1, dup
+        let code = "x   \t\n \n ";
+        let (insts, spans) = Compiler::new(code.to_owned()).compile_with_spans().unwrap();
+        assert!(matches!(insts[0], Instruction::Push(_)));
+        assert!(matches!(insts[1], Instruction::Dup));
+        assert_eq!((1, 2), (spans[0].start.line, spans[0].start.column));
+        assert_eq!("SSSTL", spans[0].visible(code, Notation::Whitespace));
+        assert_eq!((2, 1), (spans[1].start.line, spans[1].start.column));
+        assert_eq!("SLS", spans[1].visible(code, Notation::Whitespace));
+    }
+
+    #[test]
+    fn grammar_error() {
+        let code = "   \t\n \t\t";
+        let err = Compiler::new(code.to_owned()).compile().unwrap_err();
+        let expect = "\
+[STT] is grammar error.
+ --> line 2, column 1
+  |
+2 | STT
+  | ^^^
+  = sequence: STT";
+        assert_eq!(expect, err.to_string());
+    }
+
+    #[test]
+    fn visible_grammar_error() {
+        // 位置は見える表記のコード上で示す
+        let code = "# push 1\nSS ST L\n\"oops\" S TT L\n";
+        let err = Compiler::new(code.to_owned())
+            .with_notation(Notation::Visible)
+            .compile()
+            .unwrap_err();
+        let expect = "\
+[STT] is grammar error.
+ --> line 3, column 8
+  |
+3 | \"oops\" S TT L
+  |        ^^^^
+  = sequence: STT";
+        assert_eq!(expect, err.to_string());
+    }
+
+    #[test]
+    fn extensions() {
+        // dumps, dumph, host -5, exit
+        let code = "\n\n \n\n\t\t\n\n\t\t \t\n\n\n\n";
+        assert!(Compiler::new(code.to_owned()).compile().is_err());
+        let insts = Compiler::new(code.to_owned())
+            .with_extensions()
+            .compile()
+            .unwrap();
+        assert_eq!(
+            vec![
+                Instruction::DumpStack,
+                Instruction::DumpHeap,
+                Instruction::HostCall(-5),
+                Instruction::Exit
+            ],
+            insts
+        );
+        assert_eq!(code, crate::instruction::to_ws(&insts));
+    }
+
+    #[test]
+    fn unexpected_end() {
+        let code = "\t ";
+        let err = Compiler::new(code.to_owned()).compile().unwrap_err();
+        let expect = "\
+unexpected end of program.
+ --> line 1, column 1
+  |
+1 | TS
+  | ^^
+  = sequence: TS";
+        assert_eq!(expect, err.to_string());
+    }
+
+    #[test]
+    fn label_at_end() {
+        let code = "\n \n\t ";
+        let insts = Compiler::new(code.to_owned()).compile().unwrap();
+        assert_eq!(vec![Instruction::Jump("ts".to_owned())], insts);
+    }
+}
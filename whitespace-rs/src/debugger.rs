@@ -0,0 +1,442 @@
+//! 行単位のコマンドで操作するステップ実行デバッガ
+//!
+//! コマンドは入力から1行ずつ読むので、テストからも標準入力からも同じように操作できる
+//! 実行した命令は記録しておき、逆向きにも実行できる
+
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    history::HeapWrite,
+    instruction::Instruction,
+    number::Number,
+    vm::{Status, VM},
+};
+
+const HELP: &str = "\
+break <index|label>   (b)  set a breakpoint
+delete <index|label>  (d)  delete a breakpoint
+breakpoints           (bl) list breakpoints
+step                  (s)  execute one instruction
+next                  (n)  execute one instruction, stepping over calls
+continue              (c)  run until a breakpoint or the end
+rstep                 (rs) step back one instruction
+rcontinue             (rc) run backwards until a breakpoint or the start
+lastwrite <address>   (lw) run backwards to the last write of heap[address]
+writer <address>           show which instruction last wrote heap[address]
+where                 (w)  show the next instruction
+stack                      print the value stack (top last)
+heap                       print the heap
+calls                      print the call stack (innermost last)
+help                  (h)  show this message
+quit                  (q)  quit the debugger";
+
+/// I, O: デバッグするプログラムの入出力
+/// R, W: デバッガのコマンドの入出力
+pub struct Debugger<I: BufRead, O: Write, R: BufRead, W: Write> {
+    vm: VM<I, O>,
+    /// 表示用の命令列
+    insts: Vec<Instruction>,
+    breakpoints: BTreeSet<usize>,
+    /// 実行時エラーで止まった場合はそのメッセージ
+    error: Option<String>,
+    input: R,
+    output: W,
+}
+
+impl<I: BufRead, O: Write, R: BufRead, W: Write> Debugger<I, O, R, W> {
+    /// instsはvmに渡したものと同じ命令列
+    pub fn new(vm: VM<I, O>, insts: Vec<Instruction>, input: R, output: W) -> Self {
+        Self {
+            vm: vm.with_history(),
+            insts,
+            breakpoints: BTreeSet::new(),
+            error: None,
+            input,
+            output,
+        }
+    }
+
+    pub fn vm(&self) -> &VM<I, O> {
+        &self.vm
+    }
+
+    /// 入力が尽きるかquitまでコマンドを処理する
+    pub fn run(&mut self) -> Result<()> {
+        self.print_location()?;
+        loop {
+            write!(self.output, "(wsdb) ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                writeln!(self.output)?;
+                return Ok(());
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+
+            let res = match words[0] {
+                "break" | "b" => self.set_breakpoint(&words[1..]),
+                "delete" | "d" => self.delete_breakpoint(&words[1..]),
+                "breakpoints" | "bl" => self.print_breakpoints(),
+                "step" | "s" => self.step(),
+                "next" | "n" => self.next(),
+                "continue" | "c" => self.cont(),
+                "rstep" | "rs" => self.rstep(),
+                "rcontinue" | "rc" => self.rcont(),
+                "lastwrite" | "lw" => self.run_back_to_write(&words[1..]),
+                "writer" => self.print_writer(&words[1..]),
+                "where" | "w" => self.print_location(),
+                "stack" => self.print_stack(),
+                "heap" => self.print_heap(),
+                "calls" => self.print_calls(),
+                "help" | "h" => writeln!(self.output, "{}", HELP).map_err(|e| e.into()),
+                "quit" | "q" => return Ok(()),
+                cmd => Err(anyhow::anyhow!("unknown command: {}. type `help`.", cmd)),
+            };
+            if let Err(e) = res {
+                writeln!(self.output, "{}", e)?;
+            }
+        }
+    }
+
+    /// 命令の添字か、s/t表記のラベル（ラベル直後の命令を指す）
+    fn p_location(&self, args: &[&str]) -> Result<usize> {
+        let arg = args
+            .first()
+            .context("missing an instruction index or label.")?;
+        if let Ok(index) = arg.parse::<usize>() {
+            if index >= self.insts.len() {
+                return Err(anyhow::anyhow!("index {} is out of the program.", index));
+            }
+            return Ok(index);
+        }
+        let index = self
+            .vm
+            .program()
+            .labels
+            .get(*arg)
+            .with_context(|| format!("label is not found. label name: {}", arg))?;
+        Ok(index + 1)
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) -> Result<()> {
+        let index = self.p_location(args)?;
+        self.breakpoints.insert(index);
+        writeln!(self.output, "breakpoint at {}", self.describe(index))?;
+        Ok(())
+    }
+
+    fn delete_breakpoint(&mut self, args: &[&str]) -> Result<()> {
+        let index = self.p_location(args)?;
+        if !self.breakpoints.remove(&index) {
+            return Err(anyhow::anyhow!("no breakpoint at {}.", index));
+        }
+        writeln!(
+            self.output,
+            "deleted breakpoint at {}",
+            self.describe(index)
+        )?;
+        Ok(())
+    }
+
+    fn print_breakpoints(&mut self) -> Result<()> {
+        if self.breakpoints.is_empty() {
+            writeln!(self.output, "no breakpoints.")?;
+        }
+        for index in self.breakpoints.iter() {
+            writeln!(self.output, "{}", self.describe(*index))?;
+        }
+        Ok(())
+    }
+
+    /// 1命令実行する
+    /// 終了したかエラーで止まった場合はfalse
+    fn step_vm(&mut self) -> Result<bool> {
+        if let Some(e) = &self.error {
+            return Err(anyhow::anyhow!(
+                "the program has stopped with an error: {}",
+                e
+            ));
+        }
+        if self.vm.is_exited() {
+            return Err(anyhow::anyhow!("the program has exited."));
+        }
+
+        match self.vm.step() {
+            Ok(Status::Running) => Ok(true),
+            Ok(Status::Exited) => {
+                writeln!(self.output, "the program has exited.")?;
+                Ok(false)
+            }
+            Err(e) => {
+                writeln!(self.output, "error: {}", e)?;
+                self.error = Some(e.to_string());
+                Ok(false)
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<()> {
+        if self.step_vm()? {
+            self.print_location()?;
+        }
+        Ok(())
+    }
+
+    /// Callならサブルーチンから戻るまで実行する
+    fn next(&mut self) -> Result<()> {
+        let depth = self.vm.call_stack().len();
+        if !self.step_vm()? {
+            return Ok(());
+        }
+        while self.vm.call_stack().len() > depth {
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return self.print_stop();
+            }
+            if !self.step_vm()? {
+                return Ok(());
+            }
+        }
+        self.print_location()
+    }
+
+    fn cont(&mut self) -> Result<()> {
+        if !self.step_vm()? {
+            return Ok(());
+        }
+        while !self.breakpoints.contains(&self.vm.pc()) {
+            if !self.step_vm()? {
+                return Ok(());
+            }
+        }
+        self.print_stop()
+    }
+
+    /// 1命令戻る
+    /// 記録の先頭まで戻っていればfalse
+    fn step_back_vm(&mut self) -> Result<bool> {
+        if !self.vm.step_back() {
+            writeln!(self.output, "reached the start of the recording.")?;
+            return Ok(false);
+        }
+        self.error = None;
+        Ok(true)
+    }
+
+    fn rstep(&mut self) -> Result<()> {
+        if self.step_back_vm()? {
+            self.print_location()?;
+        }
+        Ok(())
+    }
+
+    fn rcont(&mut self) -> Result<()> {
+        if !self.step_back_vm()? {
+            return Ok(());
+        }
+        while !self.breakpoints.contains(&self.vm.pc()) {
+            if !self.step_back_vm()? {
+                return self.print_location();
+            }
+        }
+        self.print_stop()
+    }
+
+    fn last_write(&self, args: &[&str]) -> Result<HeapWrite> {
+        let arg = args.first().context("missing a heap address.")?;
+        let address: Number = arg
+            .parse()
+            .with_context(|| format!("invalid heap address: {}", arg))?;
+        self.vm
+            .history()
+            .and_then(|h| h.last_write(&address))
+            .with_context(|| format!("heap[{}] has not been written.", address))
+    }
+
+    /// 書き込んだ命令の実行前まで戻る
+    fn run_back_to_write(&mut self, args: &[&str]) -> Result<()> {
+        let write = self.last_write(args)?;
+        while self.vm.history().map_or(0, |h| h.len()) > write.index {
+            self.step_back_vm()?;
+        }
+        self.print_location()
+    }
+
+    fn print_writer(&mut self, args: &[&str]) -> Result<()> {
+        let write = self.last_write(args)?;
+        let old = write.old.map_or("unset".to_owned(), |n| n.to_string());
+        writeln!(
+            self.output,
+            "last written at step {} by {} (previous value: {})",
+            write.step,
+            self.describe(write.pc),
+            old
+        )?;
+        Ok(())
+    }
+
+    fn print_stop(&mut self) -> Result<()> {
+        write!(self.output, "breakpoint: ")?;
+        self.print_location()
+    }
+
+    fn print_location(&mut self) -> Result<()> {
+        let pc = self.vm.pc();
+        writeln!(self.output, "=> {}", self.describe(pc))?;
+        Ok(())
+    }
+
+    fn describe(&self, index: usize) -> String {
+        match self.insts.get(index) {
+            Some(inst) => format!("[{}] {}", index, inst),
+            None => format!("[{}] <end of program>", index),
+        }
+    }
+
+    fn print_stack(&mut self) -> Result<()> {
+        let items: Vec<String> = self.vm.stack().iter().map(|n| n.to_string()).collect();
+        writeln!(self.output, "[{}]", items.join(", "))?;
+        Ok(())
+    }
+
+    fn print_heap(&mut self) -> Result<()> {
+        let mut entries: Vec<_> = self.vm.heap().iter().collect();
+        entries.sort();
+        if entries.is_empty() {
+            writeln!(self.output, "the heap is empty.")?;
+        }
+        for (address, value) in entries.into_iter() {
+            writeln!(self.output, "{}: {}", address, value)?;
+        }
+        Ok(())
+    }
+
+    fn print_calls(&mut self) -> Result<()> {
+        if self.vm.call_stack().is_empty() {
+            writeln!(self.output, "the call stack is empty.")?;
+        }
+        let calls: Vec<usize> = self.vm.call_stack().to_vec();
+        for ret in calls.into_iter() {
+            // 戻り先の1つ前がCall
+            writeln!(
+                self.output,
+                "called from {}",
+                self.describe(ret.saturating_sub(1))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::assembler;
+
+    fn debug(asm: &str, commands: &str) -> String {
+        let insts = assembler::parse(asm).unwrap();
+        let vm = VM::new(insts.clone(), io::empty(), io::sink());
+        let mut output = vec![];
+        let mut debugger = Debugger::new(vm, insts, commands.as_bytes(), &mut output);
+        debugger.run().unwrap();
+        String::from_utf8(output).unwrap().replace("(wsdb) ", "")
+    }
+
+    const PROGRAM: &str = "
+        push 1
+        push 2
+        call st
+        push 10
+        store
+        exit
+    st:
+        add
+        ret
+    ";
+
+    #[test]
+    fn step_and_inspect() {
+        let output = debug(PROGRAM, "s\ns\nstack\nn\nstack\nc\nheap\n");
+        let expect = "\
+=> [0] push 1
+=> [1] push 2
+=> [2] call st
+[1, 2]
+=> [3] push 10
+[3]
+the program has exited.
+3: 10
+
+";
+        assert_eq!(expect, output);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let output = debug(PROGRAM, "b st\nb 4\nc\ncalls\nc\nstack\nd 4\nbl\nq\n");
+        let expect = "\
+=> [0] push 1
+breakpoint at [7] add
+breakpoint at [4] store
+breakpoint: => [7] add
+called from [2] call st
+breakpoint: => [4] store
+[3, 10]
+deleted breakpoint at [4] store
+[7] add
+";
+        assert_eq!(expect, output);
+    }
+
+    #[test]
+    fn runtime_error() {
+        let output = debug("add\nexit\n", "s\ns\nstack\n");
+        let expect = "\
+=> [0] add
+error: cannot pop from the empty stack.
+the program has stopped with an error: cannot pop from the empty stack.
+[]
+
+";
+        assert_eq!(expect, output);
+    }
+
+    #[test]
+    fn reverse() {
+        let output = debug(
+            PROGRAM,
+            "c\nwriter 3\nlw 3\nstack\nheap\nrs\nrs\nstack\ns\nb st\nrc\nrc\nrs\nc\nc\n",
+        );
+        let expect = "\
+=> [0] push 1
+the program has exited.
+last written at step 7 by [4] store (previous value: unset)
+=> [4] store
+[3, 10]
+the heap is empty.
+=> [3] push 10
+=> [8] ret
+[3]
+=> [3] push 10
+breakpoint at [7] add
+breakpoint: => [7] add
+reached the start of the recording.
+=> [0] push 1
+reached the start of the recording.
+breakpoint: => [7] add
+the program has exited.
+
+";
+        assert_eq!(expect, output);
+    }
+}
@@ -0,0 +1,157 @@
+//! Whitespaceのコードを注釈付きのアセンブリとして表示する
+//!
+//! ```text
+//! label st        ; index  line:col  encoding
+//!     push 1      ;     0   4:1      SSSTL
+//! ```
+//!
+//! ラベルはs/t表記のまま出力するので、そのままアセンブラに戻せる
+
+use anyhow::Result;
+
+use crate::compiler::Compiler;
+use crate::instruction::Instruction;
+use crate::source::Span;
+use crate::token::Notation;
+
+/// 1命令分の注釈
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub index: usize,
+    pub inst: Instruction,
+    pub span: Span,
+    /// S/T/L表記の元のコード
+    pub encoding: String,
+}
+
+/// 命令と、その元になったコードの位置を対応づける
+/// 見える表記のコードなら位置もその中で示す。extensionsなら拡張命令も読む
+pub fn entries(src_code: &str, notation: Notation, extensions: bool) -> Result<Vec<Entry>> {
+    let compiler = Compiler::new(src_code.to_owned()).with_notation(notation);
+    let compiler = if extensions {
+        compiler.with_extensions()
+    } else {
+        compiler
+    };
+    let (insts, spans) = compiler.compile_with_spans()?;
+    let entries = insts
+        .into_iter()
+        .zip(spans)
+        .enumerate()
+        .map(|(index, (inst, span))| Entry {
+            index,
+            inst,
+            span,
+            encoding: span.visible(src_code, notation),
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// 注釈付きのアセンブリを返す
+pub fn disassemble(src_code: &str, notation: Notation, extensions: bool) -> Result<String> {
+    let entries = entries(src_code, notation, extensions)?;
+    Ok(listing(&entries))
+}
+
+pub fn listing(entries: &[Entry]) -> String {
+    let asm: Vec<String> = entries
+        .iter()
+        .map(|e| match e.inst {
+            Instruction::Label(_) => e.inst.to_string(),
+            _ => format!("    {}", e.inst),
+        })
+        .collect();
+    let positions: Vec<String> = entries
+        .iter()
+        .map(|e| format!("{}:{}", e.span.start.line, e.span.start.column))
+        .collect();
+
+    let asm_width = asm.iter().map(|a| a.len()).max().unwrap_or(0).max(5);
+    let index_width = entries.len().saturating_sub(1).to_string().len().max(5);
+    let pos_width = positions.iter().map(|p| p.len()).max().unwrap_or(0).max(8);
+
+    let mut res = format!(
+        "{:aw$}  ; {:>iw$}  {:pw$}  encoding\n",
+        "",
+        "index",
+        "line:col",
+        aw = asm_width,
+        iw = index_width,
+        pw = pos_width
+    );
+    for ((e, asm), pos) in entries.iter().zip(asm.iter()).zip(positions.iter()) {
+        res.push_str(&format!(
+            "{:aw$}  ; {:>iw$}  {:pw$}  {}\n",
+            asm,
+            e.index,
+            pos,
+            e.encoding,
+            aw = asm_width,
+            iw = index_width,
+            pw = pos_width
+        ));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+
+    #[test]
+    fn annotate() {
+        let code = "hello   \t\n\n   \t\n\n\n\n";
+        let entries = entries(code, Notation::Whitespace, false).unwrap();
+        assert_eq!(3, entries.len());
+        assert_eq!("push 1", entries[0].inst.to_string());
+        assert_eq!("SSSTL", entries[0].encoding);
+        assert_eq!(
+            (1, 6),
+            (entries[0].span.start.line, entries[0].span.start.column)
+        );
+        assert_eq!("label st", entries[1].inst.to_string());
+        assert_eq!("LSSSTL", entries[1].encoding);
+        assert_eq!(
+            (2, 1),
+            (entries[1].span.start.line, entries[1].span.start.column)
+        );
+        assert_eq!("exit", entries[2].inst.to_string());
+    }
+
+    #[test]
+    fn annotate_visible() {
+        let code = "\"hello\" SSSTL\nLSSSTL\nLLL\n";
+        let entries = entries(code, Notation::Visible, false).unwrap();
+        assert_eq!("SSSTL", entries[0].encoding);
+        assert_eq!(
+            (2, 1),
+            (entries[1].span.start.line, entries[1].span.start.column)
+        );
+    }
+
+    #[test]
+    fn reassemble() {
+        let code = "   \t\n\n  \t \n\n \t \t\n\n\n\n";
+        let listing = disassemble(code, Notation::Whitespace, false).unwrap();
+        let insts = assembler::parse(&listing).unwrap();
+        let expect = Compiler::new(code.to_owned()).compile().unwrap();
+        assert_eq!(expect, insts);
+    }
+
+    #[test]
+    fn extensions() {
+        // dumps, dumph, host 1, exit
+        let code = "\n\n \n\n\t\t\n\n \t\n\n\n\n";
+        assert!(disassemble(code, Notation::Whitespace, false).is_err());
+        let listing = disassemble(code, Notation::Whitespace, true).unwrap();
+        let insts = assembler::parse(&listing).unwrap();
+        let expect = Compiler::new(code.to_owned())
+            .with_extensions()
+            .compile()
+            .unwrap();
+        assert_eq!(expect, insts);
+        assert_eq!(Instruction::HostCall(1), insts[2]);
+    }
+}
@@ -0,0 +1,212 @@
+//! 逆実行のための記録
+//!
+//! 1命令ごとに、実行前の状態へ戻すのに必要な分だけを残す
+//!
+//! - スタック: 命令が取り出しうる上からの要素。命令はその個数より下を書き換えない
+//! - ヒープ: 書き込んだアドレスと書き込む前の値
+//! - 呼び出しスタック: 実行前の深さと一番上の戻り先
+//! - 入力: 読み込んだバイト列。戻すと次に読む入力の前に戻す
+//!
+//! 出力は取り消せない
+
+use std::convert::TryFrom;
+
+use crate::{linker::Op, number::Number};
+
+/// 1命令分の記録
+#[derive(Debug, Clone)]
+pub(crate) struct Record {
+    pub(crate) pc: usize,
+    pub(crate) steps: u64,
+    pub(crate) written: u64,
+    pub(crate) exited: bool,
+    /// 実行前のスタックの要素数
+    pub(crate) depth: usize,
+    /// 実行前のスタックの上から、命令が取り出しうる要素
+    pub(crate) top: Vec<Number>,
+    /// 実行前の呼び出しスタックの深さと一番上の戻り先
+    pub(crate) calls: usize,
+    pub(crate) call_top: Option<usize>,
+    /// 書き込んだアドレスと書き込む前の値。書き込んだ順
+    pub(crate) heap: Vec<(Number, Option<Number>)>,
+    pub(crate) input: Vec<u8>,
+}
+
+/// ヒープへの書き込み
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapWrite {
+    /// 何番目の記録か。ここまで戻ると書き込んだ命令の実行前になる
+    pub index: usize,
+    /// 何命令目か（1から）
+    pub step: u64,
+    pub pc: usize,
+    /// 書き込む前の値。Noneなら未使用のアドレス
+    pub old: Option<Number>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    records: Vec<Record>,
+}
+
+impl History {
+    /// 記録した命令数
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// addressに最後に書き込んだ命令
+    pub fn last_write(&self, address: &Number) -> Option<HeapWrite> {
+        for (index, record) in self.records.iter().enumerate().rev() {
+            let write = record.heap.iter().rposition(|(a, _)| a == address);
+            if let Some(i) = write {
+                return Some(HeapWrite {
+                    index,
+                    step: record.steps + 1,
+                    pc: record.pc,
+                    old: record.heap[i].1.clone(),
+                });
+            }
+        }
+        None
+    }
+
+    pub(crate) fn push(&mut self, record: Record) {
+        self.records.push(record);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Record> {
+        self.records.pop()
+    }
+
+    pub(crate) fn heap_write(&mut self, address: Number, old: Option<Number>) {
+        if let Some(record) = self.records.last_mut() {
+            record.heap.push((address, old));
+        }
+    }
+
+    pub(crate) fn input(&mut self, buf: &[u8]) {
+        if let Some(record) = self.records.last_mut() {
+            record.input.extend_from_slice(buf);
+        }
+    }
+}
+
+/// 命令がスタックの上から取り出しうる要素数
+pub(crate) fn consumed(op: &Op) -> usize {
+    match op {
+        Op::Swap | Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod | Op::HeapWrite => 2,
+        Op::Discard
+        | Op::HeapRead
+        | Op::JumpZero(_)
+        | Op::JumpNeg(_)
+        | Op::CharOut
+        | Op::NumOut
+        | Op::CharIn
+        | Op::NumIn
+        | Op::PushArith(_, _) => 1,
+        // 負の数ならスタックが空になるまで取り出す
+        Op::Slide(n) => usize::try_from(*n).map_or(usize::MAX, |n| n.saturating_add(1)),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        assembler,
+        vm::{Status, VM},
+    };
+
+    type State = (usize, Vec<Number>, Vec<(Number, Number)>, Vec<usize>, u64);
+
+    fn state<R: io::BufRead, W: io::Write>(vm: &VM<R, W>) -> State {
+        let mut heap: Vec<(Number, Number)> = vm
+            .heap()
+            .iter()
+            .map(|(a, v)| (a.clone(), v.clone()))
+            .collect();
+        heap.sort();
+        (
+            vm.pc(),
+            vm.stack().to_vec(),
+            heap,
+            vm.call_stack().to_vec(),
+            vm.steps(),
+        )
+    }
+
+    const PROGRAM: &str = "
+        push 1
+        push 5
+        push 6
+        push 7
+        slide 2
+        push 2
+        inc
+        push 2
+        call st
+        store
+        push 2
+        load
+        outn
+        exit
+    st:
+        dup
+        swap
+        ret
+    ";
+
+    #[test]
+    fn step_back() {
+        let insts = assembler::parse(PROGRAM).unwrap();
+        let mut vm = VM::new(insts, "xy".as_bytes(), io::sink()).with_history();
+        // states[i]はi命令実行した後の状態
+        let mut states = vec![state(&vm)];
+        while vm.step().unwrap() == Status::Running {
+            states.push(state(&vm));
+        }
+        assert_eq!(vm.steps() as usize, vm.history().unwrap().len());
+
+        while let Some(expect) = states.pop() {
+            assert!(vm.step_back());
+            assert_eq!(expect, state(&vm));
+        }
+        assert!(!vm.is_exited());
+        assert!(!vm.step_back());
+
+        // 入力も戻っているので、もう一度同じように実行できる
+        vm.run().unwrap();
+        assert!(vm.is_exited());
+        assert_eq!(Some(&Number::from(2)), vm.heap().get(&Number::from(2)));
+    }
+
+    #[test]
+    fn last_write() {
+        let insts = assembler::parse(PROGRAM).unwrap();
+        let mut vm = VM::new(insts, "xy".as_bytes(), io::sink()).with_history();
+        vm.run().unwrap();
+        let history = vm.history().unwrap();
+        let write = history.last_write(&Number::from(2)).unwrap();
+        assert_eq!(9, write.pc);
+        assert_eq!(Some(Number::from('x' as i64)), write.old);
+        assert_eq!(None, history.last_write(&Number::from(3)));
+
+        // 書き込む前まで戻る
+        while vm.history().unwrap().len() > write.index {
+            vm.step_back();
+        }
+        assert_eq!(9, vm.pc());
+        assert_eq!(
+            Some(&Number::from('x' as i64)),
+            vm.heap().get(&Number::from(2))
+        );
+    }
+}
@@ -1,6 +1,10 @@
-#[derive(Debug, Clone)]
+use std::fmt;
+
+use crate::number::Number;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
-    Push(i64),
+    Push(Number),
     Dup,
     Copy(i64),
     Swap,
@@ -24,4 +28,108 @@ pub enum Instruction {
     NumOut,
     CharIn,
     NumIn,
+    /// 以下は拡張命令（``Compiler::with_extensions``）
+    /// スタックの中身を書き出す
+    DumpStack,
+    /// ヒープの中身を書き出す
+    DumpHeap,
+    /// VMに登録したホスト関数を番号で呼ぶ
+    HostCall(i64),
+}
+
+impl Instruction {
+    /// Whitespaceのコードに変換する
+    pub fn to_ws(&self) -> String {
+        match self {
+            Self::Push(n) => format!("  {}", encode_num(n)),
+            Self::Dup => " \n ".to_owned(),
+            Self::Copy(n) => format!(" \t {}", encode_num(&Number::from(*n))),
+            Self::Swap => " \n\t".to_owned(),
+            Self::Discard => " \n\n".to_owned(),
+            Self::Slide(n) => format!(" \t\n{}", encode_num(&Number::from(*n))),
+            Self::Add => "\t   ".to_owned(),
+            Self::Sub => "\t  \t".to_owned(),
+            Self::Mul => "\t  \n".to_owned(),
+            Self::Div => "\t \t ".to_owned(),
+            Self::Mod => "\t \t\t".to_owned(),
+            Self::HeapWrite => "\t\t ".to_owned(),
+            Self::HeapRead => "\t\t\t".to_owned(),
+            Self::Label(l) => format!("\n  {}", encode_label(l)),
+            Self::Call(l) => format!("\n \t{}", encode_label(l)),
+            Self::Jump(l) => format!("\n \n{}", encode_label(l)),
+            Self::JumpZero(l) => format!("\n\t {}", encode_label(l)),
+            Self::JumpNeg(l) => format!("\n\t\t{}", encode_label(l)),
+            Self::Return => "\n\t\n".to_owned(),
+            Self::Exit => "\n\n\n".to_owned(),
+            Self::CharOut => "\t\n  ".to_owned(),
+            Self::NumOut => "\t\n \t".to_owned(),
+            Self::CharIn => "\t\n\t ".to_owned(),
+            Self::NumIn => "\t\n\t\t".to_owned(),
+            Self::DumpStack => "\n\n ".to_owned(),
+            Self::DumpHeap => "\n\n\t".to_owned(),
+            Self::HostCall(n) => format!("\t\n\n{}", encode_num(&Number::from(*n))),
+        }
+    }
+}
+
+/// アセンブラのニーモニック表記
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Push(n) => write!(f, "push {}", n),
+            Self::Dup => write!(f, "dup"),
+            Self::Copy(n) => write!(f, "copy {}", n),
+            Self::Swap => write!(f, "swap"),
+            Self::Discard => write!(f, "discard"),
+            Self::Slide(n) => write!(f, "slide {}", n),
+            Self::Add => write!(f, "add"),
+            Self::Sub => write!(f, "sub"),
+            Self::Mul => write!(f, "mul"),
+            Self::Div => write!(f, "div"),
+            Self::Mod => write!(f, "mod"),
+            Self::HeapWrite => write!(f, "store"),
+            Self::HeapRead => write!(f, "load"),
+            Self::Label(l) => write!(f, "label {}", l),
+            Self::Call(l) => write!(f, "call {}", l),
+            Self::Jump(l) => write!(f, "jmp {}", l),
+            Self::JumpZero(l) => write!(f, "jz {}", l),
+            Self::JumpNeg(l) => write!(f, "jn {}", l),
+            Self::Return => write!(f, "ret"),
+            Self::Exit => write!(f, "exit"),
+            Self::CharOut => write!(f, "outc"),
+            Self::NumOut => write!(f, "outn"),
+            Self::CharIn => write!(f, "inc"),
+            Self::NumIn => write!(f, "inn"),
+            Self::DumpStack => write!(f, "dumps"),
+            Self::DumpHeap => write!(f, "dumph"),
+            Self::HostCall(n) => write!(f, "host {}", n),
+        }
+    }
+}
+
+/// 命令列をWhitespaceのコードに変換する
+pub fn to_ws(insts: &[Instruction]) -> String {
+    insts.iter().map(|inst| inst.to_ws()).collect()
+}
+
+/// 符号 + 先頭に0のない2進数 + LF
+fn encode_num(n: &Number) -> String {
+    let (negative, bin) = n.to_binary();
+    let mut res = String::new();
+    res.push(if negative { '\t' } else { ' ' });
+    for c in bin.chars() {
+        res.push(if c == '1' { '\t' } else { ' ' });
+    }
+    res.push('\n');
+    res
+}
+
+/// s/t表記のラベル + LF
+fn encode_label(label: &str) -> String {
+    let mut res: String = label
+        .chars()
+        .map(|c| if c == 't' { '\t' } else { ' ' })
+        .collect();
+    res.push('\n');
+    res
 }
@@ -0,0 +1,681 @@
+//! Craneliftで命令列をネイティブコードにJITコンパイルする
+//!
+//! スタック操作と四則演算はネイティブコードで実行し、VMのスタックの領域を直接読み書きする
+//! 入出力、ヒープ、サブルーチン呼び出しと、スタック不足や桁あふれなどの例外的な場合は
+//! ``VM::exec``を呼び出して1命令だけインタプリタで実行する
+//! そのため出力とエラーはインタプリタと同じになる
+//!
+//! 命令数と実行時間は数えないので、上限を設定したVMはインタプリタで実行する
+
+use std::{io::BufRead, io::Write, mem, rc::Rc};
+
+use anyhow::Result;
+use cranelift_codegen::{
+    ir::{
+        condcodes::IntCC, types, AbiParam, Block, BlockCall, FuncRef, InstBuilder, JumpTableData,
+        MemFlags, Value,
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use crate::{
+    arith::{Arith, Division, Overflow},
+    limits::Limits,
+    linker::{Op, Program},
+    number::Number,
+    vm::{Status, VM},
+};
+
+/// JITコンパイルしたコードとVMの間で受け渡す状態
+/// ネイティブコードから読み書きするのは先頭の4つだけ
+#[repr(C)]
+struct Ctx<'a, R: BufRead, W: Write> {
+    /// スタックの先頭。VMのVecの領域を指す
+    base: *mut Number,
+    /// スタックの容量
+    cap: usize,
+    /// スタックの要素数
+    sp: usize,
+    pc: usize,
+    vm: &'a mut VM<R, W>,
+    program: Rc<Program>,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a, R: BufRead, W: Write> Ctx<'a, R, W> {
+    /// VMのスタックの領域を読み直す
+    fn sync(&mut self) {
+        self.base = self.vm.stack.as_mut_ptr();
+        self.cap = self.vm.stack.capacity();
+        self.sp = self.vm.stack.len();
+        self.pc = self.vm.pc;
+    }
+}
+
+/// ネイティブコードから呼ばれ、pcの命令をインタプリタで実行する
+/// 0なら続行、1ならエラー
+extern "C" fn exec<R: BufRead, W: Write>(ctx: *mut Ctx<R, W>, pc: usize, sp: usize) -> i64 {
+    let ctx = unsafe { &mut *ctx };
+    // ネイティブコードが書き換えたスタックの長さを反映する
+    // 要素はi64なので、容量の範囲で長さを変えても問題ない
+    unsafe { ctx.vm.stack.set_len(sp) };
+    ctx.vm.pc = pc;
+    // 複合命令の2命令目はネイティブコードの次のブロックで実行するので、1命令目だけ実行する
+    let op = &ctx.program.ops[pc];
+    let head = op.head();
+    let res = ctx.vm.exec(head.as_ref().unwrap_or(op));
+    ctx.sync();
+    match res {
+        Ok(Status::Running) | Ok(Status::Exited) => 0,
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+/// JITコンパイルできる状態か
+/// 上限やトレースなどを設定したVMはインタプリタで実行する
+pub(crate) fn supports<R: BufRead, W: Write>(vm: &VM<R, W>) -> bool {
+    vm.limits == Limits::default() && vm.is_plain() && !vm.exited
+}
+
+/// VMの現在の状態から最後まで実行する
+pub(crate) fn run<R: BufRead, W: Write>(vm: &mut VM<R, W>) -> Result<()> {
+    let program = Rc::clone(&vm.program);
+    let module = compile::<R, W>(&program, vm.arith)?;
+
+    let mut ctx = Ctx {
+        base: std::ptr::null_mut(),
+        cap: 0,
+        sp: 0,
+        pc: 0,
+        vm,
+        program,
+        error: None,
+    };
+    ctx.sync();
+    let id = module
+        .get_name("main")
+        .and_then(|name| match name {
+            cranelift_module::FuncOrDataId::Func(id) => Some(id),
+            _ => None,
+        })
+        .expect("the compiled function is not found");
+    let code = module.get_finalized_function(id);
+    let status = unsafe {
+        let f: extern "C" fn(*mut Ctx<R, W>) -> i64 = mem::transmute(code);
+        f(&mut ctx)
+    };
+    let error = ctx.error.take();
+    let sp = ctx.sp;
+    let pc = ctx.pc;
+    let vm = ctx.vm;
+    unsafe {
+        vm.stack.set_len(sp);
+        module.free_memory();
+    }
+    vm.pc = pc;
+    vm.writer.flush()?;
+    match (status, error) {
+        (0, _) => {
+            vm.exited = true;
+            Ok(())
+        }
+        (_, Some(e)) => Err(vm.diagnose(e)),
+        (_, None) => unreachable!("the compiled code failed without an error"),
+    }
+}
+
+fn compile<R: BufRead, W: Write>(program: &Program, arith: Arith) -> Result<JITModule> {
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false")?;
+    flags.set("is_pic", "false")?;
+    flags.set("opt_level", "speed")?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| anyhow::anyhow!("JIT is not supported on this host: {}", e))?
+        .finish(settings::Flags::new(flags))?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    builder.symbol("exec", exec::<R, W> as *const u8);
+    let mut module = JITModule::new(builder);
+
+    let ptr = module.target_config().pointer_type();
+    let mut exec_sig = module.make_signature();
+    exec_sig.params.push(AbiParam::new(ptr));
+    exec_sig.params.push(AbiParam::new(types::I64));
+    exec_sig.params.push(AbiParam::new(types::I64));
+    exec_sig.returns.push(AbiParam::new(types::I64));
+    let exec_id = module.declare_function("exec", Linkage::Import, &exec_sig)?;
+
+    let mut ctx = module.make_context();
+    ctx.func.signature.params.push(AbiParam::new(ptr));
+    ctx.func.signature.returns.push(AbiParam::new(types::I64));
+    let main_id = module.declare_function("main", Linkage::Export, &ctx.func.signature)?;
+
+    let mut fctx = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+    let exec = module.declare_func_in_func(exec_id, b.func);
+    Translator::<R, W>::new(&mut b, exec, ptr, arith).translate(program);
+    b.seal_all_blocks();
+    b.finalize();
+
+    module.define_function(main_id, &mut ctx)?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions()?;
+    Ok(module)
+}
+
+/// 命令ごとにブロックを作り、ブロックの間を分岐でつなぐ
+struct Translator<'a, 'b, R: BufRead, W: Write> {
+    b: &'a mut FunctionBuilder<'b>,
+    exec: FuncRef,
+    ptr: types::Type,
+    arith: Arith,
+    ctx: Value,
+    sp: Variable,
+    base: Variable,
+    cap: Variable,
+    /// 命令ごとのブロック
+    blocks: Vec<Block>,
+    /// 末尾の番兵の位置
+    end: usize,
+    /// 引数の状態コードを返して終了するブロック
+    exit: Block,
+    marker: std::marker::PhantomData<(R, W)>,
+}
+
+impl<'a, 'b, R: BufRead, W: Write> Translator<'a, 'b, R, W> {
+    fn new(b: &'a mut FunctionBuilder<'b>, exec: FuncRef, ptr: types::Type, arith: Arith) -> Self {
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let ctx = b.block_params(entry)[0];
+        let sp = Variable::from_u32(0);
+        let base = Variable::from_u32(1);
+        let cap = Variable::from_u32(2);
+        b.declare_var(sp, types::I64);
+        b.declare_var(base, ptr);
+        b.declare_var(cap, types::I64);
+        let exit = b.create_block();
+        b.append_block_param(exit, types::I64);
+        Self {
+            b,
+            exec,
+            ptr,
+            arith,
+            ctx,
+            sp,
+            base,
+            cap,
+            blocks: vec![],
+            end: 0,
+            exit,
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    fn translate(mut self, program: &Program) {
+        self.blocks = program.ops.iter().map(|_| self.b.create_block()).collect();
+        self.end = program.len();
+
+        // VMの現在のpcから始める
+        self.reload();
+        let pc = self.load(mem::offset_of!(Ctx<R, W>, pc), types::I64);
+        self.dispatch(pc);
+
+        for (pc, op) in program.ops.iter().enumerate() {
+            self.b.switch_to_block(self.blocks[pc]);
+            self.op(pc, op);
+        }
+
+        self.b.switch_to_block(self.exit);
+        let status = self.b.block_params(self.exit)[0];
+        self.b.ins().return_(&[status]);
+    }
+
+    fn op(&mut self, pc: usize, op: &Op) {
+        // 複合命令の2命令目は次のブロックにあるので、1命令目だけ変換する
+        if let Some(head) = op.head() {
+            return self.op(pc, &head);
+        }
+        match op {
+            Op::Push(n) => {
+                let n = n
+                    .to_i64()
+                    .expect("Number is i64 without the bignum feature");
+                let ok = self.has_room();
+                self.fast_or_slow(ok, pc, |t| {
+                    let n = t.b.ins().iconst(types::I64, n);
+                    t.push(n);
+                });
+            }
+            Op::Dup => {
+                let ok = self.has_items(1);
+                let room = self.has_room();
+                let ok = self.b.ins().band(ok, room);
+                self.fast_or_slow(ok, pc, |t| {
+                    let x = t.peek(1);
+                    t.push(x);
+                });
+            }
+            Op::Copy(n) if *n >= 0 => {
+                let n = *n;
+                let ok = self.has_items(n.saturating_add(1));
+                let room = self.has_room();
+                let ok = self.b.ins().band(ok, room);
+                self.fast_or_slow(ok, pc, |t| {
+                    let x = t.peek(n + 1);
+                    t.push(x);
+                });
+            }
+            Op::Swap => {
+                let ok = self.has_items(2);
+                self.fast_or_slow(ok, pc, |t| {
+                    let x = t.peek(1);
+                    let y = t.peek(2);
+                    t.poke(1, y);
+                    t.poke(2, x);
+                });
+            }
+            Op::Discard => {
+                let sp = self.b.use_var(self.sp);
+                let dec = self.b.ins().iadd_imm(sp, -1);
+                let sp = self.b.ins().select(sp, dec, sp);
+                self.b.def_var(self.sp, sp);
+                self.next(pc);
+            }
+            Op::Slide(n) if *n >= 0 => {
+                let n = *n;
+                let ok = self.has_items(n.saturating_add(1));
+                self.fast_or_slow(ok, pc, |t| {
+                    let x = t.peek(1);
+                    t.drop(n);
+                    t.poke(1, x);
+                });
+            }
+            Op::Add | Op::Sub | Op::Mul => self.arith(pc, op),
+            Op::Div | Op::Mod => self.division(pc, op),
+            Op::Label => self.next(pc),
+            Op::Call(dest) => {
+                // 呼び出しスタックはVMのものを使う
+                self.slow(pc);
+                self.b.ins().jump(self.blocks[*dest], &[]);
+            }
+            Op::Jump(dest) => {
+                self.b.ins().jump(self.blocks[*dest], &[]);
+            }
+            Op::JumpZero(dest) | Op::JumpNeg(dest) => {
+                let ok = self.has_items(1);
+                let fast = self.b.create_block();
+                let slow = self.b.create_block();
+                self.b.ins().brif(ok, fast, &[], slow, &[]);
+
+                self.b.switch_to_block(fast);
+                let x = self.peek(1);
+                self.drop(1);
+                let taken = match op {
+                    Op::JumpZero(_) => self.b.ins().icmp_imm(IntCC::Equal, x, 0),
+                    _ => self.b.ins().icmp_imm(IntCC::SignedLessThan, x, 0),
+                };
+                let next = self.blocks[pc + 1];
+                self.b.ins().brif(taken, self.blocks[*dest], &[], next, &[]);
+
+                // 空のスタックからpopしようとしたエラー
+                self.b.switch_to_block(slow);
+                self.slow(pc);
+                self.b.ins().jump(next, &[]);
+            }
+            Op::Return => {
+                self.slow(pc);
+                let pc = self.load(mem::offset_of!(Ctx<R, W>, pc), types::I64);
+                self.dispatch(pc);
+            }
+            Op::Exit => {
+                let sp = self.b.use_var(self.sp);
+                self.store(mem::offset_of!(Ctx<R, W>, sp), sp);
+                let pc = self.b.ins().iconst(types::I64, pc as i64);
+                self.store(mem::offset_of!(Ctx<R, W>, pc), pc);
+                let ok = self.b.ins().iconst(types::I64, 0);
+                self.b.ins().jump(self.exit, &[ok]);
+            }
+            // 実行時エラーになる末尾の番兵と未定義のラベル
+            Op::End | Op::Undefined(_) => {
+                let status = self.call_exec(pc);
+                self.b.ins().jump(self.exit, &[status]);
+            }
+            // 入出力、ヒープ、拡張命令、負の引数のcopy/slide
+            _ => {
+                self.slow(pc);
+                self.next(pc);
+            }
+        }
+    }
+
+    /// 加算・減算・乗算
+    fn arith(&mut self, pc: usize, op: &Op) {
+        let ok = self.has_items(2);
+        let fast = self.b.create_block();
+        let slow = self.b.create_block();
+        self.b.ins().brif(ok, fast, &[], slow, &[]);
+
+        self.b.switch_to_block(fast);
+        let l = self.peek(2);
+        let r = self.peek(1);
+        let x = if self.arith.overflow == Overflow::Wrap {
+            match op {
+                Op::Add => self.b.ins().iadd(l, r),
+                Op::Sub => self.b.ins().isub(l, r),
+                _ => self.b.ins().imul(l, r),
+            }
+        } else {
+            // 桁あふれした場合は方針に従ってインタプリタで計算し直す
+            let (x, overflowed) = match op {
+                Op::Add => self.b.ins().sadd_overflow(l, r),
+                Op::Sub => self.b.ins().ssub_overflow(l, r),
+                _ => self.b.ins().smul_overflow(l, r),
+            };
+            let done = self.b.create_block();
+            self.b.ins().brif(overflowed, slow, &[], done, &[]);
+            self.b.switch_to_block(done);
+            x
+        };
+        self.drop(1);
+        self.poke(1, x);
+        self.next(pc);
+
+        self.b.switch_to_block(slow);
+        self.slow(pc);
+        self.next(pc);
+    }
+
+    /// 除算・剰余
+    /// 0除算とi64::MIN / -1はインタプリタに任せる
+    fn division(&mut self, pc: usize, op: &Op) {
+        let ok = self.has_items(2);
+        let check = self.b.create_block();
+        let fast = self.b.create_block();
+        let slow = self.b.create_block();
+        self.b.ins().brif(ok, check, &[], slow, &[]);
+
+        self.b.switch_to_block(check);
+        let r = self.peek(1);
+        let zero = self.b.ins().icmp_imm(IntCC::Equal, r, 0);
+        let minus_one = self.b.ins().icmp_imm(IntCC::Equal, r, -1);
+        let special = self.b.ins().bor(zero, minus_one);
+        self.b.ins().brif(special, slow, &[], fast, &[]);
+
+        self.b.switch_to_block(fast);
+        let l = self.peek(2);
+        let q = self.b.ins().sdiv(l, r);
+        let m = self.b.ins().srem(l, r);
+        let floor = self.arith.division == Division::Floor;
+        let x = match op {
+            Op::Div if floor => {
+                // 符号が異なり割り切れなければ負の無限大方向に1つずらす
+                let sign = self.b.ins().bxor(l, r);
+                let differ = self.b.ins().icmp_imm(IntCC::SignedLessThan, sign, 0);
+                let inexact = self.b.ins().icmp_imm(IntCC::NotEqual, m, 0);
+                let adjust = self.b.ins().band(differ, inexact);
+                let adjust = self.b.ins().uextend(types::I64, adjust);
+                self.b.ins().isub(q, adjust)
+            }
+            Op::Div => q,
+            _ if floor => {
+                // 剰余を除数と同じ符号にする
+                let sign = self.b.ins().bxor(m, r);
+                let differ = self.b.ins().icmp_imm(IntCC::SignedLessThan, sign, 0);
+                let inexact = self.b.ins().icmp_imm(IntCC::NotEqual, m, 0);
+                let adjust = self.b.ins().band(differ, inexact);
+                let shifted = self.b.ins().iadd(m, r);
+                self.b.ins().select(adjust, shifted, m)
+            }
+            _ => m,
+        };
+        self.drop(1);
+        self.poke(1, x);
+        self.next(pc);
+
+        self.b.switch_to_block(slow);
+        self.slow(pc);
+        self.next(pc);
+    }
+
+    /// okなら`fast`の処理、そうでなければインタプリタで実行して次の命令に進む
+    fn fast_or_slow(&mut self, ok: Value, pc: usize, fast: impl FnOnce(&mut Self)) {
+        let fast_block = self.b.create_block();
+        let slow_block = self.b.create_block();
+        self.b.ins().brif(ok, fast_block, &[], slow_block, &[]);
+
+        self.b.switch_to_block(fast_block);
+        fast(self);
+        self.next(pc);
+
+        self.b.switch_to_block(slow_block);
+        self.slow(pc);
+        self.next(pc);
+    }
+
+    fn next(&mut self, pc: usize) {
+        self.b.ins().jump(self.blocks[pc + 1], &[]);
+    }
+
+    /// インタプリタで1命令実行し、スタックの状態を読み直す
+    fn slow(&mut self, pc: usize) {
+        let status = self.call_exec(pc);
+        let cont = self.b.create_block();
+        self.b.ins().brif(status, self.exit, &[status], cont, &[]);
+        self.b.switch_to_block(cont);
+        self.reload();
+    }
+
+    fn call_exec(&mut self, pc: usize) -> Value {
+        let pc = self.b.ins().iconst(types::I64, pc as i64);
+        let sp = self.b.use_var(self.sp);
+        let call = self.b.ins().call(self.exec, &[self.ctx, pc, sp]);
+        self.b.inst_results(call)[0]
+    }
+
+    /// pcの命令に分岐する
+    fn dispatch(&mut self, pc: Value) {
+        let pc = self.b.ins().ireduce(types::I32, pc);
+        let pool = &mut self.b.func.dfg.value_lists;
+        let default = BlockCall::new(self.blocks[self.end], &[], pool);
+        let table: Vec<BlockCall> = self
+            .blocks
+            .iter()
+            .map(|block| BlockCall::new(*block, &[], pool))
+            .collect();
+        let jt = self
+            .b
+            .create_jump_table(JumpTableData::new(default, &table));
+        self.b.ins().br_table(pc, jt);
+    }
+
+    fn reload(&mut self) {
+        let base = self.load(mem::offset_of!(Ctx<R, W>, base), self.ptr);
+        let cap = self.load(mem::offset_of!(Ctx<R, W>, cap), types::I64);
+        let sp = self.load(mem::offset_of!(Ctx<R, W>, sp), types::I64);
+        self.b.def_var(self.base, base);
+        self.b.def_var(self.cap, cap);
+        self.b.def_var(self.sp, sp);
+    }
+
+    fn load(&mut self, offset: usize, ty: types::Type) -> Value {
+        self.b
+            .ins()
+            .load(ty, MemFlags::trusted(), self.ctx, offset as i32)
+    }
+
+    fn store(&mut self, offset: usize, x: Value) {
+        self.b
+            .ins()
+            .store(MemFlags::trusted(), x, self.ctx, offset as i32);
+    }
+
+    /// スタックにn個以上積まれているか
+    fn has_items(&mut self, n: i64) -> Value {
+        let sp = self.b.use_var(self.sp);
+        self.b
+            .ins()
+            .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, sp, n)
+    }
+
+    /// 容量を増やさずにpushできるか
+    fn has_room(&mut self) -> Value {
+        let sp = self.b.use_var(self.sp);
+        let cap = self.b.use_var(self.cap);
+        self.b.ins().icmp(IntCC::UnsignedLessThan, sp, cap)
+    }
+
+    /// 上からn番目（1 indexed）の要素のアドレス
+    fn addr(&mut self, n: i64) -> Value {
+        let sp = self.b.use_var(self.sp);
+        let base = self.b.use_var(self.base);
+        let i = self.b.ins().iadd_imm(sp, -n);
+        let offset = self.b.ins().ishl_imm(i, 3);
+        self.b.ins().iadd(base, offset)
+    }
+
+    fn peek(&mut self, n: i64) -> Value {
+        let addr = self.addr(n);
+        self.b.ins().load(types::I64, MemFlags::trusted(), addr, 0)
+    }
+
+    fn poke(&mut self, n: i64, x: Value) {
+        let addr = self.addr(n);
+        self.b.ins().store(MemFlags::trusted(), x, addr, 0);
+    }
+
+    fn push(&mut self, x: Value) {
+        let addr = self.addr(0);
+        self.b.ins().store(MemFlags::trusted(), x, addr, 0);
+        let sp = self.b.use_var(self.sp);
+        let sp = self.b.ins().iadd_imm(sp, 1);
+        self.b.def_var(self.sp, sp);
+    }
+
+    fn drop(&mut self, n: i64) {
+        let sp = self.b.use_var(self.sp);
+        let sp = self.b.ins().iadd_imm(sp, -n);
+        self.b.def_var(self.sp, sp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        arith::{Arith, Division, Overflow},
+        assembler,
+        compiler::Compiler,
+        instruction::Instruction,
+        vm::VM,
+    };
+
+    fn run(insts: &[Instruction], input: &str, arith: Arith, jit: bool) -> (String, String) {
+        let mut output = vec![];
+        let mut vm = VM::new(insts.to_vec(), input.as_bytes(), &mut output).with_arith(arith);
+        let res = if jit { vm.run_jit() } else { vm.run() };
+        let err = res.err().map(|e| e.to_string()).unwrap_or_default();
+        drop(vm);
+        (String::from_utf8(output).unwrap(), err)
+    }
+
+    fn assert_same(insts: &[Instruction], input: &str, arith: Arith) {
+        assert_eq!(
+            run(insts, input, arith, false),
+            run(insts, input, arith, true),
+            "{:?}",
+            arith
+        );
+    }
+
+    #[test]
+    fn examples() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let cases = [
+            ("hello.ws", ""),
+            ("fib.ws", "90\n"),
+            ("fact.ws", "20\n"),
+            ("fact.ws", "21\n"),
+        ];
+        for (name, input) in cases.iter() {
+            let code = fs::read_to_string(dir.join(name)).unwrap();
+            let insts = Compiler::new(code).compile().unwrap();
+            assert_same(&insts, input, Arith::default());
+        }
+    }
+
+    #[test]
+    fn fused() {
+        let src =
+            "push 10\nloop:\ndup\njz end\ndup\noutn\npush 1\nsub\njmp loop\nend:\npush 0\nload";
+        let insts = assembler::parse(src).unwrap();
+        let run = |jit: bool| {
+            let mut output = vec![];
+            let mut vm = VM::new(insts.clone(), "".as_bytes(), &mut output).with_fusion();
+            let res = if jit { vm.run_jit() } else { vm.run() };
+            let err = res.unwrap_err().to_string();
+            drop(vm);
+            (String::from_utf8(output).unwrap(), err)
+        };
+        assert_eq!(run(false), run(true));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let big = i64::MAX;
+        let programs = [
+            format!("push {}\npush 1\nadd\noutn\nexit", big),
+            format!("push -{}\npush 2\nsub\noutn\nexit", big),
+            format!("push {}\npush -3\nmul\noutn\nexit", big),
+            format!("push -{}\npush 1\nsub\npush -1\ndiv\noutn\nexit", big),
+            "push 7\npush -2\ndiv\noutn\npush 7\npush -2\nmod\noutn\nexit".to_owned(),
+            "push -7\npush 2\ndiv\noutn\npush -7\npush 2\nmod\noutn\nexit".to_owned(),
+            "push 7\npush 0\nmod\nexit".to_owned(),
+        ];
+        let modes = [
+            (Overflow::Trap, Division::Floor),
+            (Overflow::Wrap, Division::Truncate),
+            (Overflow::Saturate, Division::Floor),
+        ];
+        for src in programs.iter() {
+            let insts = assembler::parse(src).unwrap();
+            for (overflow, division) in modes.iter() {
+                let arith = Arith {
+                    overflow: *overflow,
+                    division: *division,
+                };
+                assert_same(&insts, "", arith);
+            }
+        }
+    }
+
+    #[test]
+    fn errors_and_control_flow() {
+        let programs = [
+            // 深い再帰
+            "push 100000\ncall down\nexit\ndown:\ndup\njz done\npush 1\nsub\ncall down\ndone:\nret",
+            "push 1\noutn\nadd",
+            "dup",
+            "push 1\ncopy 1",
+            "push 1\ncopy -1",
+            "push 1\npush 2\nslide 2",
+            "discard\ndiscard\npush 3\noutn\nexit",
+            "jz nowhere",
+            "push 0\njz nowhere",
+            "ret",
+            "push 1\noutn",
+            "push 0\nload",
+            "push 0\ninc\npush 0\nload\noutn\npush 0\ninn\npush 0\nload\noutn\nexit",
+        ];
+        for src in programs.iter() {
+            let insts = assembler::parse(src).unwrap();
+            assert_same(&insts, "a-12\n", Arith::default());
+        }
+    }
+}
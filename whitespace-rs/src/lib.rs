@@ -0,0 +1,25 @@
+pub mod arith;
+pub mod assembler;
+pub mod bytecode;
+pub mod compiler;
+pub mod debugger;
+pub mod disassembler;
+pub mod history;
+pub mod instruction;
+#[cfg(all(feature = "jit", not(feature = "bignum")))]
+mod jit;
+pub mod limits;
+pub mod linker;
+pub mod minifier;
+pub mod notation;
+pub mod number;
+pub mod optimizer;
+pub mod profile;
+pub mod snapshot;
+pub mod source;
+pub mod steganography;
+pub mod token;
+pub mod trace;
+pub mod translator;
+pub mod verifier;
+pub mod vm;
@@ -0,0 +1,36 @@
+//! 信頼できないプログラムを実行するための上限
+
+use std::time::Duration;
+
+pub use runtime_rs::limits::{parse_timeout, LimitExceeded};
+
+/// 各項目がNoneなら上限なし
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// 実行する命令数
+    pub steps: Option<u64>,
+    /// 実行時間
+    pub time: Option<Duration>,
+    /// スタックの要素数
+    pub stack: Option<usize>,
+    /// サブルーチン呼び出しの深さ
+    pub call_stack: Option<usize>,
+    /// ヒープのアドレス数
+    pub heap: Option<usize>,
+    /// 出力するバイト数
+    pub output: Option<u64>,
+}
+
+impl Limits {
+    /// 投稿されたプログラムを実行する場合の既定値
+    pub fn sandbox() -> Self {
+        Self {
+            steps: Some(100_000_000),
+            time: Some(Duration::from_secs(10)),
+            stack: Some(1 << 20),
+            call_stack: Some(1 << 16),
+            heap: Some(1 << 20),
+            output: Some(1 << 20),
+        }
+    }
+}
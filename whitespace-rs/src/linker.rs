@@ -0,0 +1,241 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{arith::BinOp, instruction::Instruction, number::Number};
+
+/// ラベルを命令位置に解決済みの命令
+/// 分岐先は次に実行する命令の位置（ラベルの直後）を指す
+#[derive(Debug, Clone)]
+pub enum Op {
+    Push(Number),
+    Dup,
+    Copy(i64),
+    Swap,
+    Discard,
+    Slide(i64),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    HeapWrite,
+    HeapRead,
+    /// 命令位置をInstructionと揃えるために残しておく
+    Label,
+    Call(usize),
+    Jump(usize),
+    JumpZero(usize),
+    JumpNeg(usize),
+    Return,
+    Exit,
+    CharOut,
+    NumOut,
+    CharIn,
+    NumIn,
+    DumpStack,
+    DumpHeap,
+    HostCall(i64),
+    /// プログラム末尾の番兵
+    End,
+    /// 未定義ラベルへの分岐先
+    /// 実際に分岐するまではエラーにしない
+    Undefined(String),
+    /// 以下は最適化で作る複合命令。次の命令まで実行する
+    /// 2命令目は元のまま残すので、そこへの分岐はそのまま実行できる
+    /// Push; Add/Sub/Mul/Div/Mod
+    PushArith(BinOp, Number),
+    /// Push; HeapRead
+    PushLoad(Number),
+    /// Dup; JumpZero
+    DupJumpZero(usize),
+    /// Dup; JumpNeg
+    DupJumpNeg(usize),
+}
+
+impl Op {
+    /// 複合命令の1命令目。複合命令でなければNone
+    pub fn head(&self) -> Option<Op> {
+        match self {
+            Self::PushArith(_, n) | Self::PushLoad(n) => Some(Self::Push(n.clone())),
+            Self::DupJumpZero(_) | Self::DupJumpNeg(_) => Some(Self::Dup),
+            _ => None,
+        }
+    }
+}
+
+/// アセンブラのニーモニック表記。分岐先は命令位置で表す
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Push(n) => write!(f, "push {}", n),
+            Self::Dup => write!(f, "dup"),
+            Self::Copy(n) => write!(f, "copy {}", n),
+            Self::Swap => write!(f, "swap"),
+            Self::Discard => write!(f, "discard"),
+            Self::Slide(n) => write!(f, "slide {}", n),
+            Self::Add => write!(f, "add"),
+            Self::Sub => write!(f, "sub"),
+            Self::Mul => write!(f, "mul"),
+            Self::Div => write!(f, "div"),
+            Self::Mod => write!(f, "mod"),
+            Self::HeapWrite => write!(f, "store"),
+            Self::HeapRead => write!(f, "load"),
+            Self::Label => write!(f, "label"),
+            Self::Call(pc) => write!(f, "call {}", pc),
+            Self::Jump(pc) => write!(f, "jmp {}", pc),
+            Self::JumpZero(pc) => write!(f, "jz {}", pc),
+            Self::JumpNeg(pc) => write!(f, "jn {}", pc),
+            Self::Return => write!(f, "ret"),
+            Self::Exit => write!(f, "exit"),
+            Self::CharOut => write!(f, "outc"),
+            Self::NumOut => write!(f, "outn"),
+            Self::CharIn => write!(f, "inc"),
+            Self::NumIn => write!(f, "inn"),
+            Self::DumpStack => write!(f, "dumps"),
+            Self::DumpHeap => write!(f, "dumph"),
+            Self::HostCall(n) => write!(f, "host {}", n),
+            Self::End => write!(f, "end"),
+            Self::Undefined(l) => write!(f, "undefined {}", l),
+            Self::PushArith(op, n) => {
+                let name = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "div",
+                    BinOp::Mod => "mod",
+                };
+                write!(f, "push {}; {}", n, name)
+            }
+            Self::PushLoad(n) => write!(f, "push {}; load", n),
+            Self::DupJumpZero(pc) => write!(f, "dup; jz {}", pc),
+            Self::DupJumpNeg(pc) => write!(f, "dup; jn {}", pc),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Program {
+    /// 末尾にEnd、その後ろにUndefinedが並ぶ
+    pub ops: Vec<Op>,
+    /// K: label, V: position
+    pub labels: HashMap<String, usize>,
+    /// Endの位置
+    end: usize,
+}
+
+impl Program {
+    /// 元のプログラムの命令数
+    pub fn len(&self) -> usize {
+        self.end
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub fn link(insts: &[Instruction]) -> Program {
+    let labels = find_labels(insts);
+    let mut undefined: Vec<String> = vec![];
+    let end = insts.len();
+
+    let mut resolve = |label: &str| -> usize {
+        match labels.get(label) {
+            Some(pc) => *pc + 1,
+            None => {
+                let i = match undefined.iter().position(|l| l == label) {
+                    Some(i) => i,
+                    None => {
+                        undefined.push(label.to_owned());
+                        undefined.len() - 1
+                    }
+                };
+                end + 1 + i
+            }
+        }
+    };
+
+    let mut ops: Vec<Op> = insts
+        .iter()
+        .map(|inst| match inst {
+            Instruction::Push(n) => Op::Push(n.clone()),
+            Instruction::Dup => Op::Dup,
+            Instruction::Copy(n) => Op::Copy(*n),
+            Instruction::Swap => Op::Swap,
+            Instruction::Discard => Op::Discard,
+            Instruction::Slide(n) => Op::Slide(*n),
+            Instruction::Add => Op::Add,
+            Instruction::Sub => Op::Sub,
+            Instruction::Mul => Op::Mul,
+            Instruction::Div => Op::Div,
+            Instruction::Mod => Op::Mod,
+            Instruction::HeapWrite => Op::HeapWrite,
+            Instruction::HeapRead => Op::HeapRead,
+            Instruction::Label(_) => Op::Label,
+            Instruction::Call(label) => Op::Call(resolve(label)),
+            Instruction::Jump(label) => Op::Jump(resolve(label)),
+            Instruction::JumpZero(label) => Op::JumpZero(resolve(label)),
+            Instruction::JumpNeg(label) => Op::JumpNeg(resolve(label)),
+            Instruction::Return => Op::Return,
+            Instruction::Exit => Op::Exit,
+            Instruction::CharOut => Op::CharOut,
+            Instruction::NumOut => Op::NumOut,
+            Instruction::CharIn => Op::CharIn,
+            Instruction::NumIn => Op::NumIn,
+            Instruction::DumpStack => Op::DumpStack,
+            Instruction::DumpHeap => Op::DumpHeap,
+            Instruction::HostCall(n) => Op::HostCall(*n),
+        })
+        .collect();
+    ops.push(Op::End);
+    ops.extend(undefined.into_iter().map(Op::Undefined));
+
+    Program { ops, labels, end }
+}
+
+fn find_labels(insts: &[Instruction]) -> HashMap<String, usize> {
+    let mut labels = HashMap::new();
+    for (i, inst) in insts.iter().enumerate() {
+        if let Instruction::Label(name) = inst {
+            // ラベル名がだぶった場合は先に登録したほうを優先する
+            labels.entry(name.clone()).or_insert(i);
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_labels() {
+        let insts = vec![
+            Instruction::Jump("t".to_owned()),
+            Instruction::Label("s".to_owned()),
+            Instruction::Label("t".to_owned()),
+            Instruction::JumpZero("s".to_owned()),
+            Instruction::Label("t".to_owned()),
+            Instruction::Exit,
+        ];
+        let program = link(&insts);
+        assert!(matches!(program.ops[0], Op::Jump(3)));
+        assert!(matches!(program.ops[3], Op::JumpZero(2)));
+        assert!(matches!(program.ops[6], Op::End));
+        assert_eq!(6, program.len());
+    }
+
+    #[test]
+    fn undefined_label() {
+        let insts = vec![
+            Instruction::Call("ss".to_owned()),
+            Instruction::Jump("tt".to_owned()),
+            Instruction::JumpNeg("ss".to_owned()),
+        ];
+        let program = link(&insts);
+        assert!(matches!(program.ops[0], Op::Call(4)));
+        assert!(matches!(program.ops[1], Op::Jump(5)));
+        assert!(matches!(program.ops[2], Op::JumpNeg(4)));
+        assert!(matches!(&program.ops[4], Op::Undefined(l) if l == "ss"));
+        assert!(matches!(&program.ops[5], Op::Undefined(l) if l == "tt"));
+    }
+}
@@ -1,27 +1,638 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    process,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Clap;
 
-use crate::{compiler::Compiler, vm::VM};
-
-mod compiler;
-mod instruction;
-mod token;
-mod vm;
+use whitespace_rs::{
+    arith::{Arith, Division, Overflow},
+    assembler, bytecode,
+    compiler::Compiler,
+    debugger::Debugger,
+    disassembler, instruction,
+    limits::{parse_timeout, LimitExceeded, Limits},
+    minifier::{self, Savings},
+    notation::{self, VISIBLE_EXTENSION},
+    optimizer,
+    snapshot::Snapshot,
+    source::Diagnostic,
+    steganography,
+    token::Notation,
+    trace::Tracer,
+    translator, verifier,
+    vm::{Encoding, Eof, VM},
+};
 
 #[derive(Debug, Clap)]
 #[clap(name = env!("CARGO_BIN_NAME"),version=env!("CARGO_PKG_VERSION"),author=env!("CARGO_PKG_AUTHORS"))]
 struct Opts {
+    #[clap(name = "Whitespace code file path")]
+    src_path: Option<PathBuf>,
+    #[clap(flatten)]
+    exec: ExecOpts,
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
+}
+
+#[derive(Debug, Clap)]
+enum SubCommand {
+    /// Run a Whitespace program
+    Run(Run),
+    /// Assemble mnemonics into a Whitespace program
+    Asm(Asm),
+    /// Print an annotated assembly listing of a Whitespace program
+    Disasm(Disasm),
+    /// Debug a Whitespace program step by step (commands are read from stdin)
+    Debug(Dbg),
+    /// Check a Whitespace program for stack underflows and control-flow errors without running it
+    Check(Check),
+    /// Compile a Whitespace program into bytecode that `run` can load
+    Compile(Compile),
+    /// Translate a Whitespace program into a standalone C program
+    Translate(Translate),
+    /// Convert a Whitespace program to the visible S/T/L notation (.wsv) or back
+    Convert(Convert),
+    /// Shorten a Whitespace program without changing its behavior
+    Minify(Minify),
+    /// Hide a Whitespace program in the whitespace of a text file
+    Embed(Embed),
+    /// Extract the Whitespace program hidden in a text file
+    Extract(Extract),
+}
+
+#[derive(Debug, Clap)]
+struct Run {
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    #[clap(flatten)]
+    exec: ExecOpts,
+}
+
+// 実行時のオプション
+#[derive(Debug, Clap)]
+struct ExecOpts {
+    /// Behavior of inc/inn at the end of input: minus-one, zero, keep or error
+    #[clap(long, default_value = "error")]
+    eof: Eof,
+    /// Character encoding of inc/outc: utf-8 (Unicode code points) or bytes
+    #[clap(long, default_value = "utf-8")]
+    encoding: Encoding,
+    /// Enable the extension instructions (LLS dumps the stack and LLT the heap to stderr, TLL n calls host function n, which the CLI does not register), negative copy and oversized slide
+    #[clap(long)]
+    extensions: bool,
+    #[clap(flatten)]
+    arith: ArithOpts,
+    #[clap(flatten)]
+    limits: LimitOpts,
+    /// Compile the program to native code before running (the interpreter is used without the jit feature, with limits, --trace or profiling)
+    #[clap(long)]
+    jit: bool,
+    /// Run the program as written, without the peephole optimizer (the optimizer changes the instruction count and stack depth that --max-steps, --max-stack and --snapshot-at see)
+    #[clap(long)]
+    no_opt: bool,
+    /// Write one JSON object per executed instruction to this file (the program is run as written)
+    #[clap(long)]
+    trace: Option<PathBuf>,
+    /// Number of stack items from the top to include in each trace record (default: all)
+    #[clap(long)]
+    trace_stack: Option<usize>,
+    /// Print a profile of executed instructions, labels and subroutine calls to stderr (the program is run as written)
+    #[clap(long)]
+    profile: bool,
+    /// Write the profile in the folded stack format for flamegraph tools to this file
+    #[clap(long)]
+    profile_folded: Option<PathBuf>,
+    /// Save the VM state to this file and stop on SIGINT/SIGTERM or at --snapshot-at
+    #[clap(long)]
+    snapshot: Option<PathBuf>,
+    /// Save a snapshot and stop after this many executed instructions (requires --snapshot; counted in the optimized program unless --no-opt)
+    #[clap(long)]
+    snapshot_at: Option<u64>,
+    /// Continue from a snapshot taken from the same program with the same options, including --no-opt
+    #[clap(long)]
+    resume: Option<PathBuf>,
+}
+
+impl ExecOpts {
+    fn profiles(&self) -> bool {
+        self.profile || self.profile_folded.is_some()
+    }
+}
+
+#[derive(Debug, Clap)]
+struct Asm {
+    #[clap(name = "assembly file path")]
+    src_path: PathBuf,
+    /// Output file path (default: stdout)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+struct Disasm {
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    /// Accept the extension instructions (LLS, LLT and TLL n)
+    #[clap(long)]
+    extensions: bool,
+    /// Output file path (default: stdout)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+struct Dbg {
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    /// Input file for the program (default: empty input)
+    #[clap(short, long)]
+    input: Option<PathBuf>,
+    /// Behavior of inc/inn at the end of input: minus-one, zero, keep or error
+    #[clap(long, default_value = "error")]
+    eof: Eof,
+    /// Character encoding of inc/outc: utf-8 (Unicode code points) or bytes
+    #[clap(long, default_value = "utf-8")]
+    encoding: Encoding,
+    /// Enable the extension instructions (LLS dumps the stack and LLT the heap to stderr, TLL n calls host function n, which the CLI does not register), negative copy and oversized slide
+    #[clap(long)]
+    extensions: bool,
+    #[clap(flatten)]
+    arith: ArithOpts,
+    #[clap(flatten)]
+    limits: LimitOpts,
+}
+
+#[derive(Debug, Clap)]
+struct Check {
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    /// Accept the extension instructions (LLS, LLT and TLL n)
+    #[clap(long)]
+    extensions: bool,
+}
+
+#[derive(Debug, Clap)]
+struct Compile {
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    /// Output file path (default: the source path with the extension .wsc)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Omit the source map (runtime errors will not point at the source code)
+    #[clap(long)]
+    strip: bool,
+    /// Accept the extension instructions (LLS, LLT and TLL n)
+    #[clap(long)]
+    extensions: bool,
+}
+
+#[derive(Debug, Clap)]
+struct Translate {
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    /// Output file path (default: stdout)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+struct Convert {
     #[clap(name = "Whitespace code file path")]
     src_path: PathBuf,
+    /// Notation to convert to: visible or whitespace (default: visible, or whitespace for .wsv files)
+    #[clap(long)]
+    to: Option<Notation>,
+    /// Output file path (default: stdout)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+struct Minify {
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    /// Accept the extension instructions (LLS, LLT and TLL n)
+    #[clap(long)]
+    extensions: bool,
+    /// Output file path (default: stdout)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+struct Embed {
+    #[clap(name = "carrier text file path")]
+    carrier_path: PathBuf,
+    #[clap(name = "Whitespace code file path")]
+    src_path: PathBuf,
+    /// Output file path (default: stdout)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+struct Extract {
+    #[clap(name = "text file path")]
+    src_path: PathBuf,
+    /// Output file path (default: stdout)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+struct ArithOpts {
+    /// Behavior on arithmetic overflow: trap, wrap or saturate
+    #[clap(long, default_value = "trap")]
+    overflow: Overflow,
+    /// Rounding of division and sign of modulo: truncate (toward zero) or floor (as in the book's Ruby)
+    #[clap(long, default_value = "truncate")]
+    division: Division,
+}
+
+impl ArithOpts {
+    fn to_arith(&self) -> Arith {
+        Arith {
+            overflow: self.overflow,
+            division: self.division,
+        }
+    }
+}
+
+// 実行の上限。指定しなければ上限なし
+// flattenする構造体のドキュメントコメントはaboutになってしまうので通常のコメントにする
+#[derive(Debug, Clap)]
+struct LimitOpts {
+    /// Use the default limits for untrusted programs (each can be overridden)
+    #[clap(long)]
+    sandbox: bool,
+    /// Maximum number of executed instructions (counted in the optimized program unless --no-opt)
+    #[clap(long)]
+    max_steps: Option<u64>,
+    /// Maximum execution time in seconds
+    #[clap(long, parse(try_from_str = parse_timeout))]
+    timeout: Option<Duration>,
+    /// Maximum number of items on the stack (the optimizer can lower the peak; use --no-opt to limit the program as written)
+    #[clap(long)]
+    max_stack: Option<usize>,
+    /// Maximum depth of subroutine calls
+    #[clap(long)]
+    max_calls: Option<usize>,
+    /// Maximum number of heap addresses
+    #[clap(long)]
+    max_heap: Option<usize>,
+    /// Maximum number of output bytes
+    #[clap(long)]
+    max_output: Option<u64>,
+}
+
+impl LimitOpts {
+    fn to_limits(&self) -> Limits {
+        let base = if self.sandbox {
+            Limits::sandbox()
+        } else {
+            Limits::default()
+        };
+        Limits {
+            steps: self.max_steps.or(base.steps),
+            time: self.timeout.or(base.time),
+            stack: self.max_stack.or(base.stack),
+            call_stack: self.max_calls.or(base.call_stack),
+            heap: self.max_heap.or(base.heap),
+            output: self.max_output.or(base.output),
+        }
+    }
+}
+
+/// SIGINTとSIGTERMで立つフラグ
+fn interrupt_flag() -> Result<Arc<AtomicBool>> {
+    let flag = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&flag))?;
+    }
+    Ok(flag)
+}
+
+/// プロファイルに表示する命令の数
+const PROFILE_TOP: usize = 20;
+
+/// 上限を超えた場合は種類ごとの終了コードで終える
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        let code = e
+            .downcast_ref::<LimitExceeded>()
+            .map_or(1, |limit| limit.exit_code());
+        process::exit(code);
+    }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let opts = Opts::parse();
-    let code = fs::read_to_string(opts.src_path)?;
-    let insts = Compiler::new(code).compile()?;
-    VM::new(insts).run()?;
+    match (opts.subcmd, opts.src_path) {
+        (Some(SubCommand::Run(run)), _) => exec(run.src_path, run.exec),
+        (Some(SubCommand::Asm(asm)), _) => assemble(asm),
+        (Some(SubCommand::Disasm(disasm)), _) => disassemble(disasm),
+        (Some(SubCommand::Debug(dbg)), _) => debug(dbg),
+        (Some(SubCommand::Check(chk)), _) => check(chk),
+        (Some(SubCommand::Compile(cmp)), _) => compile(cmp),
+        (Some(SubCommand::Translate(tr)), _) => translate(tr),
+        (Some(SubCommand::Convert(conv)), _) => convert(conv),
+        (Some(SubCommand::Minify(min)), _) => minify(min),
+        (Some(SubCommand::Embed(emb)), _) => embed(emb),
+        (Some(SubCommand::Extract(ext)), _) => extract(ext),
+        (None, Some(src_path)) => exec(src_path, opts.exec),
+        (None, None) => Err(anyhow::anyhow!(
+            "a Whitespace code file path or a subcommand is required."
+        )),
+    }
+}
+
+/// 拡張子が.wsvなら見える表記として読み、本来の表記にする
+fn read_code(path: &Path) -> Result<String> {
+    let code = fs::read_to_string(path)?;
+    match notation_of(path) {
+        Notation::Whitespace => Ok(code),
+        Notation::Visible => notation::to_whitespace(&code)
+            .with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+/// コンパイルするコードは変換せずに読み、エラーの位置を元のファイルで示せるようにする
+fn read_source(path: &Path) -> Result<(String, Notation)> {
+    let code = fs::read_to_string(path)?;
+    Ok((code, notation_of(path)))
+}
+
+fn notation_of(path: &Path) -> Notation {
+    if path.extension().is_some_and(|e| e == VISIBLE_EXTENSION) {
+        Notation::Visible
+    } else {
+        Notation::Whitespace
+    }
+}
+
+/// 表記と、拡張命令を受け付けるかに応じたコンパイラ
+fn compiler(code: String, notation: Notation, extensions: bool) -> Compiler {
+    let compiler = Compiler::new(code).with_notation(notation);
+    if extensions {
+        compiler.with_extensions()
+    } else {
+        compiler
+    }
+}
+
+/// バイトコードでもWhitespaceのコードでも実行できる
+/// トレースやプロファイルする場合は命令位置が元のプログラムと一致するよう最適化しない
+fn exec(src_path: PathBuf, opts: ExecOpts) -> Result<()> {
+    let arith = opts.arith.to_arith();
+    let optimize = !opts.no_opt && opts.trace.is_none() && !opts.profiles();
+    let bytes = fs::read(&src_path)?;
+    let (insts, source) = if bytecode::is_bytecode(&bytes) {
+        let bytecode = bytecode::decode(&bytes)
+            .with_context(|| format!("failed to load {}", src_path.display()))?;
+        (bytecode.insts, bytecode.source)
+    } else {
+        let code = String::from_utf8(bytes)
+            .with_context(|| format!("{} is not valid UTF-8", src_path.display()))?;
+        let notation = notation_of(&src_path);
+        let (insts, spans) =
+            compiler(code.clone(), notation, opts.extensions).compile_with_spans()?;
+        (insts, Some((code, notation, spans)))
+    };
+    let (insts, source) = if optimize {
+        // エラーの位置は元の命令の位置で示す
+        let optimized = optimizer::optimize(&insts, &arith);
+        let source = source.map(|(code, notation, spans)| {
+            let spans = optimized.origins.iter().map(|i| spans[*i]).collect();
+            (code, notation, spans)
+        });
+        (optimized.insts, source)
+    } else {
+        (insts, source)
+    };
+    let stdin = io::stdin();
+    let mut vm = VM::new(insts, stdin.lock(), io::stdout())
+        .with_eof(opts.eof)
+        .with_encoding(opts.encoding)
+        .with_arith(arith)
+        .with_limits(opts.limits.to_limits());
+    if opts.extensions {
+        vm = vm.with_extensions(io::stderr());
+    }
+    if let Some(path) = &opts.trace {
+        let file = fs::File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        vm = vm.with_trace(Tracer::new(file, opts.trace_stack));
+    }
+    if opts.profiles() {
+        vm = vm.with_profile();
+    }
+    match (&opts.snapshot, opts.snapshot_at) {
+        (Some(_), at) => {
+            vm = vm.with_interrupt(interrupt_flag()?);
+            if let Some(steps) = at {
+                vm = vm.with_pause_at(steps);
+            }
+        }
+        (None, Some(_)) => return Err(anyhow::anyhow!("--snapshot-at requires --snapshot.")),
+        (None, None) => (),
+    }
+    if optimize {
+        vm = vm.with_fusion();
+    }
+    if let Some((code, notation, spans)) = source {
+        vm = vm.with_source(code, notation, spans);
+    }
+    if let Some(path) = &opts.resume {
+        let bytes = fs::read(path)?;
+        let snapshot = Snapshot::decode(&bytes)
+            .with_context(|| format!("failed to load {}", path.display()))?;
+        vm.restore(snapshot)?;
+    }
+    let res = if opts.jit { vm.run_jit() } else { vm.run() };
+    // 終了せずに止まったのはスナップショットを取るため
+    if let (Ok(()), false, Some(path)) = (&res, vm.is_exited(), &opts.snapshot) {
+        let snapshot = vm.snapshot();
+        fs::write(path, snapshot.encode())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        eprintln!(
+            "snapshot saved to {} at step {}.",
+            path.display(),
+            snapshot.steps
+        );
+    }
+    // エラーで止まった場合もそこまでの集計を出す
+    if let Some(profile) = vm.profile() {
+        if opts.profile {
+            eprint!("\n{}", profile.report(vm.program(), PROFILE_TOP));
+        }
+        if let Some(path) = &opts.profile_folded {
+            fs::write(path, profile.folded(vm.program()))
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+    }
+    res
+}
+
+fn compile(cmp: Compile) -> Result<()> {
+    let (code, notation) = read_source(&cmp.src_path)?;
+    let (insts, spans) = compiler(code.clone(), notation, cmp.extensions).compile_with_spans()?;
+    let source = if cmp.strip {
+        None
+    } else {
+        Some((code.as_str(), notation, spans.as_slice()))
+    };
+    let bytes = bytecode::encode(&insts, source);
+    let output = match cmp.output {
+        Some(path) => path,
+        None => cmp.src_path.with_extension("wsc"),
+    };
+    fs::write(output, bytes)?;
+
+    Ok(())
+}
+
+fn assemble(asm: Asm) -> Result<()> {
+    let src = fs::read_to_string(&asm.src_path)?;
+    let code = assembler::assemble(&src)
+        .with_context(|| format!("failed to assemble {}", asm.src_path.display()))?;
+    match asm.output {
+        Some(path) => fs::write(path, code)?,
+        None => print!("{}", code),
+    }
+
+    Ok(())
+}
+
+fn disassemble(disasm: Disasm) -> Result<()> {
+    let (code, notation) = read_source(&disasm.src_path)?;
+    let listing = disassembler::disassemble(&code, notation, disasm.extensions)?;
+    match disasm.output {
+        Some(path) => fs::write(path, listing)?,
+        None => print!("{}", listing),
+    }
+
+    Ok(())
+}
+
+fn convert(conv: Convert) -> Result<()> {
+    let code = read_code(&conv.src_path)?;
+    let to = conv.to.unwrap_or(match notation_of(&conv.src_path) {
+        Notation::Whitespace => Notation::Visible,
+        Notation::Visible => Notation::Whitespace,
+    });
+    let converted = match to {
+        Notation::Whitespace => code,
+        Notation::Visible => notation::to_visible(&code),
+    };
+    match conv.output {
+        Some(path) => fs::write(path, converted)?,
+        None => print!("{}", converted),
+    }
+
+    Ok(())
+}
+
+/// 減ったバイト数は標準エラー出力に出す
+fn minify(min: Minify) -> Result<()> {
+    let (code, notation) = read_source(&min.src_path)?;
+    let insts = compiler(code.clone(), notation, min.extensions).compile()?;
+    // 見える表記なら本来の表記に戻したものと比べる
+    let code = match notation {
+        Notation::Whitespace => code,
+        Notation::Visible => notation::to_whitespace(&code)?,
+    };
+    let minified = instruction::to_ws(&minifier::minify(&insts));
+    match min.output {
+        Some(path) => fs::write(path, &minified)?,
+        None => print!("{}", minified),
+    }
+    eprintln!("{}", Savings::new(&code, &minified));
+
+    Ok(())
+}
+
+fn embed(emb: Embed) -> Result<()> {
+    let carrier = fs::read_to_string(&emb.carrier_path)?;
+    let code = read_code(&emb.src_path)?;
+    let text = steganography::embed(&carrier, &code)
+        .with_context(|| format!("failed to embed {}", emb.src_path.display()))?;
+    match emb.output {
+        Some(path) => fs::write(path, text)?,
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+fn extract(ext: Extract) -> Result<()> {
+    let text = fs::read_to_string(&ext.src_path)?;
+    let code = steganography::extract(&text);
+    match ext.output {
+        Some(path) => fs::write(path, code)?,
+        None => print!("{}", code),
+    }
+
+    Ok(())
+}
+
+fn translate(tr: Translate) -> Result<()> {
+    let (code, notation) = read_source(&tr.src_path)?;
+    let insts = compiler(code, notation, false).compile()?;
+    let c = translator::translate(&insts)
+        .with_context(|| format!("failed to translate {}", tr.src_path.display()))?;
+    match tr.output {
+        Some(path) => fs::write(path, c)?,
+        None => print!("{}", c),
+    }
+
+    Ok(())
+}
+
+fn debug(dbg: Dbg) -> Result<()> {
+    let (code, notation) = read_source(&dbg.src_path)?;
+    let (insts, spans) = compiler(code.clone(), notation, dbg.extensions).compile_with_spans()?;
+    // 標準入力はデバッガのコマンドに使うので、プログラムの入力はファイルから読む
+    let input: Box<dyn BufRead> = match dbg.input {
+        Some(path) => Box::new(BufReader::new(fs::File::open(path)?)),
+        None => Box::new(io::empty()),
+    };
+    let stdin = io::stdin();
+    let mut vm = VM::new(insts.clone(), input, io::stdout())
+        .with_source(code, notation, spans)
+        .with_eof(dbg.eof)
+        .with_encoding(dbg.encoding)
+        .with_arith(dbg.arith.to_arith())
+        .with_limits(dbg.limits.to_limits());
+    if dbg.extensions {
+        vm = vm.with_extensions(io::stderr());
+    }
+    let mut debugger = Debugger::new(vm, insts, stdin.lock(), io::stdout());
+    debugger.run()?;
+
+    Ok(())
+}
+
+fn check(check: Check) -> Result<()> {
+    let (code, notation) = read_source(&check.src_path)?;
+    let (insts, spans) = compiler(code.clone(), notation, check.extensions).compile_with_spans()?;
+    let findings = verifier::verify(&insts).findings;
+    for f in findings.iter() {
+        let level = if f.is_error() { "error" } else { "warning" };
+        let diagnostic = Diagnostic::new(f.to_string(), spans[f.index]).with_notation(notation);
+        println!("{}: {}\n", level, diagnostic.render(&code));
+    }
 
+    let errors = findings.iter().filter(|f| f.is_error()).count();
+    if errors > 0 {
+        return Err(anyhow::anyhow!("found {} error(s).", errors));
+    }
     Ok(())
 }
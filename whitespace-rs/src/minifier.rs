@@ -0,0 +1,201 @@
+//! 動作を変えずにWhitespaceのコードを短くする
+//!
+//! - ラベルは使われる回数の多い順に、短いs/t表記から割り当て直す
+//! - 分岐先にならないラベルと、2つ目以降の同じ名前のラベルは除く
+//! - 数値は先頭に0のない2進数で書き直す（``Instruction::to_ws``）
+//! - コメント（空白文字以外の文字）はすべて除く
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{assembler, instruction::Instruction};
+
+/// 短くした命令列
+pub fn minify(insts: &[Instruction]) -> Vec<Instruction> {
+    // 分岐先になるラベル。未定義のラベルもエラーになるよう残す
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut order: Vec<&str> = vec![];
+    for inst in insts.iter() {
+        if let Some(label) = target(inst) {
+            let count = counts.entry(label).or_insert(0);
+            if *count == 0 {
+                order.push(label);
+            }
+            *count += 1;
+        }
+    }
+
+    // 同じ名前のラベルは最初のものに分岐する
+    let mut defined: HashSet<&str> = HashSet::new();
+    let insts: Vec<&Instruction> = insts
+        .iter()
+        .filter(|inst| match inst {
+            Instruction::Label(l) if counts.contains_key(l.as_str()) => defined.insert(l),
+            Instruction::Label(_) => false,
+            _ => true,
+        })
+        .collect();
+    for l in defined {
+        *counts.get_mut(l).unwrap() += 1;
+    }
+
+    // 回数が同じなら先に現れたものを短くする
+    let mut labels = order.clone();
+    labels.sort_by_key(|l| std::cmp::Reverse(counts[l]));
+    let names: HashMap<&str, String> = labels
+        .into_iter()
+        .enumerate()
+        .map(|(i, l)| (l, assembler::label_name(i)))
+        .collect();
+    let rename = |l: &String| names[l.as_str()].clone();
+
+    insts
+        .into_iter()
+        .map(|inst| match inst {
+            Instruction::Label(l) => Instruction::Label(rename(l)),
+            Instruction::Call(l) => Instruction::Call(rename(l)),
+            Instruction::Jump(l) => Instruction::Jump(rename(l)),
+            Instruction::JumpZero(l) => Instruction::JumpZero(rename(l)),
+            Instruction::JumpNeg(l) => Instruction::JumpNeg(rename(l)),
+            inst => inst.clone(),
+        })
+        .collect()
+}
+
+/// 分岐先のラベル
+fn target(inst: &Instruction) -> Option<&str> {
+    match inst {
+        Instruction::Call(l)
+        | Instruction::Jump(l)
+        | Instruction::JumpZero(l)
+        | Instruction::JumpNeg(l) => Some(l),
+        _ => None,
+    }
+}
+
+/// 短くして減ったバイト数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savings {
+    pub original: usize,
+    /// 元のコードからコメントを除いた大きさ
+    pub whitespace: usize,
+    pub minified: usize,
+}
+
+impl Savings {
+    pub fn new(original: &str, minified: &str) -> Self {
+        Self {
+            original: original.len(),
+            whitespace: original
+                .bytes()
+                .filter(|b| matches!(b, b' ' | b'\t' | b'\n'))
+                .count(),
+            minified: minified.len(),
+        }
+    }
+}
+
+impl fmt::Display for Savings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} bytes ({} saved: {} in comments, {} in labels and numbers)",
+            self.original,
+            self.minified,
+            // 末尾の改行が無いラベルなどは1バイト増える
+            self.original.saturating_sub(self.minified),
+            self.original - self.whitespace,
+            self.whitespace.saturating_sub(self.minified)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{compiler::Compiler, instruction, vm::VM};
+
+    #[test]
+    fn labels() {
+        let src = "
+            jmp main
+        unused:
+            exit
+        loop:
+            call sub
+            jz loop
+        main:
+            push 1
+            jz loop
+            call sub
+            exit
+        sub:
+            ret
+        sub:
+            ret
+            jmp undefined
+        ";
+        let insts = assembler::parse(src).unwrap();
+        // sub: 3回、loop: 3回、main: 2回、undefined: 1回
+        let expect = assembler::parse(
+            "
+            jmp ss
+            exit
+        t:
+            call s
+            jz t
+        ss:
+            push 1
+            jz t
+            call s
+            exit
+        s:
+            ret
+            ret
+            jmp st
+        ",
+        )
+        .unwrap();
+        assert_eq!(expect, minify(&insts));
+    }
+
+    #[test]
+    fn numbers() {
+        // 先頭に余分な0のある数値
+        let code = "     \t\t\n \t   \t\n\n\n\n".to_owned();
+        let insts = Compiler::new(code.clone()).compile().unwrap();
+        let minified = instruction::to_ws(&minify(&insts));
+        assert_eq!("   \t\t\n \t  \t\n\n\n\n", minified);
+        let savings = Savings::new(&code, &minified);
+        assert_eq!(
+            "18 -> 15 bytes (3 saved: 0 in comments, 3 in labels and numbers)",
+            savings.to_string()
+        );
+    }
+
+    #[test]
+    fn examples() {
+        let run = |insts: Vec<Instruction>| {
+            let mut output = vec![];
+            let res = VM::new(insts, "10\n".as_bytes(), &mut output).run();
+            (output, res.map_err(|e| e.to_string()))
+        };
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().unwrap() != "ws" || path.ends_with("forever_a.ws") {
+                continue;
+            }
+            let code = fs::read_to_string(&path).unwrap();
+            let insts = Compiler::new(code.clone()).compile().unwrap();
+            let minified = instruction::to_ws(&minify(&insts));
+            assert!(minified.len() < code.len(), "{}", path.display());
+            let reloaded = Compiler::new(minified).compile().unwrap();
+            assert_eq!(run(insts), run(reloaded), "{}", path.display());
+        }
+    }
+}
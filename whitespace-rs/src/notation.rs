@@ -0,0 +1,148 @@
+//! 本来の表記と見える表記（``token``を参照）を相互に変換する
+//!
+//! 見える表記にするときは改行（``L``）ごとに行を分け、空白文字以外の文字は``"``で囲んで残す
+//! そのため本来の表記に戻すと元のコードとバイト単位で一致する
+
+use anyhow::Result;
+
+use crate::token::{self, Item, Token};
+
+/// 見える表記のファイルの拡張子
+pub const VISIBLE_EXTENSION: &str = "wsv";
+
+/// 本来の表記を見える表記にする
+pub fn to_visible(code: &str) -> String {
+    let mut res = String::new();
+    let mut text = String::new();
+    for c in code.chars() {
+        let token = match c {
+            ' ' => Token::Space,
+            '\t' => Token::Tab,
+            '\n' => Token::Lf,
+            '"' => {
+                text.push_str("\\\"");
+                continue;
+            }
+            '\\' => {
+                text.push_str("\\\\");
+                continue;
+            }
+            '\r' => {
+                text.push_str("\\r");
+                continue;
+            }
+            c => {
+                text.push(c);
+                continue;
+            }
+        };
+        if !text.is_empty() {
+            push_text(&mut res, &text);
+            text.clear();
+            // コメントとトークンの区切り
+            res.push(' ');
+        }
+        res.push(token.to_visible());
+        if token == Token::Lf {
+            res.push('\n');
+        }
+    }
+    if !text.is_empty() {
+        push_text(&mut res, &text);
+    }
+    if !res.is_empty() && !res.ends_with('\n') {
+        res.push('\n');
+    }
+    res
+}
+
+fn push_text(res: &mut String, text: &str) {
+    if !res.is_empty() && !res.ends_with('\n') {
+        res.push(' ');
+    }
+    res.push('"');
+    res.push_str(text);
+    res.push('"');
+}
+
+/// 見える表記を本来の表記にする。``#``や``;``のコメントは残らない
+pub fn to_whitespace(code: &str) -> Result<String> {
+    let mut res = String::new();
+    for (item, _) in token::lex_visible(code)? {
+        match item {
+            Item::Token(token) => res.push(token.to_char()),
+            Item::Text(text) => res.push_str(&text),
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{assembler, compiler::Compiler, token::Notation};
+
+    #[test]
+    fn round_trip() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let mut codes: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().unwrap() == "ws")
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect();
+        codes.push("push \"1\" \\ \r\n\t\u{3042}".to_owned());
+        for code in codes {
+            let visible = to_visible(&code);
+            assert_eq!(code, to_whitespace(&visible).unwrap());
+            // 見える表記のまま読んでも同じトークン列になる
+            let tokens = |code: &str, notation| -> Vec<Token> {
+                token::tokenize(code, notation)
+                    .unwrap()
+                    .iter()
+                    .map(|t| t.token)
+                    .collect()
+            };
+            assert_eq!(
+                tokens(&code, Notation::Whitespace),
+                tokens(&visible, Notation::Visible)
+            );
+        }
+    }
+
+    #[test]
+    fn visible() {
+        assert_eq!("\"push\" SSSTL\n\"1\"\n", to_visible("push   \t\n1"));
+
+        let code = "
+            # 1を出力して終了する
+            \"push1\" SS ST L   ; 末尾のLは数値の終わり
+            [Tab][LF][space][tab]
+            LLL
+        ";
+        let code = to_whitespace(code).unwrap();
+        assert_eq!("push1   \t\n\t\n \t\n\n\n", code);
+        let insts = Compiler::new(code).compile().unwrap();
+        assert_eq!(assembler::parse("push 1\noutn\nexit").unwrap(), insts);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |code: &str| to_whitespace(code).unwrap_err().to_string();
+        assert_eq!(
+            "unexpected character 's' at line 2, column 2.",
+            err("SS\nLs")
+        );
+        assert_eq!("unknown token [Foo] at line 1, column 2.", err("S[Foo]"));
+        assert_eq!(
+            "whitespace in comment text at line 1, column 1.",
+            err("\"push 1\"")
+        );
+        assert_eq!(
+            "unterminated comment text at line 1, column 3.",
+            err("SS\"x")
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::Result;
+#[cfg(feature = "bignum")]
+use num_bigint::BigInt;
+
+#[cfg(feature = "bignum")]
+use crate::arith::Division;
+use crate::arith::{Arith, BinOp};
+#[cfg(feature = "bignum")]
+use num_traits::{Num, Signed, ToPrimitive, Zero};
+
+/// スタックとヒープに積まれる数値
+/// `bignum` featureが有効なら多倍長整数、無効ならi64
+/// JITコンパイルしたコードはスタックをi64の配列として読み書きする
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct Number(Inner);
+
+#[cfg(not(feature = "bignum"))]
+type Inner = i64;
+#[cfg(feature = "bignum")]
+type Inner = BigInt;
+
+impl Number {
+    pub fn from_str_radix(s: &str, radix: u32) -> Result<Self> {
+        let n = Inner::from_str_radix(s, radix)?;
+        Ok(Self(n))
+    }
+
+    #[cfg(not(feature = "bignum"))]
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    #[cfg(feature = "bignum")]
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    #[cfg(not(feature = "bignum"))]
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    #[cfg(feature = "bignum")]
+    pub fn is_negative(&self) -> bool {
+        self.0.is_negative()
+    }
+
+    #[cfg(not(feature = "bignum"))]
+    pub fn to_i64(&self) -> Option<i64> {
+        Some(self.0)
+    }
+
+    #[cfg(feature = "bignum")]
+    pub fn to_i64(&self) -> Option<i64> {
+        self.0.to_i64()
+    }
+
+    /// 符号と絶対値の2進表記
+    #[cfg(not(feature = "bignum"))]
+    pub fn to_binary(&self) -> (bool, String) {
+        (self.0 < 0, format!("{:b}", self.0.unsigned_abs()))
+    }
+
+    #[cfg(feature = "bignum")]
+    pub fn to_binary(&self) -> (bool, String) {
+        (self.0.is_negative(), self.0.magnitude().to_str_radix(2))
+    }
+
+    /// 2の補数表現のリトルエンディアン。上位の符号拡張のバイトは省く
+    #[cfg(not(feature = "bignum"))]
+    pub fn to_signed_bytes_le(&self) -> Vec<u8> {
+        let mut bytes = self.0.to_le_bytes().to_vec();
+        while bytes.len() > 1 {
+            let (last, prev) = (bytes[bytes.len() - 1], bytes[bytes.len() - 2]);
+            // 削っても符号が変わらない場合だけ削る
+            if (last == 0 && prev & 0x80 == 0) || (last == 0xff && prev & 0x80 != 0) {
+                bytes.pop();
+            } else {
+                break;
+            }
+        }
+        bytes
+    }
+
+    #[cfg(feature = "bignum")]
+    pub fn to_signed_bytes_le(&self) -> Vec<u8> {
+        self.0.to_signed_bytes_le()
+    }
+
+    #[cfg(not(feature = "bignum"))]
+    pub fn from_signed_bytes_le(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() > 8 {
+            return Err(anyhow::anyhow!(
+                "the number is too large for i64. enable the bignum feature."
+            ));
+        }
+        let negative = bytes.last().is_some_and(|b| b & 0x80 != 0);
+        let mut buf = if negative { [0xff; 8] } else { [0; 8] };
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(Self(i64::from_le_bytes(buf)))
+    }
+
+    #[cfg(feature = "bignum")]
+    pub fn from_signed_bytes_le(bytes: &[u8]) -> Result<Self> {
+        Ok(Self(BigInt::from_signed_bytes_le(bytes)))
+    }
+
+    /// 下位8bit（`as u8`と同じ切り捨て）
+    #[cfg(not(feature = "bignum"))]
+    pub fn low_byte(&self) -> u8 {
+        self.0 as u8
+    }
+
+    #[cfg(feature = "bignum")]
+    pub fn low_byte(&self) -> u8 {
+        // 2の補数表現のリトルエンディアンなので先頭が下位8bit
+        self.0.to_signed_bytes_le()[0]
+    }
+}
+
+impl From<i64> for Number {
+    fn from(n: i64) -> Self {
+        Self(Inner::from(n))
+    }
+}
+
+impl FromStr for Number {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let n = s.parse::<Inner>()?;
+        Ok(Self(n))
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Number {
+    #[cfg(not(feature = "bignum"))]
+    pub fn apply(op: BinOp, l: &Number, r: &Number, arith: &Arith) -> Result<Number> {
+        Ok(Number(arith.apply_i64(op, l.0, r.0)?))
+    }
+
+    /// 多倍長整数は桁あふれしないので、除算の丸め方向だけが意味を持つ
+    #[cfg(feature = "bignum")]
+    pub fn apply(op: BinOp, l: &Number, r: &Number, arith: &Arith) -> Result<Number> {
+        let (l, r) = (&l.0, &r.0);
+        let floor = arith.division == Division::Floor;
+        let n = match op {
+            BinOp::Add => l + r,
+            BinOp::Sub => l - r,
+            BinOp::Mul => l * r,
+            BinOp::Div | BinOp::Mod if r.is_zero() => {
+                return Err(anyhow::anyhow!("division by zero."));
+            }
+            BinOp::Div => {
+                let q = l / r;
+                if floor && l.is_negative() != r.is_negative() && !(l % r).is_zero() {
+                    q - 1
+                } else {
+                    q
+                }
+            }
+            BinOp::Mod => {
+                let m = l % r;
+                if floor && !m.is_zero() && m.is_negative() != r.is_negative() {
+                    m + r
+                } else {
+                    m
+                }
+            }
+        };
+        Ok(Number(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Number;
+    use crate::arith::{Arith, BinOp, Division, Overflow};
+
+    #[test]
+    fn parse_binary() {
+        let n = Number::from_str_radix("-101", 2).unwrap();
+        assert_eq!(Number::from(-5), n);
+    }
+
+    #[test]
+    fn to_binary() {
+        assert_eq!((false, "0".to_owned()), Number::from(0).to_binary());
+        assert_eq!((true, "101".to_owned()), Number::from(-5).to_binary());
+        let (neg, bin) = Number::from(i64::MIN).to_binary();
+        assert!(neg);
+        assert_eq!(64, bin.len());
+    }
+
+    #[test]
+    fn low_byte() {
+        assert_eq!(b'A', Number::from(65).low_byte());
+        assert_eq!(0xff, Number::from(-1).low_byte());
+        assert_eq!(0x01, Number::from(257).low_byte());
+    }
+
+    #[test]
+    fn signed_bytes() {
+        for n in [0, 1, -1, 127, 128, -128, -129, i64::MAX, i64::MIN].iter() {
+            let bytes = Number::from(*n).to_signed_bytes_le();
+            assert_eq!(
+                Number::from(*n),
+                Number::from_signed_bytes_le(&bytes).unwrap()
+            );
+        }
+        assert_eq!(vec![0x80, 0x00], Number::from(128).to_signed_bytes_le());
+        assert_eq!(vec![0xff], Number::from(-1).to_signed_bytes_le());
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn beyond_i64() {
+        let max = Number::from(i64::MAX);
+        let n = Number::apply(BinOp::Mul, &max, &max, &Arith::default()).unwrap();
+        assert_eq!(None, n.to_i64());
+        assert_eq!("85070591730234615847396907784232501249", n.to_string());
+    }
+
+    #[test]
+    fn division() {
+        let (l, r) = (Number::from(-7), Number::from(2));
+        let floor = Arith {
+            overflow: Overflow::Trap,
+            division: Division::Floor,
+        };
+        let trunc = Arith::default();
+        let f = |op, arith| Number::apply(op, &l, &r, &arith).unwrap();
+        assert_eq!(Number::from(-4), f(BinOp::Div, floor));
+        assert_eq!(Number::from(1), f(BinOp::Mod, floor));
+        assert_eq!(Number::from(-3), f(BinOp::Div, trunc));
+        assert_eq!(Number::from(-1), f(BinOp::Mod, trunc));
+        let zero = Number::from(0);
+        assert!(Number::apply(BinOp::Div, &l, &zero, &floor).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+//! 命令列ののぞき穴最適化
+//!
+//! 変化がなくなるまで次の書き換えを繰り返す
+//!
+//! - 定数の畳み込み（``push 2; push 3; add``を``push 5``に）
+//! - 何もしない命令の組の削除（``dup; discard``、``swap; swap``など）
+//! - 定数による条件分岐の解決と、分岐先が分岐の場合の飛び越し
+//! - 到達できない命令と、使われないラベルの削除
+//!
+//! スタックが足りない場合にエラーになる命令は、検査器がスタックの深さを保証できる場合だけ消す
+//! そのため実行時エラーになるプログラムも、エラーの内容は最適化しない場合と同じ
+//!
+//! このほか、VMで実行する際によく現れる2命令の組を複合命令にまとめる（[`fuse`]）
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    arith::{Arith, BinOp},
+    instruction::Instruction,
+    linker::{Op, Program},
+    number::Number,
+    verifier,
+};
+
+/// 書き換えを繰り返す回数の上限
+const MAX_PASSES: usize = 64;
+
+/// 最適化した命令列
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Optimized {
+    pub insts: Vec<Instruction>,
+    /// 各命令の元の命令の添字。エラーの表示で元のコードの位置を示すのに使う
+    pub origins: Vec<usize>,
+}
+
+/// arithは定数の畳み込みに使う。実行時と同じものを渡す
+pub fn optimize(insts: &[Instruction], arith: &Arith) -> Optimized {
+    let mut code: Vec<(Instruction, usize)> = insts.iter().cloned().zip(0..).collect();
+    for _ in 0..MAX_PASSES {
+        match pass(&code, arith) {
+            Some(next) => code = next,
+            None => break,
+        }
+    }
+    let (insts, origins) = code.into_iter().unzip();
+    Optimized { insts, origins }
+}
+
+/// 1回分の書き換え。変化がなければNone
+fn pass(code: &[(Instruction, usize)], arith: &Arith) -> Option<Vec<(Instruction, usize)>> {
+    let insts: Vec<Instruction> = code.iter().map(|(inst, _)| inst.clone()).collect();
+    let analysis = verifier::verify(&insts);
+    // 各命令の直前に保証されるスタックの深さ
+    let depth = |i: usize| analysis.depths[i].and_then(|d| d.min).unwrap_or(0);
+    let flow = Flow::new(&insts);
+
+    let mut out = Vec::with_capacity(code.len());
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        // 到達できない命令
+        if analysis.depths[i].is_none() {
+            changed = true;
+            i += 1;
+            continue;
+        }
+        let rest = &code[i..];
+        let (replaced, consumed) = match rest {
+            [(Instruction::Label(l), _), ..] if !flow.is_target(l, i) => (vec![], 1),
+            [(Instruction::Push(a), _), (Instruction::Push(b), _), (op, origin), ..] => {
+                match binop(op).and_then(|op| Number::apply(op, a, b, arith).ok()) {
+                    Some(n) => (vec![(Instruction::Push(n), *origin)], 3),
+                    None => match op {
+                        Instruction::Swap => (
+                            vec![
+                                (Instruction::Push(b.clone()), code[i].1),
+                                (Instruction::Push(a.clone()), code[i + 1].1),
+                            ],
+                            3,
+                        ),
+                        _ => (vec![code[i].clone()], 1),
+                    },
+                }
+            }
+            [(Instruction::Push(_), _), (Instruction::Discard, _), ..] => (vec![], 2),
+            [(Instruction::Push(a), o1), (Instruction::Dup, o2), ..] => (
+                vec![
+                    (Instruction::Push(a.clone()), *o1),
+                    (Instruction::Push(a.clone()), *o2),
+                ],
+                2,
+            ),
+            [(Instruction::Push(a), _), (Instruction::JumpZero(l), origin), ..] => {
+                if a.is_zero() {
+                    (vec![(Instruction::Jump(l.clone()), *origin)], 2)
+                } else {
+                    (vec![], 2)
+                }
+            }
+            [(Instruction::Push(a), _), (Instruction::JumpNeg(l), origin), ..] => {
+                if a.is_negative() {
+                    (vec![(Instruction::Jump(l.clone()), *origin)], 2)
+                } else {
+                    (vec![], 2)
+                }
+            }
+            [(Instruction::Dup, _), (Instruction::Discard, _), ..] if depth(i) >= 1 => (vec![], 2),
+            [(Instruction::Swap, _), (Instruction::Swap, _), ..] if depth(i) >= 2 => (vec![], 2),
+            [(Instruction::Slide(0), _), ..] if depth(i) >= 1 => (vec![], 1),
+            [(Instruction::Copy(0), origin), ..] if depth(i) >= 1 => {
+                (vec![(Instruction::Dup, *origin)], 1)
+            }
+            [(Instruction::Jump(l), origin), ..] => match flow.follow(l) {
+                // 直後のラベルへの分岐
+                _ if flow.falls_into(i, l) => (vec![], 1),
+                Target::Inst(inst) => (vec![(inst, *origin)], 1),
+                Target::Label(m) if m != *l => (vec![(Instruction::Jump(m), *origin)], 1),
+                _ => (vec![code[i].clone()], 1),
+            },
+            [(inst, origin), ..] => match retarget(inst, &flow) {
+                Some(inst) => (vec![(inst, *origin)], 1),
+                None => (vec![code[i].clone()], 1),
+            },
+            [] => unreachable!(),
+        };
+        if consumed != 1 || replaced.len() != 1 || replaced[0] != code[i] {
+            changed = true;
+        }
+        out.extend(replaced);
+        i += consumed;
+    }
+    if changed {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+fn binop(inst: &Instruction) -> Option<BinOp> {
+    match inst {
+        Instruction::Add => Some(BinOp::Add),
+        Instruction::Sub => Some(BinOp::Sub),
+        Instruction::Mul => Some(BinOp::Mul),
+        Instruction::Div => Some(BinOp::Div),
+        Instruction::Mod => Some(BinOp::Mod),
+        _ => None,
+    }
+}
+
+/// 分岐先が無条件分岐なら、その先のラベルに付け替える
+fn retarget(inst: &Instruction, flow: &Flow) -> Option<Instruction> {
+    let (label, make): (&String, fn(String) -> Instruction) = match inst {
+        Instruction::Call(l) => (l, Instruction::Call),
+        Instruction::JumpZero(l) => (l, Instruction::JumpZero),
+        Instruction::JumpNeg(l) => (l, Instruction::JumpNeg),
+        _ => return None,
+    };
+    match flow.follow(label) {
+        Target::Label(m) if m != *label => Some(make(m)),
+        _ => None,
+    }
+}
+
+/// 分岐を辿った先
+enum Target {
+    /// このラベルに分岐する
+    Label(String),
+    /// 分岐する代わりにこの命令を実行すればよい（exit、ret）
+    Inst(Instruction),
+}
+
+/// ラベルの定義位置と参照
+struct Flow<'a> {
+    insts: &'a [Instruction],
+    /// 最初の定義の位置
+    defs: HashMap<&'a str, usize>,
+    /// 分岐・呼び出しで参照されるラベル
+    used: HashSet<&'a str>,
+}
+
+impl<'a> Flow<'a> {
+    fn new(insts: &'a [Instruction]) -> Self {
+        let mut defs = HashMap::new();
+        let mut used = HashSet::new();
+        for (i, inst) in insts.iter().enumerate() {
+            match inst {
+                Instruction::Label(l) => {
+                    defs.entry(l.as_str()).or_insert(i);
+                }
+                Instruction::Call(l)
+                | Instruction::Jump(l)
+                | Instruction::JumpZero(l)
+                | Instruction::JumpNeg(l) => {
+                    used.insert(l.as_str());
+                }
+                _ => (),
+            }
+        }
+        Self { insts, defs, used }
+    }
+
+    /// i番目のラベルが分岐先になりうるか
+    /// 2つ目以降の定義は使われない
+    fn is_target(&self, label: &str, i: usize) -> bool {
+        self.used.contains(label) && self.defs.get(label) == Some(&i)
+    }
+
+    /// ラベルの後の最初のラベル以外の命令
+    fn first_after(&self, label: &str) -> Option<&'a Instruction> {
+        let pos = *self.defs.get(label)?;
+        self.insts[pos + 1..]
+            .iter()
+            .find(|inst| !matches!(inst, Instruction::Label(_)))
+    }
+
+    /// 無条件分岐の連鎖を辿る
+    fn follow(&self, label: &str) -> Target {
+        let mut label = label;
+        let mut seen = HashSet::new();
+        while seen.insert(label) {
+            match self.first_after(label) {
+                Some(Instruction::Jump(next)) => label = next,
+                Some(Instruction::Exit) => return Target::Inst(Instruction::Exit),
+                Some(Instruction::Return) => return Target::Inst(Instruction::Return),
+                _ => break,
+            }
+        }
+        Target::Label(label.to_owned())
+    }
+
+    /// i番目の命令から、ラベルだけを挟んでlabelの定義に達するか
+    fn falls_into(&self, i: usize, label: &str) -> bool {
+        match self.defs.get(label) {
+            Some(pos) if *pos > i => self.insts[i + 1..*pos]
+                .iter()
+                .all(|inst| matches!(inst, Instruction::Label(_))),
+            _ => false,
+        }
+    }
+}
+
+/// よく現れる2命令の組を、1命令目の位置で複合命令に置き換える
+/// 2命令目は残すので、命令の位置と分岐先は変わらない
+pub fn fuse(program: &mut Program) {
+    for i in 0..program.len().saturating_sub(1) {
+        let fused = match (&program.ops[i], &program.ops[i + 1]) {
+            (Op::Push(n), Op::Add) => Op::PushArith(BinOp::Add, n.clone()),
+            (Op::Push(n), Op::Sub) => Op::PushArith(BinOp::Sub, n.clone()),
+            (Op::Push(n), Op::Mul) => Op::PushArith(BinOp::Mul, n.clone()),
+            (Op::Push(n), Op::Div) => Op::PushArith(BinOp::Div, n.clone()),
+            (Op::Push(n), Op::Mod) => Op::PushArith(BinOp::Mod, n.clone()),
+            (Op::Push(n), Op::HeapRead) => Op::PushLoad(n.clone()),
+            (Op::Dup, Op::JumpZero(dest)) => Op::DupJumpZero(*dest),
+            (Op::Dup, Op::JumpNeg(dest)) => Op::DupJumpNeg(*dest),
+            _ => continue,
+        };
+        program.ops[i] = fused;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::{assembler, compiler::Compiler, vm::VM};
+
+    fn optimized(src: &str) -> Vec<Instruction> {
+        let insts = assembler::parse(src).unwrap();
+        optimize(&insts, &Arith::default()).insts
+    }
+
+    /// (出力, エラー)
+    fn run(insts: &[Instruction], input: &str, optimized: bool) -> (String, String) {
+        let mut output = vec![];
+        let res = if optimized {
+            let insts = optimize(insts, &Arith::default()).insts;
+            VM::new(insts, input.as_bytes(), &mut output)
+                .with_fusion()
+                .run()
+        } else {
+            VM::new(insts.to_vec(), input.as_bytes(), &mut output).run()
+        };
+        let err = res.err().map(|e| e.to_string()).unwrap_or_default();
+        (String::from_utf8(output).unwrap(), err)
+    }
+
+    #[test]
+    fn fold_constants() {
+        let expect = assembler::parse("push 7\noutn\nexit").unwrap();
+        assert_eq!(
+            expect,
+            optimized("push 2\npush 3\nmul\npush 1\nadd\noutn\nexit")
+        );
+        let expect = assembler::parse("push -1\noutn\nexit").unwrap();
+        assert_eq!(expect, optimized("push 2\npush 1\nswap\nsub\noutn\nexit"));
+        // 0除算は実行時のエラーのために残す
+        let src = "push 1\npush 0\ndiv\nexit";
+        assert_eq!(assembler::parse(src).unwrap(), optimized(src));
+    }
+
+    #[test]
+    fn remove_no_ops() {
+        let expect = assembler::parse("push 1\noutn\nexit").unwrap();
+        assert_eq!(
+            expect,
+            optimized("push 1\ndup\ndiscard\npush 5\ndiscard\nslide 0\noutn\nexit")
+        );
+        // スタックが空ならdupのエラーを残す
+        let src = "dup\ndiscard\nexit";
+        assert_eq!(assembler::parse(src).unwrap(), optimized(src));
+        // ホスト関数が引数を取り出すかもしれない
+        let src = "push 1\nhost 0\ndup\ndiscard\nswap\nswap\nslide 0\nexit";
+        assert_eq!(assembler::parse(src).unwrap(), optimized(src));
+    }
+
+    #[test]
+    fn branches() {
+        let src = "
+            push 0
+            jz a
+            push 5
+            outn
+        a:
+            jmp b
+        b:
+            push 1
+            call s
+            jmp e
+        s:
+            jmp c
+        c:
+            ret
+        e:
+            exit
+        ";
+        // ラベル名は付け替わるので命令の種類だけ比べる
+        let mnemonics: Vec<String> = optimized(src)
+            .iter()
+            .map(|inst| inst.to_string().split(' ').next().unwrap().to_owned())
+            .collect();
+        assert_eq!(vec!["push", "call", "exit", "label", "ret"], mnemonics);
+    }
+
+    #[test]
+    fn equivalence() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let cases = [
+            ("hello.ws", ""),
+            ("fib.ws", "20\n"),
+            ("fact.ws", "10\n"),
+            ("fact.ws", "x\n"),
+        ];
+        for (name, input) in cases.iter() {
+            let code = fs::read_to_string(dir.join(name)).unwrap();
+            let insts = Compiler::new(code).compile().unwrap();
+            assert_eq!(
+                run(&insts, input, false),
+                run(&insts, input, true),
+                "{}",
+                name
+            );
+        }
+        let programs = [
+            "push 3\ndup\njz a\npush 1\nsub\noutn\na:\nexit",
+            "push -1\ndup\njn a\nexit\na:\npush 0\nload",
+            "push 10\npush 7\nstore\npush 10\nload\npush 3\nmod\noutn\nexit",
+            "push 1\npush 0\ndiv",
+            "jmp nowhere",
+            "push 1\nadd",
+            "swap\nswap",
+        ];
+        for src in programs.iter() {
+            let insts = assembler::parse(src).unwrap();
+            assert_eq!(run(&insts, "", false), run(&insts, "", true), "{}", src);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! 命令ごとの実行回数と、サブルーチンの呼び出しグラフを集計する
+//!
+//! サブルーチンは呼び出し先のラベルで表し、開始位置から始まる部分は``main``とする
+//! 呼び出しの経路ごとに実行した命令数を数えるので、inclusive（呼び出し先を含む）と
+//! exclusive（そのサブルーチン自身）の命令数、flamegraph向けのfolded形式を出せる
+
+use std::collections::HashMap;
+
+use crate::linker::{Op, Program};
+
+/// 開始位置から始まる部分の名前
+const ROOT: &str = "main";
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// 命令位置ごとの実行回数
+    counts: Vec<u64>,
+    /// 呼び出しの経路を表す木。親は必ず子より前に並ぶ
+    nodes: Vec<Node>,
+    /// 実行中のノード
+    current: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// 呼び出し先の命令位置。根は0
+    entry: usize,
+    parent: Option<usize>,
+    /// K: 呼び出し先の命令位置, V: ノード
+    children: HashMap<usize, usize>,
+    /// このサブルーチン自身が実行した命令数
+    steps: u64,
+    /// この経路で呼び出された回数
+    calls: u64,
+}
+
+impl Node {
+    fn new(entry: usize, parent: Option<usize>) -> Self {
+        Self {
+            entry,
+            parent,
+            children: HashMap::new(),
+            steps: 0,
+            calls: 0,
+        }
+    }
+}
+
+/// サブルーチンごとの集計
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub calls: u64,
+    /// 呼び出し先で実行した命令を含む命令数。再帰呼び出しは二重に数えない
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+/// 呼び出し元と呼び出し先の組ごとの集計
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub caller: String,
+    pub callee: String,
+    pub calls: u64,
+}
+
+impl Profile {
+    pub(crate) fn new(len: usize) -> Self {
+        let mut root = Node::new(0, None);
+        root.calls = 1;
+        Self {
+            counts: vec![0; len],
+            nodes: vec![root],
+            current: 0,
+        }
+    }
+
+    /// 実行した命令を記録する。呼び出しと復帰は成功した場合だけ木をたどる
+    #[inline]
+    pub(crate) fn record(&mut self, pc: usize, op: &Op, ok: bool) {
+        self.counts[pc] += 1;
+        self.nodes[self.current].steps += 1;
+        if op.head().is_some() {
+            self.counts[pc + 1] += 1;
+            self.nodes[self.current].steps += 1;
+        }
+        if !ok {
+            return;
+        }
+        match op {
+            Op::Call(target) => self.enter(*target),
+            Op::Return => {
+                if let Some(parent) = self.nodes[self.current].parent {
+                    self.current = parent;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn enter(&mut self, entry: usize) {
+        let next = self.nodes.len();
+        let child = *self.nodes[self.current]
+            .children
+            .entry(entry)
+            .or_insert(next);
+        if child == next {
+            self.nodes.push(Node::new(entry, Some(self.current)));
+        }
+        self.nodes[child].calls += 1;
+        self.current = child;
+    }
+
+    /// 命令位置ごとの実行回数
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// 実行した命令数の合計
+    pub fn total(&self) -> u64 {
+        self.nodes.iter().map(|n| n.steps).sum()
+    }
+
+    /// サブルーチンごとの集計。inclusiveの多い順
+    pub fn functions(&self, program: &Program) -> Vec<Function> {
+        let names = Names::new(program);
+        let inclusive = self.inclusive();
+        let mut functions: HashMap<usize, Function> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let f = functions.entry(node.entry).or_insert_with(|| Function {
+                name: names.function(node.entry),
+                calls: 0,
+                inclusive: 0,
+                exclusive: 0,
+            });
+            f.calls += node.calls;
+            f.exclusive += node.steps;
+            // 再帰呼び出しの内側は外側のinclusiveに含まれている
+            if !self.ancestors(i).any(|a| self.nodes[a].entry == node.entry) {
+                f.inclusive += inclusive[i];
+            }
+        }
+        let mut functions: Vec<Function> = functions.into_values().collect();
+        functions.sort_by(|a, b| {
+            b.inclusive
+                .cmp(&a.inclusive)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        functions
+    }
+
+    /// 呼び出し元と呼び出し先の組ごとの呼び出し回数。多い順
+    pub fn edges(&self, program: &Program) -> Vec<Edge> {
+        let names = Names::new(program);
+        let mut edges: HashMap<(usize, usize), u64> = HashMap::new();
+        for node in self.nodes.iter() {
+            if let Some(parent) = node.parent {
+                let key = (self.nodes[parent].entry, node.entry);
+                *edges.entry(key).or_insert(0) += node.calls;
+            }
+        }
+        let mut edges: Vec<Edge> = edges
+            .into_iter()
+            .map(|((caller, callee), calls)| Edge {
+                caller: names.function(caller),
+                callee: names.function(callee),
+                calls,
+            })
+            .collect();
+        edges.sort_by(|a, b| {
+            b.calls
+                .cmp(&a.calls)
+                .then_with(|| (&a.caller, &a.callee).cmp(&(&b.caller, &b.callee)))
+        });
+        edges
+    }
+
+    /// 直前のラベルごとの命令数。多い順
+    pub fn labels(&self, program: &Program) -> Vec<(String, u64)> {
+        let names = Names::new(program);
+        let mut labels: Vec<(String, u64)> = vec![];
+        let mut current = ROOT.to_owned();
+        let mut steps = 0;
+        for (pc, count) in self.counts.iter().take(program.len()).enumerate() {
+            if let Some(name) = names.labels.get(&pc) {
+                labels.push((current, steps));
+                current = name.to_string();
+                steps = 0;
+            }
+            steps += count;
+        }
+        labels.push((current, steps));
+        labels.retain(|(_, steps)| *steps > 0);
+        labels.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        labels
+    }
+
+    /// flamegraphのツールで読めるfolded形式
+    /// 呼び出しの経路を``;``でつなぎ、その経路で実行した命令数を続ける
+    pub fn folded(&self, program: &Program) -> String {
+        let names = Names::new(program);
+        let mut lines: Vec<String> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.steps > 0)
+            .map(|(i, node)| {
+                let mut path: Vec<String> = std::iter::once(i)
+                    .chain(self.ancestors(i))
+                    .map(|n| names.function(self.nodes[n].entry))
+                    .collect();
+                path.reverse();
+                format!("{} {}\n", path.join(";"), node.steps)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+
+    /// 人が読むための集計表。命令は実行回数の多いものからtop個
+    pub fn report(&self, program: &Program, top: usize) -> String {
+        let mut res = format!("total steps: {}\n", self.total());
+
+        res += "\nsubroutines\n";
+        res += &format!(
+            "{:>12}  {:>12}  {:>10}  name\n",
+            "inclusive", "exclusive", "calls"
+        );
+        for f in self.functions(program) {
+            res += &format!(
+                "{:>12}  {:>12}  {:>10}  {}\n",
+                f.inclusive, f.exclusive, f.calls, f.name
+            );
+        }
+
+        let edges = self.edges(program);
+        if !edges.is_empty() {
+            res += "\ncall graph\n";
+            res += &format!("{:>10}  caller -> callee\n", "calls");
+            for e in edges {
+                res += &format!("{:>10}  {} -> {}\n", e.calls, e.caller, e.callee);
+            }
+        }
+
+        res += "\nlabels\n";
+        res += &format!("{:>12}  label\n", "steps");
+        for (name, steps) in self.labels(program) {
+            res += &format!("{:>12}  {}\n", steps, name);
+        }
+
+        let mut insts: Vec<(usize, u64)> = self
+            .counts
+            .iter()
+            .copied()
+            .enumerate()
+            .take(program.len())
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        insts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        res += "\ninstructions\n";
+        res += &format!("{:>12}  {:>6}  instruction\n", "count", "index");
+        for (pc, count) in insts.into_iter().take(top) {
+            res += &format!("{:>12}  {:>6}  {}\n", count, pc, program.ops[pc]);
+        }
+        res
+    }
+
+    /// 呼び出し先を含む命令数をノードごとに求める
+    fn inclusive(&self) -> Vec<u64> {
+        let mut res: Vec<u64> = self.nodes.iter().map(|n| n.steps).collect();
+        for i in (1..self.nodes.len()).rev() {
+            if let Some(parent) = self.nodes[i].parent {
+                res[parent] += res[i];
+            }
+        }
+        res
+    }
+
+    fn ancestors(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(self.nodes[node].parent, move |n| self.nodes[*n].parent)
+    }
+}
+
+/// 命令位置からラベル名を引く
+struct Names<'a> {
+    /// K: ラベルの位置, V: ラベル名
+    labels: HashMap<usize, &'a str>,
+}
+
+impl<'a> Names<'a> {
+    fn new(program: &'a Program) -> Self {
+        let labels = program
+            .labels
+            .iter()
+            .map(|(name, pc)| (*pc, name.as_str()))
+            .collect();
+        Self { labels }
+    }
+
+    /// 分岐先はラベルの直後を指す
+    fn function(&self, entry: usize) -> String {
+        if entry == 0 {
+            return ROOT.to_owned();
+        }
+        match self.labels.get(&(entry - 1)) {
+            Some(name) => name.to_string(),
+            None => format!("@{}", entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{assembler, vm::VM};
+
+    fn profile(src: &str) -> (Profile, VM<&'static [u8], io::Sink>) {
+        let insts = assembler::parse(src).unwrap();
+        let mut vm = VM::new(insts, "".as_bytes(), io::sink()).with_profile();
+        vm.run().unwrap();
+        (vm.profile().unwrap().clone(), vm)
+    }
+
+    #[test]
+    fn call_graph() {
+        // sをsの中から1回、mainから2回呼ぶ。tはsから呼ぶ
+        let src = "push 1\ncall s\npush 0\ncall s\nexit\n\
+                   s:\njz ss\ncall t\npush 0\ncall s\nss:\nret\n\
+                   t:\npush 1\ndiscard\nret";
+        let (profile, vm) = profile(src);
+        let program = vm.program();
+        assert_eq!(1, profile.counts()[0]);
+        assert_eq!(3, profile.counts()[6]);
+        assert_eq!(profile.total(), vm.steps());
+
+        let functions = profile.functions(program);
+        let f = |name: &str| {
+            let f = functions.iter().find(|f| f.name == name).unwrap();
+            (f.calls, f.inclusive, f.exclusive)
+        };
+        assert_eq!((1, 18, 5), f("main"));
+        // 再帰呼び出しの2回分はinclusiveに二重に数えない
+        assert_eq!((3, 13, 10), f("s"));
+        assert_eq!((1, 3, 3), f("t"));
+
+        let edges = profile.edges(program);
+        let calls: Vec<(&str, &str, u64)> = edges
+            .iter()
+            .map(|e| (e.caller.as_str(), e.callee.as_str(), e.calls))
+            .collect();
+        assert_eq!(vec![("main", "s", 2), ("s", "s", 1), ("s", "t", 1)], calls);
+
+        assert_eq!(
+            "main 5\nmain;s 8\nmain;s;s 2\nmain;s;t 3\n",
+            profile.folded(program)
+        );
+    }
+
+    #[test]
+    fn labels() {
+        // ラベルの後の命令は、次のラベルまでそのラベルに数える
+        let src = "push 3\ns:\npush 1\nsub\ndup\njz t\njmp s\nt:\nexit";
+        let (profile, vm) = profile(src);
+        let labels = profile.labels(vm.program());
+        let expect = vec![
+            ("s".to_owned(), 15),
+            ("main".to_owned(), 1),
+            ("t".to_owned(), 1),
+        ];
+        assert_eq!(expect, labels);
+    }
+}
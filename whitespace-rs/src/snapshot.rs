@@ -0,0 +1,270 @@
+//! VMの実行状態を保存するバイナリ形式
+//!
+//! 数値はすべてリトルエンディアン
+//!
+//! ```text
+//! magic     b"WSSN"
+//! version   u16
+//! program   u64            命令列のハッシュ（bytecode::fingerprint）
+//! flags     u16            bit 0: 最適化した命令列で実行していた
+//! pc        u64
+//! steps     u64            実行した命令数
+//! written   u64            出力したバイト数
+//! stack     u32 個数, 各数値は u32 長さ + 2の補数のリトルエンディアン
+//! heap      u32 個数, 各要素は (アドレス, 値)。アドレス順
+//! calls     u32 個数, 各戻り先は u64
+//! input     u32 長さ + 読み込み済みでまだ使っていない入力
+//! checksum  u64            ここまでのFNV-1a
+//! ```
+//!
+//! 入力は読み込んだ分しか保存しないので、残りは再開するときに与える
+
+use anyhow::Result;
+
+use crate::{
+    bytecode::{fnv1a, Reader, Writer},
+    number::Number,
+};
+
+pub const MAGIC: &[u8; 4] = b"WSSN";
+pub const VERSION: u16 = 2;
+
+const FLAG_OPTIMIZED: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// 命令列のハッシュ。別のプログラムでは再開しない
+    pub program: u64,
+    /// 最適化した命令列で実行していたか。最適化の有無が違えば命令列のハッシュも違う
+    pub optimized: bool,
+    pub pc: usize,
+    pub steps: u64,
+    pub written: u64,
+    pub stack: Vec<Number>,
+    /// アドレス順
+    pub heap: Vec<(Number, Number)>,
+    pub call_stack: Vec<usize>,
+    pub input: Vec<u8>,
+}
+
+/// 先頭がマジックナンバーならスナップショットとみなす
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w.u64(self.program);
+        w.u16(if self.optimized { FLAG_OPTIMIZED } else { 0 });
+        w.u64(self.pc as u64);
+        w.u64(self.steps);
+        w.u64(self.written);
+        w.u32(self.stack.len() as u32);
+        for n in self.stack.iter() {
+            w.number(n);
+        }
+        w.u32(self.heap.len() as u32);
+        for (address, value) in self.heap.iter() {
+            w.number(address);
+            w.number(value);
+        }
+        w.u32(self.call_stack.len() as u32);
+        for pc in self.call_stack.iter() {
+            w.u64(*pc as u64);
+        }
+        w.u32(self.input.len() as u32);
+        w.bytes(&self.input);
+
+        let checksum = fnv1a(&w.buf);
+        w.u64(checksum);
+        w.buf
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if !is_snapshot(bytes) {
+            return Err(anyhow::anyhow!(
+                "not a Whitespace snapshot file (bad magic number)."
+            ));
+        }
+        let mut r = Reader::new(bytes, "snapshot");
+        r.pos = MAGIC.len();
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(anyhow::anyhow!(
+                "unsupported snapshot version: {} (supported: {}).",
+                version,
+                VERSION
+            ));
+        }
+        if bytes.len() < 8 {
+            return Err(r.corrupted("unexpected end of data"));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 8);
+        let mut buf = [0; 8];
+        buf.copy_from_slice(checksum);
+        if fnv1a(body) != u64::from_le_bytes(buf) {
+            return Err(r.corrupted("checksum mismatch"));
+        }
+        r.bytes = body;
+
+        let program = r.u64()?;
+        let flags = r.u16()?;
+        if flags & !FLAG_OPTIMIZED != 0 {
+            return Err(r.corrupted("unknown flags"));
+        }
+        let optimized = flags & FLAG_OPTIMIZED != 0;
+        let pc = r.u64()? as usize;
+        let steps = r.u64()?;
+        let written = r.u64()?;
+        let len = r.u32()? as usize;
+        let mut stack = Vec::with_capacity(len.min(body.len()));
+        for _ in 0..len {
+            stack.push(r.number()?);
+        }
+        let len = r.u32()? as usize;
+        let mut heap: Vec<(Number, Number)> = Vec::with_capacity(len.min(body.len()));
+        for _ in 0..len {
+            let address = r.number()?;
+            if heap.last().is_some_and(|(last, _)| *last >= address) {
+                return Err(r.corrupted("heap addresses are not sorted"));
+            }
+            heap.push((address, r.number()?));
+        }
+        let len = r.u32()? as usize;
+        let mut call_stack = Vec::with_capacity(len.min(body.len()));
+        for _ in 0..len {
+            call_stack.push(r.u64()? as usize);
+        }
+        let len = r.u32()? as usize;
+        let input = r.take(len)?.to_vec();
+
+        if r.pos != r.bytes.len() {
+            return Err(r.corrupted("trailing data"));
+        }
+        Ok(Self {
+            program,
+            optimized,
+            pc,
+            steps,
+            written,
+            stack,
+            heap,
+            call_stack,
+            input,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        assembler,
+        vm::{Eof, VM},
+    };
+
+    /// 1文字ずつ読んで、読んだ文字数をヒープの0番地に数えながらそのまま出力する
+    const ECHO: &str = "
+        push 0
+        push 0
+        store
+    loop:
+        push 1
+        inc
+        push 1
+        load
+        dup
+        jn end
+        outc
+        push 0
+        push 0
+        load
+        push 1
+        add
+        store
+        jmp loop
+    end:
+        exit
+    ";
+
+    #[test]
+    fn resume() {
+        let insts = assembler::parse(ECHO).unwrap();
+        let input = "hello, world";
+
+        let mut expect = vec![];
+        VM::new(insts.clone(), input.as_bytes(), &mut expect)
+            .with_eof(Eof::MinusOne)
+            .run()
+            .unwrap();
+
+        // 途中で止めて、残りの入力を与えずに再開する
+        let mut first = vec![];
+        let mut vm = VM::new(insts.clone(), input.as_bytes(), &mut first)
+            .with_eof(Eof::MinusOne)
+            .with_pause_at(40);
+        vm.run().unwrap();
+        assert!(!vm.is_exited());
+        let bytes = vm.snapshot().encode();
+        drop(vm);
+
+        let snapshot = Snapshot::decode(&bytes).unwrap();
+        assert_eq!(40, snapshot.steps);
+        // 3文字目まで読んでいる
+        assert_eq!(&input.as_bytes()[3..], &snapshot.input[..]);
+        let mut second = vec![];
+        let mut vm = VM::new(insts, "".as_bytes(), &mut second).with_eof(Eof::MinusOne);
+        vm.restore(snapshot).unwrap();
+        vm.run().unwrap();
+        assert!(vm.is_exited());
+        assert_eq!(12, vm.heap()[&Number::from(0)].to_i64().unwrap());
+        drop(vm);
+
+        first.extend(second);
+        assert_eq!(expect, first);
+    }
+
+    #[test]
+    fn errors() {
+        let insts = assembler::parse(ECHO).unwrap();
+        let vm = VM::new(insts, "".as_bytes(), io::sink());
+        let snapshot = vm.snapshot();
+        let mut bytes = snapshot.encode();
+        assert_eq!(snapshot, Snapshot::decode(&bytes).unwrap());
+
+        let other = assembler::parse("push 1\nexit").unwrap();
+        let mut vm = VM::new(other, "".as_bytes(), io::sink());
+        let err = vm.restore(snapshot).unwrap_err();
+        assert_eq!(
+            "the snapshot was taken from a different program.",
+            err.to_string()
+        );
+
+        // 最適化の有無が違う場合は、別のプログラムとは言わずにその旨を示す
+        let insts = assembler::parse(ECHO).unwrap();
+        let snapshot = VM::new(insts.clone(), "".as_bytes(), io::sink())
+            .with_fusion()
+            .snapshot();
+        assert_eq!(snapshot, Snapshot::decode(&snapshot.encode()).unwrap());
+        let mut vm = VM::new(insts, "".as_bytes(), io::sink());
+        let err = vm.restore(snapshot).unwrap_err();
+        assert_eq!(
+            "the snapshot was taken with the program optimized, but it is now run as written. resume with the same optimization setting.",
+            err.to_string()
+        );
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let err = Snapshot::decode(&bytes).unwrap_err();
+        assert_eq!(
+            "corrupted snapshot file: checksum mismatch.",
+            err.to_string()
+        );
+        assert!(Snapshot::decode(b"WSBC").is_err());
+    }
+}
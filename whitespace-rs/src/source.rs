@@ -0,0 +1,184 @@
+use std::fmt;
+
+use crate::token::{self, Notation};
+
+/// ソースコード上の位置
+/// line, columnは1始まりで、columnは文字単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Pos {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Pos {
+    /// この位置にある1文字の直後
+    pub fn next(&self, c: char) -> Self {
+        if c == '\n' {
+            Self {
+                offset: self.offset + 1,
+                line: self.line + 1,
+                column: 1,
+            }
+        } else {
+            Self {
+                offset: self.offset + c.len_utf8(),
+                line: self.line,
+                column: self.column + 1,
+            }
+        }
+    }
+}
+
+/// ソースコード末尾の位置
+pub fn end_pos(src: &str) -> Pos {
+    let start = Pos {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+    src.chars().fold(start, |pos, c| pos.next(c))
+}
+
+/// [start, end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+impl Span {
+    /// 範囲内のトークンをS/T/Lで表した列
+    pub fn visible(&self, src: &str, notation: Notation) -> String {
+        let text = &src[self.start.offset..self.end.offset];
+        match notation {
+            Notation::Whitespace => visible(text),
+            // 範囲はトークンの境目にあるので、切り出しても同じトークン列になる
+            Notation::Visible => token::tokenize(text, notation)
+                .map(|tokens| tokens.iter().map(|t| t.token.to_visible()).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// 空白文字をS/T/Lに置き換え、それ以外の文字を除く
+pub fn visible(s: &str) -> String {
+    s.chars()
+        .filter_map(|c| match c {
+            ' ' => Some('S'),
+            '\t' => Some('T'),
+            '\n' => Some('L'),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 位置情報付きのエラー
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub msg: String,
+    pub span: Span,
+    /// ソースコードの表記
+    notation: Notation,
+}
+
+impl Diagnostic {
+    pub fn new(msg: impl Into<String>, span: Span) -> Self {
+        Self {
+            msg: msg.into(),
+            span,
+            notation: Notation::Whitespace,
+        }
+    }
+
+    /// 見える表記のソースコードは、該当行をそのまま示す
+    pub fn with_notation(mut self, notation: Notation) -> Self {
+        self.notation = notation;
+        self
+    }
+
+    /// 該当行を空白が見える形で示し、キャレットで位置を指す
+    ///
+    /// ```text
+    /// [STT] is grammar error.
+    ///  --> line 2, column 1
+    ///   |
+    /// 2 | STTL
+    ///   | ^^^
+    ///   = sequence: STT
+    /// ```
+    pub fn render(&self, src: &str) -> String {
+        let start = self.span.start;
+        let end = self.span.end;
+        let line = src.lines().nth(start.line - 1).unwrap_or("");
+        let shown: String = match self.notation {
+            Notation::Whitespace => {
+                let has_lf = src.split('\n').count() > start.line;
+                let mut shown: String = line
+                    .chars()
+                    .map(|c| match c {
+                        ' ' => 'S',
+                        '\t' => 'T',
+                        _ => c,
+                    })
+                    .collect();
+                if has_lf {
+                    shown.push('L');
+                }
+                shown
+            }
+            Notation::Visible => line.to_owned(),
+        };
+
+        let width = if end.line == start.line && end.column > start.column {
+            end.column - start.column
+        } else if end.line > start.line {
+            shown
+                .chars()
+                .count()
+                .saturating_sub(start.column - 1)
+                .max(1)
+        } else {
+            1
+        };
+
+        let gutter = start.line.to_string().len();
+        let mut res = String::new();
+        res.push_str(&format!("{}\n", self.msg));
+        res.push_str(&format!(
+            "{:>w$}--> line {}, column {}\n",
+            "",
+            start.line,
+            start.column,
+            w = gutter
+        ));
+        res.push_str(&format!("{:>w$} |\n", "", w = gutter));
+        res.push_str(&format!("{} | {}\n", start.line, shown));
+        res.push_str(&format!(
+            "{:>w$} | {}{}\n",
+            "",
+            " ".repeat(start.column - 1),
+            "^".repeat(width),
+            w = gutter
+        ));
+        res.push_str(&format!(
+            "{:>w$} = sequence: {}",
+            "",
+            self.span.visible(src, self.notation),
+            w = gutter
+        ));
+        res
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (line {}, column {})",
+            self.msg, self.span.start.line, self.span.start.column
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {}
@@ -0,0 +1,118 @@
+//! 任意のテキストの中にWhitespaceのプログラムを埋め込む
+//!
+//! 空白文字以外はコメントとして読み飛ばされるので、テキストの見た目を保ったまま
+//! 空白文字だけをプログラムのものに入れ替える
+//!
+//! - テキストの改行はプログラムの改行（``L``）に置き換える
+//! - 改行の間にあるプログラムの空白とタブは、行末に見えない空白として置く
+//! - テキストの行の中の空白とタブは、トークンにならないノーブレークスペース（U+00A0）にする。
+//!   タブは8桁ごとのタブ位置まで埋める
+//!
+//! プログラムの改行がテキストの行より多ければ、残りは空行になる
+
+use anyhow::Result;
+
+/// テキストの行の中の空白の代わり
+const SPACE: char = '\u{a0}';
+const TAB_WIDTH: usize = 8;
+
+/// carrierにcodeのプログラムを埋め込む。codeのコメントは残らない
+pub fn embed(carrier: &str, code: &str) -> Result<String> {
+    let program = extract(code);
+    // segments[i]はi番目の改行の前に置く空白とタブ。最後はプログラム末尾の改行の後
+    let segments: Vec<&str> = program.split('\n').collect();
+    let lines: Vec<&str> = carrier.split('\n').collect();
+    if lines.len() > segments.len() {
+        return Err(anyhow::anyhow!(
+            "the carrier text needs {} line feeds but the program has only {}.",
+            lines.len() - 1,
+            segments.len() - 1
+        ));
+    }
+
+    let mut res = String::with_capacity(carrier.len() + program.len());
+    for (i, segment) in segments.iter().enumerate() {
+        let line = lines.get(i).copied().unwrap_or("");
+        // CRLFの行はCRの前に置き、改行の形を保つ
+        let (line, cr) = match line.strip_suffix('\r') {
+            Some(line) => (line, "\r"),
+            None => (line, ""),
+        };
+        let mut column = 0;
+        for c in line.chars() {
+            match c {
+                ' ' => {
+                    res.push(SPACE);
+                    column += 1;
+                }
+                '\t' => {
+                    let width = TAB_WIDTH - column % TAB_WIDTH;
+                    res.extend(std::iter::repeat_n(SPACE, width));
+                    column += width;
+                }
+                c => {
+                    res.push(c);
+                    column += 1;
+                }
+            }
+        }
+        res.push_str(segment);
+        res.push_str(cr);
+        if i + 1 < segments.len() {
+            res.push('\n');
+        }
+    }
+    Ok(res)
+}
+
+/// 埋め込んだプログラムを取り出す。空白、タブ、改行以外をすべて除く
+pub fn extract(text: &str) -> String {
+    text.chars()
+        .filter(|c| matches!(c, ' ' | '\t' | '\n'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::compiler::Compiler;
+
+    const CARRIER: &str = "fn main() {\r
+\tprintln!(\"hello, world\");\r
+}\r
+";
+
+    #[test]
+    fn embed_and_extract() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fib.ws");
+        let code = fs::read_to_string(path).unwrap();
+        let text = embed(CARRIER, &code).unwrap();
+        assert_eq!(
+            Compiler::new(code.clone()).compile().unwrap(),
+            Compiler::new(text.clone()).compile().unwrap()
+        );
+        assert_eq!(extract(&code), extract(&text));
+
+        // 見た目は元のテキストのまま
+        let shown: String = text
+            .lines()
+            .take(3)
+            .map(|line| line.trim_end().replace(SPACE, " ") + "\n")
+            .collect();
+        assert_eq!(
+            "fn main() {\n        println!(\"hello, world\");\n}\n",
+            shown
+        );
+    }
+
+    #[test]
+    fn too_many_lines() {
+        let err = embed("a\nb\nc", "   \t\n\t").unwrap_err();
+        assert_eq!(
+            "the carrier text needs 2 line feeds but the program has only 1.",
+            err.to_string()
+        );
+    }
+}
@@ -1,31 +1,245 @@
+//! Whitespaceのコードをトークン列にする
+//!
+//! 空白文字で書いた本来の表記のほかに、コードレビューなどで読めるよう
+//! 空白文字を文字で表した見える表記も読める
+//!
+//! ```text
+//! # 1を積んで出力する
+//! "push 1" SS ST L
+//! [Tab][LF][Space][Tab]
+//! ```
+//!
+//! - ``S``、``T``、``L``か``[Space]``、``[Tab]``、``[LF]``がそれぞれ空白、タブ、改行
+//! - 空白文字は読み飛ばす。``#``または``;``から行末まではコメント
+//! - ``"``で囲んだ文字列は、本来の表記に戻すときにそのまま書き出すコメント。
+//!   空白、タブ、改行は含められず、``\"``、``\\``、``\r``でエスケープする
+
+use std::{
+    iter::Peekable,
+    str::{Chars, FromStr},
+};
+
 use anyhow::Result;
-use regex::Regex;
 
-#[derive(Debug, Clone)]
+use crate::source::{Pos, Span};
+
+/// コードの表記
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Notation {
+    /// 空白、タブ、改行で書いた本来の表記
+    #[default]
+    Whitespace,
+    /// S/T/Lで書いた見える表記
+    Visible,
+}
+
+impl FromStr for Notation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "whitespace" | "ws" => Ok(Self::Whitespace),
+            "visible" | "stl" => Ok(Self::Visible),
+            _ => Err(anyhow::anyhow!(
+                "unknown notation: {}. expected whitespace or visible.",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Token {
     Space,
     Tab,
     Lf,
 }
 
-pub fn tokenize(code: &str) -> Result<Vec<Token>> {
-    let code = bleach(code)?;
-    let code = code.as_bytes();
+impl Token {
+    pub fn to_char(self) -> char {
+        match self {
+            Self::Space => ' ',
+            Self::Tab => '\t',
+            Self::Lf => '\n',
+        }
+    }
+
+    /// 見える表記での1文字
+    pub fn to_visible(self) -> char {
+        match self {
+            Self::Space => 'S',
+            Self::Tab => 'T',
+            Self::Lf => 'L',
+        }
+    }
+}
+
+/// 元のソースコード上の位置を付加したトークン
+#[derive(Debug, Clone, Copy)]
+pub struct Spanned {
+    pub token: Token,
+    pub pos: Pos,
+    /// トークンの直後の位置。見える表記の``[Space]``などは複数文字になる
+    pub end: Pos,
+}
+
+/// 本来の表記では空白以外の文字はすべて読み飛ばす
+pub fn tokenize(code: &str, notation: Notation) -> Result<Vec<Spanned>> {
+    if notation == Notation::Visible {
+        let tokens = lex_visible(code)?
+            .into_iter()
+            .filter_map(|(item, span)| match item {
+                Item::Token(token) => Some(Spanned {
+                    token,
+                    pos: span.start,
+                    end: span.end,
+                }),
+                Item::Text(_) => None,
+            })
+            .collect();
+        return Ok(tokens);
+    }
     let mut tokens = vec![];
-    for c in code.iter() {
-        match c {
-            b' ' => tokens.push(Token::Space),
-            b'\t' => tokens.push(Token::Tab),
-            b'\n' => tokens.push(Token::Lf),
-            _ => (),
+    let mut pos = Pos {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+    for c in code.chars() {
+        let token = match c {
+            ' ' => Some(Token::Space),
+            '\t' => Some(Token::Tab),
+            '\n' => Some(Token::Lf),
+            _ => None,
+        };
+        let end = pos.next(c);
+        if let Some(token) = token {
+            tokens.push(Spanned { token, pos, end });
         }
+        pos = end;
     }
     Ok(tokens)
 }
 
-/// 空白以外の文字をすべて除去
-fn bleach(s: &str) -> Result<String> {
-    let regex = Regex::new(r"[^\x20\t\n]")?;
-    let res = regex.replace_all(s, "");
-    Ok(res.to_string())
+/// 見える表記の要素
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Item {
+    Token(Token),
+    /// ``"``で囲んだコメント。エスケープは戻してある
+    Text(String),
+}
+
+/// 見える表記を要素に分ける。``#``や``;``のコメントは捨てる
+pub(crate) fn lex_visible(code: &str) -> Result<Vec<(Item, Span)>> {
+    let mut items = vec![];
+    let mut pos = Pos {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+    let mut chars = code.chars().peekable();
+    let error = |msg: &str, pos: Pos| {
+        anyhow::anyhow!("{} at line {}, column {}.", msg, pos.line, pos.column)
+    };
+    loop {
+        let start = pos;
+        let c = match bump(&mut chars, &mut pos) {
+            Some(c) => c,
+            None => break,
+        };
+        let item = match c {
+            'S' => Item::Token(Token::Space),
+            'T' => Item::Token(Token::Tab),
+            'L' => Item::Token(Token::Lf),
+            ' ' | '\t' | '\r' | '\n' => continue,
+            '#' | ';' => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    bump(&mut chars, &mut pos);
+                }
+                continue;
+            }
+            '[' => {
+                let mut name = String::new();
+                loop {
+                    match bump(&mut chars, &mut pos) {
+                        Some(']') => break,
+                        Some(c) if c.is_ascii_alphabetic() => name.push(c),
+                        _ => return Err(error("unterminated token name", start)),
+                    }
+                }
+                match name.to_ascii_lowercase().as_str() {
+                    "space" => Item::Token(Token::Space),
+                    "tab" => Item::Token(Token::Tab),
+                    "lf" => Item::Token(Token::Lf),
+                    _ => return Err(error(&format!("unknown token [{}]", name), start)),
+                }
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match bump(&mut chars, &mut pos) {
+                        Some('"') => break,
+                        Some('\\') => match bump(&mut chars, &mut pos) {
+                            Some('"') => text.push('"'),
+                            Some('\\') => text.push('\\'),
+                            Some('r') => text.push('\r'),
+                            _ => return Err(error("invalid escape in comment text", start)),
+                        },
+                        Some(' ') | Some('\t') | Some('\n') => {
+                            return Err(error("whitespace in comment text", start))
+                        }
+                        Some(c) => text.push(c),
+                        None => return Err(error("unterminated comment text", start)),
+                    }
+                }
+                Item::Text(text)
+            }
+            c => return Err(error(&format!("unexpected character {:?}", c), start)),
+        };
+        items.push((item, Span { start, end: pos }));
+    }
+    Ok(items)
+}
+
+/// 1文字読んで位置を進める
+fn bump(chars: &mut Peekable<Chars>, pos: &mut Pos) -> Option<char> {
+    let c = chars.next()?;
+    *pos = pos.next(c);
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visible_positions() {
+        let tokens = tokenize("S # c\n [Tab]L", Notation::Visible).unwrap();
+        let actual: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.token, t.pos.line, t.pos.column, t.end.column))
+            .collect();
+        let expect = vec![
+            (Token::Space, 1, 1, 2),
+            (Token::Tab, 2, 2, 7),
+            (Token::Lf, 2, 7, 8),
+        ];
+        assert_eq!(expect, actual);
+    }
+
+    #[test]
+    fn positions() {
+        let tokens = tokenize("a \tb\n\u{3042} ", Notation::Whitespace).unwrap();
+        let actual: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.token, t.pos.offset, t.pos.line, t.pos.column))
+            .collect();
+        let expect = vec![
+            (Token::Space, 1, 1, 2),
+            (Token::Tab, 2, 1, 3),
+            (Token::Lf, 4, 1, 5),
+            (Token::Space, 8, 2, 2),
+        ];
+        assert_eq!(expect, actual);
+    }
 }
@@ -0,0 +1,202 @@
+//! 実行した命令を1行に1つのJSONで書き出す（JSON Lines）
+//!
+//! ```text
+//! {"step":1,"pc":0,"inst":"push 72","depth":1,"stack":[72],"calls":0}
+//! {"step":2,"pc":1,"inst":"outc","depth":0,"stack":[],"calls":0,"io":{"out":"H"}}
+//! ```
+//!
+//! - stack: 実行後のスタック（底から順）。上からの個数を指定した場合はその分だけ
+//! - depth: 実行後のスタックの要素数
+//! - calls: 実行後の呼び出しスタックの深さ
+//! - heap: ヒープに書き込んだ場合のみ``{"address":..,"value":..}``
+//! - io: 入出力した場合のみ``{"out":".."}``、``{"in":".."}``、入力が尽きていれば``{"eof":true}``
+//! - error: 実行時エラーになった場合のみ
+//!
+//! 実行しながら書き出すので、長く動くプログラムでもメモリに溜めない
+
+use std::{
+    fmt,
+    io::{self, BufWriter, Write},
+};
+
+use crate::{linker::Op, number::Number};
+
+pub struct Tracer {
+    writer: BufWriter<Box<dyn Write>>,
+    /// スタックを上から何個書き出すか。Noneなら全部
+    top: Option<usize>,
+    /// 実行中の命令で起きたこと
+    heap: Option<(Number, Number)>,
+    io: Option<Io>,
+}
+
+enum Io {
+    Out(Vec<u8>),
+    In(Vec<u8>),
+    Eof,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer").field("top", &self.top).finish()
+    }
+}
+
+impl Tracer {
+    pub fn new(writer: impl Write + 'static, top: Option<usize>) -> Self {
+        Self {
+            writer: BufWriter::new(Box::new(writer)),
+            top,
+            heap: None,
+            io: None,
+        }
+    }
+
+    pub(crate) fn heap_write(&mut self, address: &Number, value: &Number) {
+        self.heap = Some((address.clone(), value.clone()));
+    }
+
+    pub(crate) fn output(&mut self, buf: &[u8]) {
+        self.io = Some(Io::Out(buf.to_vec()));
+    }
+
+    /// Noneなら入力が尽きている
+    pub(crate) fn input(&mut self, buf: Option<&[u8]>) {
+        self.io = Some(buf.map_or(Io::Eof, |buf| Io::In(buf.to_vec())));
+    }
+
+    /// 1命令分の記録を書き出す
+    pub(crate) fn record(
+        &mut self,
+        step: u64,
+        pc: usize,
+        op: &Op,
+        stack: &[Number],
+        calls: usize,
+        error: Option<&anyhow::Error>,
+    ) -> io::Result<()> {
+        let skip = self.top.map_or(0, |top| stack.len().saturating_sub(top));
+        let items: Vec<String> = stack[skip..].iter().map(|n| n.to_string()).collect();
+        let mut line = format!(
+            "{{\"step\":{},\"pc\":{},\"inst\":{},\"depth\":{},\"stack\":[{}],\"calls\":{}",
+            step,
+            pc,
+            string(&op.to_string()),
+            stack.len(),
+            items.join(","),
+            calls
+        );
+        if let Some((address, value)) = self.heap.take() {
+            line += &format!(",\"heap\":{{\"address\":{},\"value\":{}}}", address, value);
+        }
+        match self.io.take() {
+            Some(Io::Out(buf)) => line += &format!(",\"io\":{{\"out\":{}}}", bytes(&buf)),
+            Some(Io::In(buf)) => line += &format!(",\"io\":{{\"in\":{}}}", bytes(&buf)),
+            Some(Io::Eof) => line += ",\"io\":{\"eof\":true}",
+            None => (),
+        }
+        if let Some(e) = error {
+            line += &format!(",\"error\":{}", string(&e.to_string()));
+        }
+        writeln!(self.writer, "{}}}", line)
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// UTF-8として不正なバイトは置き換える
+fn bytes(buf: &[u8]) -> String {
+    string(&String::from_utf8_lossy(buf))
+}
+
+/// JSONの文字列リテラル
+fn string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    use super::*;
+    use crate::{assembler, vm::VM};
+
+    /// テストから中身を読めるよう共有する出力
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(src: &str, input: &str, top: Option<usize>) -> Vec<String> {
+        let insts = assembler::parse(src).unwrap();
+        let shared = Shared::default();
+        let _ = VM::new(insts, input.as_bytes(), io::sink())
+            .with_trace(Tracer::new(shared.clone(), top))
+            .run();
+        let buf = shared.0.borrow();
+        String::from_utf8(buf.clone())
+            .unwrap()
+            .lines()
+            .map(|l| l.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn records() {
+        let src = "push 1\npush '\\n'\nstore\npush 1\ncall c\nexit\nc:\ninc\nret";
+        let expect = vec![
+            r#"{"step":1,"pc":0,"inst":"push 1","depth":1,"stack":[1],"calls":0}"#,
+            r#"{"step":2,"pc":1,"inst":"push 10","depth":2,"stack":[1,10],"calls":0}"#,
+            r#"{"step":3,"pc":2,"inst":"store","depth":0,"stack":[],"calls":0,"heap":{"address":1,"value":10}}"#,
+            r#"{"step":4,"pc":3,"inst":"push 1","depth":1,"stack":[1],"calls":0}"#,
+            r#"{"step":5,"pc":4,"inst":"call 7","depth":1,"stack":[1],"calls":1}"#,
+            r#"{"step":6,"pc":7,"inst":"inc","depth":0,"stack":[],"calls":1,"heap":{"address":1,"value":34},"io":{"in":"\""}}"#,
+            r#"{"step":7,"pc":8,"inst":"ret","depth":0,"stack":[],"calls":0}"#,
+            r#"{"step":8,"pc":5,"inst":"exit","depth":0,"stack":[],"calls":0}"#,
+        ];
+        assert_eq!(expect, trace(src, "\"", None));
+    }
+
+    #[test]
+    fn top_and_errors() {
+        let lines = trace("push 1\npush 2\npush 3\noutn\ninn", "", Some(2));
+        assert_eq!(
+            vec![
+                r#"{"step":1,"pc":0,"inst":"push 1","depth":1,"stack":[1],"calls":0}"#,
+                r#"{"step":2,"pc":1,"inst":"push 2","depth":2,"stack":[1,2],"calls":0}"#,
+                r#"{"step":3,"pc":2,"inst":"push 3","depth":3,"stack":[2,3],"calls":0}"#,
+                r#"{"step":4,"pc":3,"inst":"outn","depth":2,"stack":[1,2],"calls":0,"io":{"out":"3"}}"#,
+                r#"{"step":5,"pc":4,"inst":"inn","depth":1,"stack":[1],"calls":0,"io":{"eof":true},"error":"reached the end of input."}"#,
+            ],
+            lines
+        );
+    }
+}
@@ -0,0 +1,548 @@
+//! Whitespaceのプログラムを単独で動くCのコードに変換する
+//!
+//! ラベルは``goto``の飛び先にする。``call``は戻り先の番号を呼び出しスタックに積み、
+//! ``ret``はその番号で``switch``して戻り先に飛ぶ
+//! 算術演算、文字の入出力、入力の終端、実行時エラーの扱いは既定の設定のVMと同じ
+//! 拡張命令（``Compiler::with_extensions``）は変換できない
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::{instruction::Instruction, linker};
+
+/// 生成するコードの共通部分
+/// スタックと呼び出しスタックは固定長の配列、ヒープはオープンアドレス法のハッシュ表
+const RUNTIME: &str = r#"#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#ifndef WS_STACK_SIZE
+#define WS_STACK_SIZE (1 << 20)
+#endif
+#ifndef WS_CALL_STACK_SIZE
+#define WS_CALL_STACK_SIZE (1 << 16)
+#endif
+
+static int64_t stack[WS_STACK_SIZE];
+static size_t sp = 0;
+static uint32_t calls[WS_CALL_STACK_SIZE];
+static size_t csp = 0;
+
+typedef struct {
+    int64_t key;
+    int64_t value;
+    int used;
+} cell;
+
+static cell *heap = NULL;
+static size_t heap_cap = 0;
+static size_t heap_len = 0;
+
+static void die(const char *fmt, ...) {
+    va_list args;
+    fflush(stdout);
+    fputs("Error: ", stderr);
+    va_start(args, fmt);
+    vfprintf(stderr, fmt, args);
+    va_end(args);
+    fputc('\n', stderr);
+    exit(1);
+}
+
+static inline void push(int64_t x) {
+    if (sp >= WS_STACK_SIZE) die("stack overflow.");
+    stack[sp++] = x;
+}
+
+static inline int64_t pop(void) {
+    if (sp == 0) die("cannot pop from the empty stack.");
+    return stack[--sp];
+}
+
+static inline void dup(void) {
+    if (sp == 0) die("cannot duplicate the top of the empty stack.");
+    push(stack[sp - 1]);
+}
+
+static inline void copy(int64_t n) {
+    if (n < 0 || (uint64_t)n >= sp) die("cannot copy the %lldth item of the stack.", (long long)n);
+    push(stack[sp - 1 - (size_t)n]);
+}
+
+static inline void swap(void) {
+    int64_t x = pop();
+    int64_t y = pop();
+    push(x);
+    push(y);
+}
+
+static inline void discard(void) {
+    if (sp > 0) sp--;
+}
+
+static inline void slide(int64_t n) {
+    int64_t x = pop();
+    if (n < 0) die("cannot slide a negative number of items: %lld.", (long long)n);
+    if ((uint64_t)n > sp) die("cannot pop from the empty stack.");
+    sp -= (size_t)n;
+    push(x);
+}
+
+static inline void overflow(int64_t l, const char *op, int64_t r) {
+    die("arithmetic overflow: %lld %s %lld.", (long long)l, op, (long long)r);
+}
+
+static inline void add(void) {
+    int64_t r = pop(), l = pop(), x;
+    if (__builtin_add_overflow(l, r, &x)) overflow(l, "+", r);
+    push(x);
+}
+
+static inline void sub(void) {
+    int64_t r = pop(), l = pop(), x;
+    if (__builtin_sub_overflow(l, r, &x)) overflow(l, "-", r);
+    push(x);
+}
+
+static inline void mul(void) {
+    int64_t r = pop(), l = pop(), x;
+    if (__builtin_mul_overflow(l, r, &x)) overflow(l, "*", r);
+    push(x);
+}
+
+/* 0方向に丸める */
+static inline void div_(void) {
+    int64_t r = pop(), l = pop();
+    if (r == 0) die("division by zero.");
+    if (l == INT64_MIN && r == -1) overflow(l, "/", r);
+    push(l / r);
+}
+
+/* 剰余は被除数と同じ符号 */
+static inline void mod(void) {
+    int64_t r = pop(), l = pop();
+    if (r == 0) die("division by zero.");
+    push(r == -1 ? 0 : l % r);
+}
+
+static inline size_t slot(int64_t key) {
+    size_t i = (size_t)(((uint64_t)key * UINT64_C(0x9e3779b97f4a7c15)) >> 32) & (heap_cap - 1);
+    while (heap[i].used && heap[i].key != key) i = (i + 1) & (heap_cap - 1);
+    return i;
+}
+
+static inline void grow(void) {
+    cell *old = heap;
+    size_t old_cap = heap_cap, i;
+    heap_cap = heap_cap ? heap_cap * 2 : 64;
+    heap = calloc(heap_cap, sizeof(cell));
+    if (heap == NULL) die("out of memory.");
+    for (i = 0; i < old_cap; i++) {
+        if (old[i].used) heap[slot(old[i].key)] = old[i];
+    }
+    free(old);
+}
+
+static inline void store(int64_t address, int64_t value) {
+    size_t i;
+    if ((heap_len + 1) * 2 > heap_cap) grow();
+    i = slot(address);
+    if (!heap[i].used) {
+        heap[i].used = 1;
+        heap[i].key = address;
+        heap_len++;
+    }
+    heap[i].value = value;
+}
+
+static inline void heap_write(void) {
+    int64_t value = pop();
+    int64_t address = pop();
+    store(address, value);
+}
+
+static inline void heap_read(void) {
+    int64_t address = pop();
+    if (heap_cap > 0) {
+        size_t i = slot(address);
+        if (heap[i].used) {
+            push(heap[i].value);
+            return;
+        }
+    }
+    die("cannot read an uninitialized heap position.");
+}
+
+static inline void call(uint32_t ret) {
+    if (csp >= WS_CALL_STACK_SIZE) die("call stack overflow.");
+    calls[csp++] = ret;
+}
+
+static inline uint32_t ret(void) {
+    if (csp == 0) die("cannot return from the out of subroutine.");
+    return calls[--csp];
+}
+
+/* コードポイントをUTF-8で書き出す */
+static inline void char_out(void) {
+    int64_t c = pop();
+    if (c < 0 || c > 0x10ffff || (c >= 0xd800 && c <= 0xdfff)) die("invalid character code: %lld.", (long long)c);
+    if (c < 0x80) {
+        putchar((int)c);
+    } else if (c < 0x800) {
+        putchar(0xc0 | (int)(c >> 6));
+        putchar(0x80 | (int)(c & 0x3f));
+    } else if (c < 0x10000) {
+        putchar(0xe0 | (int)(c >> 12));
+        putchar(0x80 | (int)((c >> 6) & 0x3f));
+        putchar(0x80 | (int)(c & 0x3f));
+    } else {
+        putchar(0xf0 | (int)(c >> 18));
+        putchar(0x80 | (int)((c >> 12) & 0x3f));
+        putchar(0x80 | (int)((c >> 6) & 0x3f));
+        putchar(0x80 | (int)(c & 0x3f));
+    }
+}
+
+static inline void num_out(void) {
+    printf("%lld", (long long)pop());
+}
+
+/* UTF-8の1文字を読んでコードポイントを書き込む */
+/* 入力を促す出力が見えるよう、読み込む前に書き出しておく */
+static inline void char_in(void) {
+    static const int32_t min[] = {0, 0, 0x80, 0x800, 0x10000};
+    int64_t address = pop();
+    int c, len, i, n = 1;
+    int32_t cp;
+    fflush(stdout);
+    c = getchar();
+    if (c == EOF) die("reached the end of input.");
+    if (c < 0x80) {
+        store(address, c);
+        return;
+    }
+    len = c >= 0xc2 && c <= 0xdf ? 2 : c >= 0xe0 && c <= 0xef ? 3 : c >= 0xf0 && c <= 0xf4 ? 4 : 1;
+    cp = len == 2 ? c & 0x1f : len == 3 ? c & 0x0f : c & 0x07;
+    /* 続きのバイトでなければ読まずに残し、次の文字にする */
+    for (i = 1; i < len; i++, n++) {
+        int d = getchar();
+        if (d == EOF) break;
+        if ((d & 0xc0) != 0x80) {
+            ungetc(d, stdin);
+            break;
+        }
+        cp = (cp << 6) | (d & 0x3f);
+    }
+    if (len == 1 || n != len || cp < min[len] || cp > 0x10ffff || (cp >= 0xd800 && cp <= 0xdfff)) die("invalid UTF-8 input.");
+    store(address, cp);
+}
+
+static inline int is_space(int c) {
+    return c == ' ' || c == '\t' || c == '\n' || c == '\v' || c == '\f' || c == '\r';
+}
+
+/* 1行読んで前後の空白を除き、符号付きの10進数として解釈する */
+static inline void num_in(void) {
+    int64_t address = pop();
+    char *buf = NULL;
+    size_t len = 0, cap = 0, start, end, i;
+    int c, negative = 0;
+    uint64_t n = 0, max;
+    fflush(stdout);
+    while ((c = getchar()) != EOF) {
+        if (len + 1 >= cap) {
+            cap = cap ? cap * 2 : 64;
+            buf = realloc(buf, cap);
+            if (buf == NULL) die("out of memory.");
+        }
+        buf[len++] = (char)c;
+        if (c == '\n') break;
+    }
+    if (len == 0) die("reached the end of input.");
+    buf[len] = '\0';
+    for (start = 0; start < len && is_space((unsigned char)buf[start]); start++);
+    for (end = len; end > start && is_space((unsigned char)buf[end - 1]); end--);
+    buf[end] = '\0';
+    i = start;
+    if (i < end && (buf[i] == '+' || buf[i] == '-')) negative = buf[i++] == '-';
+    max = negative ? (uint64_t)INT64_MAX + 1 : (uint64_t)INT64_MAX;
+    if (i == end) die("invalid number input: \"%s\"", buf + start);
+    for (; i < end; i++) {
+        int d = buf[i] - '0';
+        if (d < 0 || d > 9 || n > (max - (uint64_t)d) / 10) die("invalid number input: \"%s\"", buf + start);
+        n = n * 10 + (uint64_t)d;
+    }
+    free(buf);
+    store(address, negative ? (int64_t)(0 - n) : (int64_t)n);
+}
+"#;
+
+/// Cのコードを生成する
+/// 数値はi64に収まらなければならない
+pub fn translate(insts: &[Instruction]) -> Result<String> {
+    let program = linker::link(insts);
+    // ラベル名がだぶった場合は先に定義したほうだけを飛び先にする
+    // 使われないラベルはCの警告になるので出力しない
+    let targets: HashSet<usize> = insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Instruction::Call(l)
+            | Instruction::Jump(l)
+            | Instruction::JumpZero(l)
+            | Instruction::JumpNeg(l) => program.labels.get(l).copied(),
+            _ => None,
+        })
+        .collect();
+    let goto = |label: &str| match program.labels.get(label) {
+        Some(i) => format!("goto L{};", i),
+        None => format!("die(\"label is not found. label name: {}\");", label),
+    };
+
+    let mut body = String::new();
+    let mut returns = 0;
+    let mut has_ret = false;
+    for (i, inst) in insts.iter().enumerate() {
+        let stmt = match inst {
+            Instruction::Push(n) => {
+                let n = n.to_i64().ok_or_else(|| {
+                    anyhow::anyhow!("cannot translate a number beyond i64 into C: {}", n)
+                })?;
+                format!("push({});", literal(n))
+            }
+            Instruction::Dup => "dup();".to_owned(),
+            Instruction::Copy(n) => format!("copy({});", literal(*n)),
+            Instruction::Swap => "swap();".to_owned(),
+            Instruction::Discard => "discard();".to_owned(),
+            Instruction::Slide(n) => format!("slide({});", literal(*n)),
+            Instruction::Add => "add();".to_owned(),
+            Instruction::Sub => "sub();".to_owned(),
+            Instruction::Mul => "mul();".to_owned(),
+            Instruction::Div => "div_();".to_owned(),
+            Instruction::Mod => "mod();".to_owned(),
+            Instruction::HeapWrite => "heap_write();".to_owned(),
+            Instruction::HeapRead => "heap_read();".to_owned(),
+            Instruction::Label(_) if targets.contains(&i) => format!("L{}:;", i),
+            Instruction::Label(_) => ";".to_owned(),
+            Instruction::Call(l) => {
+                returns += 1;
+                format!("call({}); {} R{}:;", returns - 1, goto(l), returns - 1)
+            }
+            Instruction::Jump(l) => goto(l),
+            Instruction::JumpZero(l) => format!("if (pop() == 0) {}", goto(l)),
+            Instruction::JumpNeg(l) => format!("if (pop() < 0) {}", goto(l)),
+            Instruction::Return => {
+                has_ret = true;
+                "goto RET;".to_owned()
+            }
+            Instruction::Exit => "return 0;".to_owned(),
+            Instruction::CharOut => "char_out();".to_owned(),
+            Instruction::NumOut => "num_out();".to_owned(),
+            Instruction::CharIn => "char_in();".to_owned(),
+            Instruction::NumIn => "num_in();".to_owned(),
+            Instruction::DumpStack | Instruction::DumpHeap | Instruction::HostCall(_) => {
+                return Err(anyhow::anyhow!(
+                    "cannot translate the extension instruction into C: {}",
+                    inst
+                ))
+            }
+        };
+        body.push_str(&format!("    {} /* {} */\n", stmt, inst));
+    }
+    body.push_str("    die(\"exit command must be done in the last of Whitespace program.\");\n");
+    if has_ret {
+        body.push_str("RET:\n    switch (ret()) {\n");
+        for i in 0..returns {
+            body.push_str(&format!("    case {}: goto R{};\n", i, i));
+        }
+        body.push_str("    }\n");
+    }
+    body.push_str("    return 1;\n");
+
+    Ok(format!("{}\nint main(void) {{\n{}}}\n", RUNTIME, body))
+}
+
+/// i64::MINはそのまま書くと符号なしの定数に負号をつけたことになるので分けて書く
+fn literal(n: i64) -> String {
+    if n == i64::MIN {
+        "INT64_MIN".to_owned()
+    } else {
+        format!("INT64_C({})", n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::{Read, Write},
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+    use crate::{
+        assembler,
+        compiler::Compiler,
+        limits::{LimitExceeded, Limits},
+        vm::VM,
+    };
+
+    /// 変換したコードをccでコンパイルし、実行ファイルのパスを返す
+    fn build_c(insts: &[Instruction], name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("whitespace-rs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join(format!("{}.c", name));
+        let exe: PathBuf = dir.join(name);
+        fs::write(&src, translate(insts).unwrap()).unwrap();
+        let status = Command::new("cc")
+            .args(["-O1", "-Wall", "-Werror", "-o"])
+            .arg(&exe)
+            .arg(&src)
+            .status()
+            .expect("cc is required");
+        assert!(status.success(), "failed to compile {}", src.display());
+        fs::remove_file(&src).unwrap();
+        exe
+    }
+
+    /// 変換したコードをccでコンパイルして実行し、標準出力と成否を返す
+    fn run_c(insts: &[Instruction], name: &str, input: &str) -> (String, bool) {
+        let exe = build_c(insts, name);
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        fs::remove_file(&exe).unwrap();
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            output.status.success(),
+        )
+    }
+
+    /// 終わらないプログラム用。標準出力の先頭limitバイトを読んだら止める
+    fn run_c_bounded(insts: &[Instruction], name: &str, limit: usize) -> String {
+        let exe = build_c(insts, name);
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut buf = vec![0; limit];
+        let res = child.stdout.take().unwrap().read_exact(&mut buf);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        fs::remove_file(&exe).unwrap();
+        res.unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn run_vm(insts: &[Instruction], input: &str) -> (String, bool) {
+        let mut output = vec![];
+        let ok = VM::new(insts.to_vec(), input.as_bytes(), &mut output)
+            .run()
+            .is_ok();
+        (String::from_utf8(output).unwrap(), ok)
+    }
+
+    /// 出力の上限に達するまで実行し、そこまでの出力を返す
+    fn run_vm_bounded(insts: &[Instruction], limit: usize) -> String {
+        let mut output = vec![];
+        let limits = Limits {
+            output: Some(limit as u64),
+            ..Limits::default()
+        };
+        let err = VM::new(insts.to_vec(), "".as_bytes(), &mut output)
+            .with_limits(limits)
+            .run()
+            .unwrap_err();
+        assert!(err.downcast_ref::<LimitExceeded>().is_some(), "{}", err);
+        String::from_utf8(output).unwrap()
+    }
+
+    fn load(path: &Path) -> Vec<Instruction> {
+        let src = fs::read_to_string(path).unwrap();
+        if path.extension().unwrap() == "wsa" {
+            return assembler::parse(&src).unwrap();
+        }
+        Compiler::new(src).compile().unwrap()
+    }
+
+    #[test]
+    fn examples() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let inputs = [
+            ("hello.ws", ""),
+            ("fib.ws", "10\n"),
+            ("fact.ws", "5\n"),
+            ("count.wsa", ""),
+        ];
+        for (name, input) in inputs.iter() {
+            let insts = load(&dir.join(name));
+            let c = run_c(&insts, &name.replace('.', "_"), input);
+            assert_eq!(run_vm(&insts, input), c, "{}", name);
+        }
+
+        // 終わらないので、先頭の出力だけを比べる
+        let insts = load(&dir.join("forever_a.ws"));
+        let c = run_c_bounded(&insts, "forever_a_ws", 4096);
+        assert_eq!(run_vm_bounded(&insts, 4096), c);
+    }
+
+    #[test]
+    fn runtime_semantics() {
+        let cases = [
+            // 0方向に丸める除算と剰余
+            (
+                "push -7\npush 2\ndiv\noutn\npush -7\npush 2\nmod\noutn\nexit",
+                "",
+            ),
+            (
+                "push 0\ninn\npush 0\nload\noutn\npush 1\ninc\npush 1\nload\noutc\nexit",
+                "  -42 \nx",
+            ),
+            ("push 0\ninn\nexit", "12a\n"),
+            ("push 0\ninc\nexit", ""),
+            ("push 5\nload\nexit", ""),
+            ("call sub\npush 2\noutn\nexit\nsub:\npush 1\noutn\nret", ""),
+            ("ret", ""),
+            ("push 1\njz nowhere\npush 0\njz nowhere\nexit", ""),
+            ("push 1\npush 2\npush 3\nslide 1\noutn\noutn\ncopy 0", ""),
+            ("push 1\npush 2\nslide -1", ""),
+            ("push 1\noutn", ""),
+            // UTF-8の1文字ずつ読み書きする
+            (
+                "push 0\ninc\npush 0\nload\ndup\noutn\noutc\npush 0\ninc\npush 0\nload\noutc\nexit",
+                "あé",
+            ),
+            ("push 55296\noutc\nexit", ""),
+        ];
+        for (i, (src, input)) in cases.iter().enumerate() {
+            let insts = assembler::parse(src).unwrap();
+            let c = run_c(&insts, &format!("case{}", i), input);
+            assert_eq!(run_vm(&insts, input), c, "{}", src);
+        }
+    }
+
+    /// 多倍長整数のVMは桁あふれしないので比べられない
+    #[cfg(not(feature = "bignum"))]
+    #[test]
+    fn overflow() {
+        // 桁あふれはそこまでの出力を残してエラー
+        let src = "push 1\noutn\npush 9223372036854775807\npush 1\nadd\nexit";
+        let insts = assembler::parse(src).unwrap();
+        assert_eq!(run_vm(&insts, ""), run_c(&insts, "overflow", ""));
+    }
+}
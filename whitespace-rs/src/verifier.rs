@@ -0,0 +1,666 @@
+//! 実行せずに命令列を検査する
+//!
+//! ラベル・分岐・サブルーチン呼び出しから制御フローグラフを作り、
+//! 各命令の直前のスタックの深さの範囲を求めて次の問題を報告する
+//!
+//! - スタックが足りなくなる可能性のある命令
+//! - 未定義のラベル、重複したラベル（実行時は最初の定義が使われる）
+//! - 到達できない命令
+//! - Exitせずにプログラムの末尾に達する経路
+//! - サブルーチンの外でのReturn
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+};
+
+use crate::instruction::Instruction;
+
+/// スタックの深さの範囲
+/// minがNoneなら下限不明（ホスト関数の呼び出しの後など）
+/// maxがNoneなら上限なし（ループで積み続ける場合など）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Depth {
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+impl Depth {
+    fn exact(n: usize) -> Self {
+        Self {
+            min: Some(n),
+            max: Some(n),
+        }
+    }
+
+    fn join(self, other: Self) -> Self {
+        let both = |a: Option<usize>, b: Option<usize>, f: fn(usize, usize) -> usize| {
+            a.and_then(|a| b.map(|b| f(a, b)))
+        };
+        Self {
+            min: both(self.min, other.min, usize::min),
+            max: both(self.max, other.max, usize::max),
+        }
+    }
+}
+
+impl fmt::Display for Depth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if max == min => write!(f, "{}", max),
+            (Some(min), Some(max)) => write!(f, "{}..{}", min, max),
+            (Some(min), None) => write!(f, "{}..", min),
+            (None, Some(max)) => write!(f, "?..{}", max),
+            (None, None) => write!(f, "?"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    /// 実行時にneed個必要だが、min個しかない可能性がある
+    Underflow {
+        need: usize,
+        min: usize,
+    },
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    Unreachable,
+    FallOffEnd,
+    ReturnOutsideCall,
+}
+
+/// 問題のあった命令の添字と内容
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub index: usize,
+    pub kind: Kind,
+}
+
+impl Finding {
+    /// 到達できない命令は実行に影響しないので警告にとどめる
+    pub fn is_error(&self) -> bool {
+        self.kind != Kind::Unreachable
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            Kind::Underflow { need, min } => write!(
+                f,
+                "the stack may underflow: {} item(s) needed, but only {} may be on the stack.",
+                need, min
+            ),
+            Kind::UndefinedLabel(label) => {
+                write!(f, "label is not found. label name: {}", label)
+            }
+            Kind::DuplicateLabel(label) => write!(
+                f,
+                "label is duplicate and the first definition is used. label name: {}",
+                label
+            ),
+            Kind::Unreachable => write!(f, "unreachable code."),
+            Kind::FallOffEnd => write!(f, "the program may reach the end without exit."),
+            Kind::ReturnOutsideCall => write!(f, "return may be executed outside a subroutine."),
+        }
+    }
+}
+
+/// 検査の結果
+#[derive(Debug)]
+pub struct Analysis {
+    /// 各命令の直前のスタックの深さ。到達できない命令はNone
+    pub depths: Vec<Option<Depth>>,
+    /// 命令の順に並ぶ
+    pub findings: Vec<Finding>,
+}
+
+pub fn verify(insts: &[Instruction]) -> Analysis {
+    Verifier::new(insts).run()
+}
+
+/// (取り出す数, 積む数)
+fn stack_effect(inst: &Instruction) -> (usize, usize) {
+    let index = |n: i64| n.max(0) as usize;
+    match inst {
+        Instruction::Push(_) => (0, 1),
+        Instruction::Dup => (1, 2),
+        Instruction::Copy(n) => (index(*n) + 1, index(*n) + 2),
+        Instruction::Swap => (2, 2),
+        Instruction::Discard => (1, 0),
+        Instruction::Slide(n) => (index(*n) + 1, 1),
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Mod => (2, 1),
+        Instruction::HeapWrite => (2, 0),
+        Instruction::HeapRead => (1, 1),
+        Instruction::JumpZero(_) | Instruction::JumpNeg(_) => (1, 0),
+        Instruction::CharOut | Instruction::NumOut => (1, 0),
+        Instruction::CharIn | Instruction::NumIn => (1, 0),
+        Instruction::Label(_)
+        | Instruction::Call(_)
+        | Instruction::Jump(_)
+        | Instruction::Return
+        | Instruction::Exit
+        | Instruction::DumpStack
+        | Instruction::DumpHeap => (0, 0),
+        // 取り出す数と積む数はVMに登録した関数で決まる。呼んだ後の深さは分からない（analyze_local）
+        Instruction::HostCall(_) => (0, 0),
+    }
+}
+
+/// サブルーチンの入口からの相対的な深さの範囲
+/// Noneの側は上限・下限なし
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rel {
+    lo: Option<i64>,
+    hi: Option<i64>,
+}
+
+impl Rel {
+    const ZERO: Self = Self {
+        lo: Some(0),
+        hi: Some(0),
+    };
+    const UNKNOWN: Self = Self { lo: None, hi: None };
+
+    fn join(self, other: Self) -> Self {
+        Self {
+            lo: self.lo.and_then(|a| other.lo.map(|b| a.min(b))),
+            hi: self.hi.and_then(|a| other.hi.map(|b| a.max(b))),
+        }
+    }
+
+    /// oldより広がった側を上限・下限なしにする
+    /// ループや再帰で深さが伸び続けても解析が止まるようにする
+    fn widen(self, old: Self) -> Self {
+        let new = old.join(self);
+        Self {
+            lo: if new.lo == old.lo { new.lo } else { None },
+            hi: if new.hi == old.hi { new.hi } else { None },
+        }
+    }
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            lo: self.lo.and_then(|a| other.lo.map(|b| a + b)),
+            hi: self.hi.and_then(|a| other.hi.map(|b| a + b)),
+        }
+    }
+
+    fn apply(self, need: usize, push: usize) -> Self {
+        let d = push as i64 - need as i64;
+        self.add(Self {
+            lo: Some(d),
+            hi: Some(d),
+        })
+    }
+
+    /// 入口の深さがentryのときの深さ
+    fn absolute(self, entry: Depth) -> Depth {
+        let clamp = |n: i64| n.max(0) as usize;
+        Depth {
+            min: entry
+                .min
+                .and_then(|min| self.lo.map(|lo| clamp(min as i64 + lo))),
+            max: entry
+                .max
+                .and_then(|max| self.hi.map(|hi| clamp(max as i64 + hi))),
+        }
+    }
+}
+
+fn widen(old: Option<Rel>, new: Rel) -> Rel {
+    match old {
+        Some(old) => new.widen(old),
+        None => new,
+    }
+}
+
+/// 1つのサブルーチン（または先頭からのメインの流れ）の解析結果
+/// Callの先には入らず、呼び出し先の戻り値の深さの変化だけを使う
+#[derive(Debug, Clone)]
+struct Local {
+    /// 各命令の直前の、入口からの相対的な深さ
+    rels: Vec<Option<Rel>>,
+    /// Returnしたときの深さの変化。戻らないならNone
+    ret: Option<Rel>,
+    /// 到達できるReturn
+    returns: Vec<usize>,
+    /// 末尾に落ちる経路があるか
+    falls_off: bool,
+}
+
+struct Verifier<'a> {
+    insts: &'a [Instruction],
+    /// K: label, V: 最初に定義された位置
+    labels: HashMap<&'a str, usize>,
+    /// 先頭と、Callされるラベルの位置
+    entries: BTreeSet<usize>,
+    /// K: 入口, V: その解析結果
+    locals: HashMap<usize, Local>,
+    findings: BTreeSet<(usize, Kind)>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(insts: &'a [Instruction]) -> Self {
+        Self {
+            insts,
+            labels: HashMap::new(),
+            entries: BTreeSet::new(),
+            locals: HashMap::new(),
+            findings: BTreeSet::new(),
+        }
+    }
+
+    fn run(mut self) -> Analysis {
+        self.find_labels();
+        if self.insts.is_empty() {
+            return Analysis {
+                depths: vec![],
+                findings: self.into_findings(),
+            };
+        }
+        self.summarize();
+        let entry_depths = self.entry_depths();
+        let depths = self.depths(&entry_depths);
+
+        self.check_underflow(&depths);
+        self.check_exits(&entry_depths);
+        self.check_unreachable(&depths);
+
+        Analysis {
+            depths,
+            findings: self.into_findings(),
+        }
+    }
+
+    fn into_findings(self) -> Vec<Finding> {
+        self.findings
+            .into_iter()
+            .map(|(index, kind)| Finding { index, kind })
+            .collect()
+    }
+
+    fn find_labels(&mut self) {
+        for (i, inst) in self.insts.iter().enumerate() {
+            if let Instruction::Label(label) = inst {
+                if self.labels.contains_key(label.as_str()) {
+                    self.report(i, Kind::DuplicateLabel(label.clone()));
+                } else {
+                    self.labels.insert(label, i);
+                }
+            }
+        }
+        self.entries.insert(0);
+        for (i, inst) in self.insts.iter().enumerate() {
+            if let Some(label) = Self::target_label(inst) {
+                match self.labels.get(label) {
+                    Some(t) => {
+                        if let Instruction::Call(_) = inst {
+                            self.entries.insert(*t);
+                        }
+                    }
+                    None => self.report(i, Kind::UndefinedLabel(label.to_owned())),
+                }
+            }
+        }
+    }
+
+    fn target_label(inst: &Instruction) -> Option<&str> {
+        match inst {
+            Instruction::Call(l)
+            | Instruction::Jump(l)
+            | Instruction::JumpZero(l)
+            | Instruction::JumpNeg(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    fn target(&self, inst: &Instruction) -> Option<usize> {
+        Self::target_label(inst).and_then(|l| self.labels.get(l).copied())
+    }
+
+    fn report(&mut self, index: usize, kind: Kind) {
+        self.findings.insert((index, kind));
+    }
+
+    /// 各サブルーチンの深さの変化を、再帰呼び出しも含めて変わらなくなるまで求める
+    fn summarize(&mut self) {
+        let entries: Vec<usize> = self.entries.iter().copied().collect();
+        loop {
+            let mut changed = false;
+            for e in entries.iter() {
+                let mut local = self.analyze_local(*e);
+                let old = self.locals.get(e).and_then(|l| l.ret);
+                local.ret = local.ret.map(|ret| widen(old, ret));
+                if local.ret != old || !self.locals.contains_key(e) {
+                    changed = true;
+                }
+                self.locals.insert(*e, local);
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// 入口から、呼び出し先の中には入らずに深さを求める
+    fn analyze_local(&self, entry: usize) -> Local {
+        let len = self.insts.len();
+        let mut local = Local {
+            rels: vec![None; len],
+            ret: None,
+            returns: vec![],
+            falls_off: false,
+        };
+        let mut queue = VecDeque::new();
+        local.rels[entry] = Some(Rel::ZERO);
+        queue.push_back(entry);
+
+        while let Some(i) = queue.pop_front() {
+            let rel = local.rels[i].expect("queued instructions have a depth");
+            let inst = &self.insts[i];
+            let (need, push) = stack_effect(inst);
+            let next = rel.apply(need, push);
+            let target = self.target(inst);
+
+            let mut succs: Vec<(usize, Rel)> = vec![];
+            match inst {
+                Instruction::Jump(_) => succs.extend(target.map(|t| (t, next))),
+                Instruction::JumpZero(_) | Instruction::JumpNeg(_) => {
+                    succs.extend(target.map(|t| (t, next)));
+                    succs.push((i + 1, next));
+                }
+                Instruction::Call(_) => {
+                    let ret = target.and_then(|t| self.locals.get(&t).and_then(|l| l.ret));
+                    if let Some(ret) = ret {
+                        succs.push((i + 1, next.add(ret)));
+                    }
+                }
+                Instruction::Return => {
+                    local.ret = Some(match local.ret {
+                        Some(r) => r.join(next),
+                        None => next,
+                    });
+                    if !local.returns.contains(&i) {
+                        local.returns.push(i);
+                    }
+                }
+                Instruction::Exit => (),
+                Instruction::HostCall(_) => succs.push((i + 1, Rel::UNKNOWN)),
+                _ => succs.push((i + 1, next)),
+            }
+
+            for (j, rel) in succs {
+                if j >= len {
+                    local.falls_off = true;
+                    continue;
+                }
+                let new = widen(local.rels[j], rel);
+                if local.rels[j] != Some(new) {
+                    local.rels[j] = Some(new);
+                    queue.push_back(j);
+                }
+            }
+        }
+        local
+    }
+
+    /// 各サブルーチンの入口での絶対的な深さ
+    /// 呼び出し元が複数あれば合流させる
+    fn entry_depths(&self) -> HashMap<usize, Depth> {
+        let mut depths: HashMap<usize, Depth> = HashMap::new();
+        depths.insert(0, Depth::exact(0));
+        let mut queue = VecDeque::new();
+        queue.push_back(0);
+
+        while let Some(e) = queue.pop_front() {
+            let entry = depths[&e];
+            let local = &self.locals[&e];
+            for (i, inst) in self.insts.iter().enumerate() {
+                let (callee, rel) = match (inst, self.target(inst), local.rels[i]) {
+                    (Instruction::Call(_), Some(callee), Some(rel)) => (callee, rel),
+                    _ => continue,
+                };
+                let depth = rel.absolute(entry);
+                let new = match depths.get(&callee) {
+                    None => depth,
+                    Some(old) => {
+                        let mut new = old.join(depth);
+                        // 再帰で積み続けても止まるよう、上限が伸びたら上限なしにする
+                        if new.max != old.max {
+                            new.max = None;
+                        }
+                        new
+                    }
+                };
+                if depths.get(&callee) != Some(&new) {
+                    depths.insert(callee, new);
+                    queue.push_back(callee);
+                }
+            }
+        }
+        depths
+    }
+
+    /// 到達できるすべての入口について、各命令の直前の深さを合流させる
+    fn depths(&self, entry_depths: &HashMap<usize, Depth>) -> Vec<Option<Depth>> {
+        let mut depths: Vec<Option<Depth>> = vec![None; self.insts.len()];
+        for (e, entry) in entry_depths.iter() {
+            for (i, rel) in self.locals[e].rels.iter().enumerate() {
+                if let Some(rel) = rel {
+                    let depth = rel.absolute(*entry);
+                    depths[i] = Some(match depths[i] {
+                        Some(d) => d.join(depth),
+                        None => depth,
+                    });
+                }
+            }
+        }
+        depths
+    }
+
+    fn check_underflow(&mut self, depths: &[Option<Depth>]) {
+        for (i, depth) in depths.iter().enumerate() {
+            // 下限が分からなければ足りるかどうかも分からないので報告しない
+            if let Some(min) = depth.and_then(|d| d.min) {
+                let (need, _) = stack_effect(&self.insts[i]);
+                if min < need {
+                    self.report(i, Kind::Underflow { need, min });
+                }
+            }
+        }
+    }
+
+    /// 末尾に落ちる経路と、メインの流れから到達できるReturn
+    fn check_exits(&mut self, entry_depths: &HashMap<usize, Depth>) {
+        let last = self.insts.len() - 1;
+        let mut entries: Vec<usize> = entry_depths.keys().copied().collect();
+        entries.sort_unstable();
+        for e in entries {
+            let local = &self.locals[&e];
+            let falls_off = local.falls_off;
+            let returns = if e == 0 {
+                local.returns.clone()
+            } else {
+                vec![]
+            };
+            if falls_off {
+                self.report(last, Kind::FallOffEnd);
+            }
+            for r in returns {
+                self.report(r, Kind::ReturnOutsideCall);
+            }
+        }
+    }
+
+    /// 到達できない命令が続く範囲の先頭を報告する
+    /// ラベルは実行に影響しないので数えない
+    fn check_unreachable(&mut self, depths: &[Option<Depth>]) {
+        let mut in_dead = false;
+        for (i, depth) in depths.iter().enumerate() {
+            if depth.is_some() {
+                in_dead = false;
+            } else if !in_dead && !matches!(self.insts[i], Instruction::Label(_)) {
+                in_dead = true;
+                self.report(i, Kind::Unreachable);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+
+    fn check(src: &str) -> Vec<(usize, Kind)> {
+        let insts = assembler::parse(src).unwrap();
+        verify(&insts)
+            .findings
+            .into_iter()
+            .map(|f| (f.index, f.kind))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        let src = "
+            push 1
+            push 2
+            call add
+            outn
+            exit
+        add:
+            add
+            ret
+        ";
+        let insts = assembler::parse(src).unwrap();
+        let analysis = verify(&insts);
+        assert!(analysis.findings.is_empty());
+        // 戻ってきた後は1つ
+        assert_eq!(Some(Depth::exact(1)), analysis.depths[3]);
+        assert_eq!(Some(Depth::exact(2)), analysis.depths[6]);
+    }
+
+    #[test]
+    fn underflow_on_a_branch() {
+        let src = "
+            push 0
+            jz skip
+            push 1
+        skip:
+            push 2
+            add
+            outn
+            exit
+        ";
+        // addで止まらなかった場合の続きも報告される
+        let expect = vec![
+            (5, Kind::Underflow { need: 2, min: 1 }),
+            (6, Kind::Underflow { need: 1, min: 0 }),
+        ];
+        assert_eq!(expect, check(src));
+    }
+
+    #[test]
+    fn calls_from_different_depths() {
+        // 呼び出し元ごとの深さで戻り先を調べる
+        let src = "
+            push 1
+            call drop
+            push 2
+            push 3
+            call drop
+            outn
+            push 5
+            call fact
+            outn
+            exit
+        drop:
+            discard
+            ret
+        fact:
+            dup
+            jz base
+            dup
+            push 1
+            sub
+            call fact
+            mul
+            ret
+        base:
+            discard
+            push 1
+            ret
+        ";
+        assert!(check(src).is_empty());
+    }
+
+    #[test]
+    fn loops_terminate() {
+        let src = "
+        loop:
+            push 1
+            jmp loop
+        ";
+        let insts = assembler::parse(src).unwrap();
+        let analysis = verify(&insts);
+        assert!(analysis.findings.is_empty());
+        let depth = Depth {
+            min: Some(0),
+            max: None,
+        };
+        assert_eq!(Some(depth), analysis.depths[1]);
+    }
+
+    #[test]
+    fn labels_and_control_flow() {
+        let src = "
+            push 0
+            jz nowhere
+            jmp a
+            push 1
+        a:
+        a:
+            ret
+        ";
+        let expect = vec![
+            (1, Kind::UndefinedLabel("s".to_owned())),
+            (3, Kind::Unreachable),
+            (5, Kind::DuplicateLabel("t".to_owned())),
+            (6, Kind::ReturnOutsideCall),
+        ];
+        assert_eq!(expect, check(src));
+    }
+
+    #[test]
+    fn after_host_call() {
+        // ホスト関数が何個積むかは分からないので、続くoutnは報告しない
+        let src = "
+            push 3
+            push 4
+            host 1
+            outn
+            exit
+        ";
+        let insts = assembler::parse(src).unwrap();
+        let analysis = verify(&insts);
+        assert!(analysis.findings.is_empty());
+        let depth = Depth {
+            min: None,
+            max: None,
+        };
+        assert_eq!(Some(depth), analysis.depths[3]);
+    }
+
+    #[test]
+    fn fall_off_end() {
+        let expect = vec![(1, Kind::FallOffEnd)];
+        assert_eq!(expect, check("push 1\noutn\n"));
+    }
+}
@@ -1,206 +1,1329 @@
-use std::{collections::HashMap, io, io::BufWriter, io::Write};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    io::{self, BufRead, BufWriter, Read, Write},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use anyhow::{self, Context, Result};
 
-use crate::instruction::Instruction;
+use crate::{
+    arith::{Arith, BinOp},
+    bytecode,
+    history::{self, History, Record},
+    instruction::Instruction,
+    limits::{LimitExceeded, Limits},
+    linker::{self, Op, Program},
+    number::Number,
+    optimizer,
+    profile::Profile,
+    snapshot::Snapshot,
+    source::{Diagnostic, Span},
+    token::Notation,
+    trace::Tracer,
+};
+
+pub use runtime_rs::io::{Encoding, Eof};
+
+/// 1命令実行した後の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Exited,
+}
+
+/// 拡張命令でスタックやヒープを書き出す先
+struct Dump(Box<dyn Write>);
+
+impl fmt::Debug for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Dump")
+    }
+}
+
+/// 拡張命令（``host n``）で呼ぶホスト関数
+/// 引数は積んだ順（末尾がスタックの先頭）に渡し、返した値を順に積む
+type HostFn = Box<dyn FnMut(&[Number]) -> Result<Vec<Number>>>;
+
+struct Host {
+    arity: usize,
+    f: HostFn,
+}
+
+impl fmt::Debug for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Host").field("arity", &self.arity).finish()
+    }
+}
 
 #[derive(Debug)]
-pub struct VM {
-    insts: Vec<Instruction>,
-    stack: Vec<i64>,
+pub struct VM<R: BufRead, W: Write> {
+    pub(crate) program: Rc<Program>,
+    pub(crate) stack: Vec<Number>,
     /// K: address, V: value
-    heap: HashMap<u64, i64>,
-    /// K: label, V: position
-    labels: HashMap<String, u64>,
+    heap: HashMap<Number, Number>,
     /// 末尾はサブルーチンの戻り先
     call_stack: Vec<usize>,
+    pub(crate) pc: usize,
+    pub(crate) exited: bool,
+    /// 実行時エラーの表示に使うソースコードとその表記、各命令に対応する範囲
+    source: Option<(String, Notation, Vec<Span>)>,
+    eof: Eof,
+    encoding: Encoding,
+    pub(crate) arith: Arith,
+    pub(crate) limits: Limits,
+    /// 実行した命令数
+    steps: u64,
+    /// 最初の命令を実行した時刻
+    started: Option<Instant>,
+    /// 出力したバイト数
+    written: u64,
+    reader: Input<R>,
+    pub(crate) writer: BufWriter<W>,
+    trace: Option<Tracer>,
+    profile: Option<Profile>,
+    /// 命令列のハッシュ。スナップショットが同じプログラムのものか調べる
+    fingerprint: u64,
+    /// 複合命令にまとめて実行するか。最適化した命令列とみなし、スナップショットに記録する
+    fused: bool,
+    /// 立っていれば次の命令の前で止まる
+    interrupt: Option<Arc<AtomicBool>>,
+    /// この命令数まで実行したら止まる
+    pause_at: Option<u64>,
+    history: Option<History>,
+    /// 拡張命令を使えるときのみSome
+    dump: Option<Dump>,
+    /// K: 番号
+    hosts: HashMap<i64, Host>,
 }
 
-impl VM {
-    pub fn new(insts: Vec<Instruction>) -> Self {
-        let labels = Self::find_labels(&insts);
+impl<R: BufRead, W: Write> VM<R, W> {
+    pub fn new(insts: Vec<Instruction>, input: R, output: W) -> Self {
+        let program = linker::link(&insts);
+        let fingerprint = bytecode::fingerprint(&insts);
         Self {
-            insts: insts,
+            program: Rc::new(program),
             stack: Vec::new(),
             heap: HashMap::new(),
-            labels: labels,
             call_stack: vec![],
+            pc: 0,
+            exited: false,
+            source: None,
+            eof: Eof::default(),
+            encoding: Encoding::default(),
+            arith: Arith::default(),
+            limits: Limits::default(),
+            steps: 0,
+            started: None,
+            written: 0,
+            reader: Input::new(input),
+            writer: BufWriter::new(output),
+            trace: None,
+            profile: None,
+            fingerprint,
+            fused: false,
+            interrupt: None,
+            pause_at: None,
+            history: None,
+            dump: None,
+            hosts: HashMap::new(),
         }
     }
 
+    pub fn with_source(mut self, src_code: String, notation: Notation, spans: Vec<Span>) -> Self {
+        self.source = Some((src_code, notation, spans));
+        self
+    }
+
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_arith(mut self, arith: Arith) -> Self {
+        self.arith = arith;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// 実行した命令を1つずつ書き出す
+    pub fn with_trace(mut self, trace: Tracer) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// 命令ごとの実行回数と呼び出しグラフを集計する
+    pub fn with_profile(mut self) -> Self {
+        self.profile = Some(Profile::new(self.program.ops.len()));
+        self
+    }
+
+    /// フラグが立つと次の命令の前で止まる。シグナルで中断するのに使う
+    pub fn with_interrupt(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    /// 実行した命令数がstepsに達したら止まる
+    pub fn with_pause_at(mut self, steps: u64) -> Self {
+        self.pause_at = Some(steps);
+        self
+    }
+
+    /// 1命令ごとに実行前の状態へ戻すための記録を残す
+    pub fn with_history(mut self) -> Self {
+        self.history = Some(History::default());
+        self
+    }
+
+    /// 拡張命令を実行し、copyとslideの引数を拡張の意味で扱う
+    /// - dumps、dumph: スタックとヒープの中身をdumpに1行で書き出す
+    /// - copy n（nが負）: スタックの底から-n番目（1 indexed）を積む
+    /// - slide n（nがスタックの深さ以上）: 先頭以外をすべて捨てる。nが負なら何もしない
+    ///
+    /// 拡張しない場合、これらはいずれも実行時エラーになる
+    pub fn with_extensions(mut self, dump: impl Write + 'static) -> Self {
+        self.dump = Some(Dump(Box::new(dump)));
+        self
+    }
+
+    /// ``host id``で呼ぶ関数を登録する。同じ番号なら後に登録したものを使う
+    /// スタックの上からarity個を取り出して渡し、返した値を順に積む
+    pub fn with_host_fn(
+        mut self,
+        id: i64,
+        arity: usize,
+        f: impl FnMut(&[Number]) -> Result<Vec<Number>> + 'static,
+    ) -> Self {
+        let f = Box::new(f);
+        self.hosts.insert(id, Host { arity, f });
+        self
+    }
+
+    /// よく現れる2命令の組を複合命令にまとめて実行する
+    pub fn with_fusion(mut self) -> Self {
+        let program = Rc::get_mut(&mut self.program).expect("the program is not shared yet");
+        optimizer::fuse(program);
+        self.fused = true;
+        self
+    }
+
+    /// 途中で止めた場合は終了していない状態で返るので、`is_exited`で見分ける
     pub fn run(&mut self) -> Result<()> {
-        let mut pc = 0;
-        while pc < self.insts.len() {
-            let inst = self.insts[pc].clone();
-            match inst {
-                Instruction::Push(n) => {
-                    self.stack.push(n);
-                }
-                Instruction::Dup => {
-                    // if let Some(n) = self.stack.last() {
-                    //     self.stack.push(*n);
-                    // }
-                    let x = self.stack[self.stack.len() - 1];
-                    self.stack.push(x);
-                }
-                Instruction::Copy(n) => {
-                    // ケツからn番目（0 indexed）
-                    let v = self.stack[self.stack.len() - (n as usize + 1)];
-                    self.stack.push(v);
-                }
-                Instruction::Swap => {
-                    let x = self.pop()?;
-                    let y = self.pop()?;
-                    self.stack.push(x);
-                    self.stack.push(y);
-                }
-                Instruction::Discard => {
-                    let _ = self.stack.pop();
+        // 命令列を借用したままスタックなどを書き換えるため、Rcを複製しておく
+        let program = Rc::clone(&self.program);
+        let mut res = Ok(Status::Running);
+        if self.is_plain() {
+            // 記録や中断を調べない分だけ速い
+            while let Ok(Status::Running) = res {
+                res = self.tick().and_then(|_| self.exec(&program.ops[self.pc]));
+            }
+        } else {
+            while let Ok(Status::Running) = res {
+                if self.paused() {
+                    break;
                 }
-                Instruction::Slide(n) => {
-                    let x = self.pop()?;
-                    for _ in 0..(n as usize) {
-                        self.pop()?;
+                res = self.advance(&program);
+            }
+        }
+        // エラーで止まった場合もそれまでの出力は書き出しておく
+        self.writer.flush()?;
+        if let Some(trace) = &mut self.trace {
+            trace.flush()?;
+        }
+        res.map(|_| ()).map_err(|e| self.diagnose(e))
+    }
+
+    /// JITコンパイルして最後まで実行する
+    /// `jit` featureが無効な場合や、上限やトレースなどを設定した場合は`run`と同じ
+    pub fn run_jit(&mut self) -> Result<()> {
+        #[cfg(all(feature = "jit", not(feature = "bignum")))]
+        {
+            if crate::jit::supports(self) {
+                return crate::jit::run(self);
+            }
+        }
+        self.run()
+    }
+
+    /// 1命令だけ実行する
+    pub fn step(&mut self) -> Result<Status> {
+        if self.exited {
+            return Ok(Status::Exited);
+        }
+        let program = Rc::clone(&self.program);
+        let res = self.advance(&program);
+        self.writer.flush()?;
+        res.map_err(|e| self.diagnose(e))
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// 次に実行する命令の位置
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn stack(&self) -> &[Number] {
+        &self.stack
+    }
+
+    pub fn heap(&self) -> &HashMap<Number, Number> {
+        &self.heap
+    }
+
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    pub fn is_exited(&self) -> bool {
+        self.exited
+    }
+
+    /// 実行した命令数
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// 実行状態を保存する
+    pub fn snapshot(&self) -> Snapshot {
+        let mut heap: Vec<(Number, Number)> = self
+            .heap
+            .iter()
+            .map(|(a, v)| (a.clone(), v.clone()))
+            .collect();
+        heap.sort();
+        Snapshot {
+            program: self.fingerprint,
+            optimized: self.fused,
+            pc: self.pc,
+            steps: self.steps,
+            written: self.written,
+            stack: self.stack.clone(),
+            heap,
+            call_stack: self.call_stack.clone(),
+            input: self.reader.pending().to_vec(),
+        }
+    }
+
+    /// 保存した実行状態から再開できるようにする
+    /// 保存してあった入力の読み残しは、このVMの入力より先に読む
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<()> {
+        // 最適化の有無が違うと命令列も違うので、ハッシュより先に調べて理由を示す
+        if snapshot.optimized != self.fused {
+            let (taken, now) = if snapshot.optimized {
+                ("optimized", "as written")
+            } else {
+                ("as written", "optimized")
+            };
+            return Err(anyhow::anyhow!(
+                "the snapshot was taken with the program {}, but it is now run {}. resume with the same optimization setting.",
+                taken,
+                now
+            ));
+        }
+        if snapshot.program != self.fingerprint {
+            return Err(anyhow::anyhow!(
+                "the snapshot was taken from a different program."
+            ));
+        }
+        let len = self.program.ops.len();
+        if snapshot.pc >= len || snapshot.call_stack.iter().any(|pc| *pc >= len) {
+            return Err(anyhow::anyhow!(
+                "corrupted snapshot file: position out of range."
+            ));
+        }
+        self.pc = snapshot.pc;
+        self.steps = snapshot.steps;
+        self.written = snapshot.written;
+        self.stack = snapshot.stack;
+        self.heap = snapshot.heap.into_iter().collect();
+        self.call_stack = snapshot.call_stack;
+        self.exited = false;
+        self.reader.unread(&snapshot.input);
+        Ok(())
+    }
+
+    /// `with_history`を指定した場合の記録
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// 最後に実行した命令の実行前に戻る。記録がなければfalse
+    /// 出力は取り消せないが、読み込んだ入力は次に読む入力の前に戻す
+    pub fn step_back(&mut self) -> bool {
+        let record = match self.history.as_mut().and_then(|h| h.pop()) {
+            Some(record) => record,
+            None => return false,
+        };
+        for (address, old) in record.heap.into_iter().rev() {
+            match old {
+                Some(value) => self.heap.insert(address, value),
+                None => self.heap.remove(&address),
+            };
+        }
+        // 命令は取り出しうる要素より下を書き換えていない
+        self.stack.truncate(record.depth - record.top.len());
+        self.stack.extend(record.top);
+        self.call_stack.truncate(record.calls);
+        if let (true, Some(ret)) = (self.call_stack.len() < record.calls, record.call_top) {
+            self.call_stack.push(ret);
+        }
+        self.reader.unread(&record.input);
+        self.pc = record.pc;
+        self.steps = record.steps;
+        self.written = record.written;
+        self.exited = record.exited;
+        true
+    }
+
+    /// `with_profile`を指定した場合の集計
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// 実行中の命令の位置をエラーに付加する
+    /// 上限超過は終了コードを決められるよう、型を保ったまま返す
+    pub(crate) fn diagnose(&self, e: anyhow::Error) -> anyhow::Error {
+        if e.is::<LimitExceeded>() {
+            return e;
+        }
+        match &self.source {
+            Some((src_code, notation, spans)) if self.pc < spans.len() => {
+                let diag = Diagnostic::new(e.to_string(), spans[self.pc]).with_notation(*notation);
+                anyhow::anyhow!(diag.render(src_code))
+            }
+            _ => e,
+        }
+    }
+
+    /// 呼び出し元が増えてもインライン展開されるようにしておく
+    /// 展開されないと命令ごとの呼び出しが重く、ループの多いプログラムで2倍以上遅くなる
+    #[inline(always)]
+    pub(crate) fn exec(&mut self, op: &Op) -> Result<Status> {
+        match op {
+            Op::Push(n) => {
+                self.reserve()?;
+                self.stack.push(n.clone());
+            }
+            Op::Dup => {
+                self.reserve()?;
+                let x = self
+                    .stack
+                    .last()
+                    .context("cannot duplicate the top of the empty stack.")?
+                    .clone();
+                self.stack.push(x);
+            }
+            Op::Copy(n) => {
+                self.reserve()?;
+                // ケツからn番目（0 indexed）
+                let v = match (*n as usize)
+                    .checked_add(1)
+                    .and_then(|i| self.stack.len().checked_sub(i))
+                    .and_then(|i| self.stack.get(i))
+                {
+                    Some(v) => v.clone(),
+                    None => self.copy_from_bottom(*n)?,
+                };
+                self.stack.push(v);
+            }
+            Op::Swap => {
+                let x = self.pop()?;
+                let y = self.pop()?;
+                self.stack.push(x);
+                self.stack.push(y);
+            }
+            Op::Discard => {
+                let _ = self.stack.pop();
+            }
+            Op::Slide(n) => {
+                let x = self.pop()?;
+                match usize::try_from(*n) {
+                    Ok(n) if n <= self.stack.len() => {
+                        self.stack.truncate(self.stack.len() - n);
                     }
-                    self.stack.push(x);
-                }
-                Instruction::Add => {
-                    let r = self.pop()?;
-                    let l = self.pop()?;
-                    self.stack.push(l + r);
-                }
-                Instruction::Sub => {
-                    let r = self.pop()?;
-                    let l = self.pop()?;
-                    self.stack.push(l - r);
-                }
-                Instruction::Mul => {
-                    let r = self.pop()?;
-                    let l = self.pop()?;
-                    self.stack.push(l * r);
+                    _ => self.slide_all(*n)?,
                 }
-                Instruction::Div => {
-                    let r = self.pop()?;
-                    let l = self.pop()?;
-                    self.stack.push(l / r);
-                }
-                Instruction::Mod => {
-                    let r = self.pop()?;
-                    let l = self.pop()?;
-                    self.stack.push(l % r);
-                }
-                Instruction::HeapWrite => {
-                    let value = self.pop()?;
-                    let address = self.pop()?;
-                    *self.heap.entry(address as u64).or_insert(value) = value;
-                }
-                Instruction::HeapRead => {
-                    let address = self.pop()?;
-                    let value = self
-                        .heap
-                        .get(&(address as u64))
-                        .context("cannot read an uninitialized heap position.")?;
-                    self.stack.push(*value);
+                self.stack.push(x);
+            }
+            Op::Add => self.binop(BinOp::Add)?,
+            Op::Sub => self.binop(BinOp::Sub)?,
+            Op::Mul => self.binop(BinOp::Mul)?,
+            Op::Div => self.binop(BinOp::Div)?,
+            Op::Mod => self.binop(BinOp::Mod)?,
+            Op::HeapWrite => {
+                let value = self.pop()?;
+                let address = self.pop()?;
+                self.store(address, value)?;
+            }
+            Op::HeapRead => {
+                let address = self.pop()?;
+                let value = self
+                    .heap
+                    .get(&address)
+                    .context("cannot read an uninitialized heap position.")?;
+                self.stack.push(value.clone());
+            }
+            // ラベルの位置はすでに解決しているので何もしない
+            Op::Label => (),
+            Op::Call(dest) => {
+                if let Some(max) = self.limits.call_stack {
+                    if self.call_stack.len() >= max {
+                        return Err(LimitExceeded::CallStack(max).into());
+                    }
                 }
-                // ラベルの位置はすでに調べているので何もしない
-                Instruction::Label(_) => (),
-                Instruction::Call(label) => {
-                    self.call_stack.push(pc);
-                    pc = self.resolve_label(&label)?;
+                self.call_stack.push(self.pc + 1);
+                self.pc = *dest;
+                return Ok(Status::Running);
+            }
+            Op::Jump(dest) => {
+                self.pc = *dest;
+                return Ok(Status::Running);
+            }
+            Op::JumpZero(dest) => {
+                let x = self.pop()?;
+                if x.is_zero() {
+                    self.pc = *dest;
+                    return Ok(Status::Running);
                 }
-                Instruction::Jump(label) => {
-                    pc = self.resolve_label(&label)?;
+            }
+            Op::JumpNeg(dest) => {
+                let x = self.pop()?;
+                if x.is_negative() {
+                    self.pc = *dest;
+                    return Ok(Status::Running);
                 }
-                Instruction::JumpZero(label) => {
-                    let x = self.pop()?;
-                    if x == 0 {
-                        pc = self.resolve_label(&label)?;
-                    }
+            }
+            Op::Return => match self.call_stack.pop() {
+                Some(x) => {
+                    self.pc = x;
+                    return Ok(Status::Running);
                 }
-                Instruction::JumpNeg(label) => {
-                    let x = self.pop()?;
-                    if x < 0 {
-                        pc = self.resolve_label(&label)?;
+                _ => return Err(anyhow::anyhow!("cannot return from the out of subroutine.")),
+            },
+            Op::Exit => {
+                self.exited = true;
+                return Ok(Status::Exited);
+            }
+            Op::CharOut => {
+                let x = self.pop()?;
+                match self.encoding {
+                    Encoding::Utf8 => {
+                        let c = to_char(&x)?;
+                        self.write(c.encode_utf8(&mut [0; 4]).as_bytes())?;
                     }
+                    Encoding::Bytes => self.write(&[x.low_byte()])?,
                 }
-                Instruction::Return => match self.call_stack.pop() {
-                    Some(x) => pc = x,
-                    _ => return Err(anyhow::anyhow!("cannot return from the out of subroutine.")),
-                },
-                Instruction::Exit => {
-                    return Ok(());
-                }
-                Instruction::CharOut => {
-                    let x = self.pop()?;
-                    let mut writer = BufWriter::new(io::stdout());
-                    // ASCIIコードとみなす
-                    let x = x as u8;
-                    writer.write(&[x])?;
-                    writer.flush()?;
-                }
-                Instruction::NumOut => {
-                    let x = self.pop()?;
-                    let x = x.to_string();
-                    let mut writer = BufWriter::new(io::stdout());
-                    writer.write(x.as_bytes())?;
-                    writer.flush()?;
+            }
+            Op::NumOut => {
+                let x = self.pop()?;
+                let x = x.to_string();
+                self.write(x.as_bytes())?;
+            }
+            Op::CharIn => {
+                let address = self.pop()?;
+                match self.read_char()? {
+                    Some(c) => self.store(address, Number::from(c as i64))?,
+                    None => self.store_eof(address)?,
                 }
-                Instruction::CharIn => {
-                    let mut buf = String::new();
-                    io::stdin().read_line(&mut buf)?;
-                    let buf = buf.trim_end(); // 末尾の改行を除去
-                    let buf = buf.as_bytes();
-
-                    let address = self.pop()?;
-                    let n = buf[0] as i64;
-                    *self.heap.entry(address as u64).or_insert(n) = n;
+            }
+            Op::NumIn => {
+                let address = self.pop()?;
+                match self.read_line()? {
+                    Some(buf) => {
+                        // 前後の空白と末尾の改行を除去
+                        let buf = buf.trim();
+                        let n = buf
+                            .parse()
+                            .with_context(|| format!("invalid number input: {:?}", buf))?;
+                        self.store(address, n)?;
+                    }
+                    None => self.store_eof(address)?,
                 }
-                Instruction::NumIn => {
-                    let mut buf = String::new();
-                    io::stdin().read_line(&mut buf)?;
-                    let buf = buf.trim_end(); // 末尾の改行を除去
-
-                    let address = self.pop()?;
-                    let n = buf.parse()?;
-                    *self.heap.entry(address as u64).or_insert(n) = n;
+            }
+            Op::DumpStack => {
+                let items: Vec<String> = self.stack.iter().map(|n| n.to_string()).collect();
+                self.dump(format!("stack: [{}]", items.join(", ")), op)?;
+            }
+            Op::DumpHeap => {
+                let mut entries: Vec<_> = self.heap.iter().collect();
+                entries.sort();
+                let entries: Vec<String> = entries
+                    .into_iter()
+                    .map(|(address, value)| format!("{}: {}", address, value))
+                    .collect();
+                self.dump(format!("heap: {{{}}}", entries.join(", ")), op)?;
+            }
+            Op::HostCall(id) => self.host_call(*id)?,
+            Op::End => {
+                return Err(anyhow::anyhow!(
+                    "exit command must be done in the last of Whitespace program."
+                ));
+            }
+            Op::Undefined(label) => {
+                return Err(anyhow::anyhow!("label is not found. label name: {}", label));
+            }
+            // 複合命令は2命令目に進めてから残りを実行し、エラーはその位置で報告する
+            Op::PushArith(op, n) => {
+                self.reserve()?;
+                self.next()?;
+                let l = self.pop()?;
+                let x = Number::apply(*op, &l, n, &self.arith)?;
+                self.stack.push(x);
+            }
+            Op::PushLoad(address) => {
+                self.reserve()?;
+                self.next()?;
+                let value = self
+                    .heap
+                    .get(address)
+                    .context("cannot read an uninitialized heap position.")?;
+                self.stack.push(value.clone());
+            }
+            Op::DupJumpZero(dest) | Op::DupJumpNeg(dest) => {
+                self.reserve()?;
+                let x = self
+                    .stack
+                    .last()
+                    .context("cannot duplicate the top of the empty stack.")?;
+                let taken = match op {
+                    Op::DupJumpZero(_) => x.is_zero(),
+                    _ => x.is_negative(),
+                };
+                self.next()?;
+                if taken {
+                    self.pc = *dest;
+                    return Ok(Status::Running);
                 }
             }
+        }
+
+        self.pc += 1;
+        Ok(Status::Running)
+    }
+
+    /// トレース、プロファイル、中断、逆実行の記録のいずれも設定していない
+    pub(crate) fn is_plain(&self) -> bool {
+        self.trace.is_none()
+            && self.profile.is_none()
+            && self.history.is_none()
+            && self.interrupt.is_none()
+            && self.pause_at.is_none()
+    }
+
+    #[inline]
+    fn paused(&self) -> bool {
+        self.pause_at.is_some_and(|steps| self.steps >= steps)
+            || self
+                .interrupt
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// 1命令実行し、トレースやプロファイルを有効にしていれば記録する
+    /// `run`の速い経路を膨らませないよう、展開しない
+    #[inline(never)]
+    fn advance(&mut self, program: &Program) -> Result<Status> {
+        if self.history.is_some() {
+            let record = self.record(&program.ops[self.pc]);
+            if let Some(history) = &mut self.history {
+                history.push(record);
+            }
+        }
+        self.tick()?;
+        let pc = self.pc;
+        let res = self.exec(&program.ops[pc]);
+        if let Some(trace) = &mut self.trace {
+            let error = res.as_ref().err();
+            trace.record(
+                self.steps,
+                pc,
+                &program.ops[pc],
+                &self.stack,
+                self.call_stack.len(),
+                error,
+            )?;
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc, &program.ops[pc], res.is_ok());
+        }
+        res
+    }
 
-            pc += 1;
+    /// opを実行する前の状態に戻すための記録
+    fn record(&self, op: &Op) -> Record {
+        let k = match op {
+            Op::HostCall(id) => self.hosts.get(id).map_or(0, |host| host.arity),
+            _ => history::consumed(op),
+        };
+        let k = k.min(self.stack.len());
+        Record {
+            pc: self.pc,
+            steps: self.steps,
+            written: self.written,
+            exited: self.exited,
+            depth: self.stack.len(),
+            top: self.stack[self.stack.len() - k..].to_vec(),
+            calls: self.call_stack.len(),
+            call_top: self.call_stack.last().copied(),
+            heap: vec![],
+            input: vec![],
         }
+    }
 
-        Err(anyhow::anyhow!(
-            "exit command must be done in the last of Whitespace program."
-        ))
+    /// 1文字読んでコードポイントを返す。入力が尽きていればNone
+    /// 入力を促す出力が見えるよう、読み込む前に書き出しておく
+    fn read_char(&mut self) -> Result<Option<u32>> {
+        self.writer.flush()?;
+        let buf = runtime_rs::io::read_char(&mut self.reader, self.encoding)?;
+        if let Some(trace) = &mut self.trace {
+            trace.input(if buf.is_empty() { None } else { Some(&buf) });
+        }
+        if let Some(history) = &mut self.history {
+            history.input(&buf);
+        }
+        runtime_rs::io::decode_char(&buf, self.encoding)
     }
 
-    fn find_labels(insts: &Vec<Instruction>) -> HashMap<String, u64> {
-        let mut labels = HashMap::new();
-        for (i, inst) in insts.iter().enumerate() {
-            match inst {
-                Instruction::Label(name) => {
-                    // ラベル名がだぶった場合は先に登録したほうを優先する
-                    labels.entry(name.clone()).or_insert(i as u64);
-                }
-                _ => (),
+    /// 改行まで読む。CharInと同じ入力から読むので、読み残しはそのまま次の入力になる
+    fn read_line(&mut self) -> Result<Option<String>> {
+        self.writer.flush()?;
+        let mut buf = String::new();
+        let eof = self.reader.read_line(&mut buf)? == 0;
+        if let Some(trace) = &mut self.trace {
+            trace.input(if eof { None } else { Some(buf.as_bytes()) });
+        }
+        if let Some(history) = &mut self.history {
+            history.input(buf.as_bytes());
+        }
+        Ok(if eof { None } else { Some(buf) })
+    }
+
+    fn store_eof(&mut self, address: Number) -> Result<()> {
+        let n = match self.eof {
+            Eof::MinusOne => Number::from(-1),
+            Eof::Zero => Number::from(0),
+            Eof::Keep => return Ok(()),
+            Eof::Error => return Err(anyhow::anyhow!("reached the end of input.")),
+        };
+        self.store(address, n)
+    }
+
+    /// 複合命令の2命令目に進む
+    fn next(&mut self) -> Result<()> {
+        self.pc += 1;
+        self.tick()
+    }
+
+    /// 命令数と実行時間の上限を調べる
+    /// 時刻の取得は重いので、実行時間は一定の命令数ごとに調べる
+    #[inline]
+    fn tick(&mut self) -> Result<()> {
+        self.steps += 1;
+        if let Some(max) = self.limits.steps {
+            if self.steps > max {
+                return Err(LimitExceeded::Steps(max).into());
+            }
+        }
+        if let Some(max) = self.limits.time {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if self.steps.is_multiple_of(1024) && started.elapsed() > max {
+                return Err(LimitExceeded::Time(max).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// 範囲外のcopy。拡張していれば負のnを底からの位置とみなす
+    #[cold]
+    fn copy_from_bottom(&self, n: i64) -> Result<Number> {
+        let v = match self.dump {
+            Some(_) if n < 0 => usize::try_from(n.unsigned_abs() - 1)
+                .ok()
+                .and_then(|i| self.stack.get(i)),
+            _ => None,
+        };
+        let v = v.with_context(|| format!("cannot copy the {}th item of the stack.", n))?;
+        Ok(v.clone())
+    }
+
+    /// 残りの要素より多いか負の数のslide。先頭はすでに取り出してある
+    #[cold]
+    fn slide_all(&mut self, n: i64) -> Result<()> {
+        match (self.dump.is_some(), n < 0) {
+            (true, true) => (),
+            (true, false) => self.stack.clear(),
+            (false, true) => {
+                return Err(anyhow::anyhow!(
+                    "cannot slide a negative number of items: {}.",
+                    n
+                ))
+            }
+            (false, false) => {
+                self.stack.clear();
+                return Err(anyhow::anyhow!("cannot pop from the empty stack."));
+            }
+        }
+        Ok(())
+    }
+
+    fn host_call(&mut self, id: i64) -> Result<()> {
+        let host = self
+            .hosts
+            .get_mut(&id)
+            .with_context(|| format!("host function {} is not registered.", id))?;
+        let len = self.stack.len();
+        let depth = len.checked_sub(host.arity).with_context(|| {
+            format!(
+                "host function {} needs {} argument(s), but the stack has only {}.",
+                id, host.arity, len
+            )
+        })?;
+        let args = self.stack.split_off(depth);
+        let results = (host.f)(&args).with_context(|| format!("host function {} failed", id))?;
+        for x in results.into_iter() {
+            self.reserve()?;
+            self.stack.push(x);
+        }
+        Ok(())
+    }
+
+    /// 拡張命令の出力。プログラムの出力との順序を保つため先に書き出しておく
+    fn dump(&mut self, line: String, op: &Op) -> Result<()> {
+        let dump = match &mut self.dump {
+            Some(Dump(dump)) => dump,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "the extension instruction is not enabled: {}",
+                    op
+                ))
             }
+        };
+        self.writer.flush()?;
+        writeln!(dump, "{}", line)?;
+        dump.flush()?;
+        Ok(())
+    }
+
+    /// スタックに1つ積めるか調べる
+    #[inline]
+    fn reserve(&self) -> Result<()> {
+        if let Some(max) = self.limits.stack {
+            if self.stack.len() >= max {
+                return Err(LimitExceeded::Stack(max).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, address: Number, value: Number) -> Result<()> {
+        if let Some(max) = self.limits.heap {
+            if self.heap.len() >= max && !self.heap.contains_key(&address) {
+                return Err(LimitExceeded::Heap(max).into());
+            }
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.heap_write(&address, &value);
         }
-        labels
+        if let Some(history) = &mut self.history {
+            history.heap_write(address.clone(), self.heap.get(&address).cloned());
+        }
+        self.heap.insert(address, value);
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(max) = self.limits.output {
+            if self.written + buf.len() as u64 > max {
+                return Err(LimitExceeded::Output(max).into());
+            }
+        }
+        self.written += buf.len() as u64;
+        self.writer.write_all(buf)?;
+        if let Some(trace) = &mut self.trace {
+            trace.output(buf);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn binop(&mut self, op: BinOp) -> Result<()> {
+        let r = self.pop()?;
+        let l = self.pop()?;
+        let n = Number::apply(op, &l, &r, &self.arith)?;
+        self.stack.push(n);
+        Ok(())
     }
 
-    fn pop(&mut self) -> Result<i64> {
+    fn pop(&mut self) -> Result<Number> {
         let x = self
             .stack
             .pop()
             .context("cannot pop from the empty stack.")?;
         Ok(x)
     }
+}
+
+/// 入力を読み込んだ分だけ自前のバッファに移して持つ
+/// 読み込み済みでまだ使っていない入力をスナップショットに含めるため
+#[derive(Debug)]
+struct Input<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> Input<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    fn pending(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// 次に読む入力の前に戻す
+    fn unread(&mut self, bytes: &[u8]) {
+        let mut buf = bytes.to_vec();
+        buf.extend_from_slice(self.pending());
+        self.buf = buf;
+        self.pos = 0;
+    }
+}
+
+impl<R: BufRead> Read for Input<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let data = self.fill_buf()?;
+        let n = data.len().min(out.len());
+        out[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Input<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            let data = self.inner.fill_buf()?;
+            self.buf.clear();
+            self.buf.extend_from_slice(data);
+            self.pos = 0;
+            let n = data.len();
+            self.inner.consume(n);
+        }
+        Ok(self.pending())
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.buf.len());
+    }
+}
+
+/// Unicodeのスカラー値でなければ実行時エラー
+/// i64に収まらない数（多倍長整数の場合）も同じエラーにする
+fn to_char(n: &Number) -> Result<char> {
+    match n.to_i64() {
+        Some(n) => runtime_rs::io::to_char(n),
+        None => Err(anyhow::anyhow!("invalid character code: {}.", n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, io, path::Path};
+
+    use super::*;
+    use crate::{assembler, compiler::Compiler, limits::Limits};
+
+    /// テストから中身を読めるよう共有する出力
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// limitバイト書いた後はエラーを返す出力
+    struct Limited {
+        buf: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if self.buf.len() >= self.limit {
+                return Err(io::Error::other("output limit"));
+            }
+            let n = data.len().min(self.limit - self.buf.len());
+            self.buf.extend_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn load(path: &Path) -> Vec<Instruction> {
+        let src = fs::read_to_string(path).unwrap();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("wsa") => assembler::parse(&src).unwrap(),
+            _ => Compiler::new(src).compile().unwrap(),
+        }
+    }
+
+    fn run(insts: Vec<Instruction>, input: &str) -> String {
+        run_with(insts, input, Eof::default())
+    }
+
+    fn run_with(insts: Vec<Instruction>, input: &str, eof: Eof) -> String {
+        let mut output = vec![];
+        VM::new(insts, input.as_bytes(), &mut output)
+            .with_eof(eof)
+            .run()
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn examples() {
+        let cases: HashMap<&str, (&str, &str)> = vec![
+            ("hello.ws", ("", "Hello World\n")),
+            (
+                "fib.ws",
+                (
+                    "10\n",
+                    "How many? 1\n1\n2\n3\n5\n8\n13\n21\n34\n55\n89\n144\n",
+                ),
+            ),
+            ("fact.ws", ("5\n", "Enter a number: 5! = 120\r\n")),
+            ("count.wsa", ("", "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n")),
+        ]
+        .into_iter()
+        .collect();
+
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if name == "forever_a.ws" {
+                continue;
+            }
+            let (input, expect) = cases
+                .get(name)
+                .unwrap_or_else(|| panic!("no expected output for {}", name));
+            assert_eq!(*expect, run(load(&path), input), "{}", name);
+        }
+    }
+
+    #[test]
+    fn forever() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/forever_a.ws");
+        let mut output = Limited {
+            buf: vec![],
+            limit: 100,
+        };
+        let res = VM::new(load(&path), io::empty(), &mut output).run();
+        assert!(res.is_err());
+        assert_eq!(vec![b'A'; 100], output.buf);
+    }
+
+    /// 入力を1バイトずつそのまま出力する
+    const CAT: &str = "
+        loop:
+            push 0
+            inc
+            push 0
+            load
+            dup
+            jn end
+            outc
+            jmp loop
+        end:
+            exit
+    ";
+
+    #[test]
+    fn char_in() {
+        let insts = assembler::parse(CAT).unwrap();
+        let input = "ab\n\ncd";
+        assert_eq!(input, run_with(insts, input, Eof::MinusOne));
+    }
+
+    #[test]
+    fn encodings() {
+        let cat = assembler::parse(CAT).unwrap();
+        let input = "あé\n𝄞";
+        assert_eq!(input, run_with(cat.clone(), input, Eof::MinusOne));
+
+        let run_bytes = |insts: Vec<Instruction>, input: &[u8], encoding| {
+            let mut output = vec![];
+            let res = VM::new(insts, input, &mut output)
+                .with_eof(Eof::MinusOne)
+                .with_encoding(encoding)
+                .run();
+            res.map(|_| output).map_err(|e| e.to_string())
+        };
+        // 1バイトずつなら不正なUTF-8もそのまま通す
+        let bytes = [0xe3, 0x81, b'x', 0xff];
+        assert_eq!(
+            Ok(bytes.to_vec()),
+            run_bytes(cat.clone(), &bytes, Encoding::Bytes)
+        );
+        // 途中で切れた文字は、続かなかったバイトを次の文字として残してエラー
+        assert_eq!(
+            Err("invalid UTF-8 input: [e3, 81]".to_owned()),
+            run_bytes(cat, &bytes, Encoding::Utf8)
+        );
+
+        let out = assembler::parse("push 12354\noutc\npush 55296\noutc\nexit").unwrap();
+        assert_eq!(
+            Err("invalid character code: 55296.".to_owned()),
+            run_bytes(out.clone(), b"", Encoding::Utf8)
+        );
+        assert_eq!(Ok(vec![0x42, 0x00]), run_bytes(out, b"", Encoding::Bytes));
+    }
+
+    #[test]
+    fn extensions() {
+        let src = "
+            push 1
+            push 2
+            push 3
+            slide -1
+            dumps
+            copy -1
+            copy -3
+            push 7
+            push 70
+            store
+            push -1
+            push 10
+            store
+            dumph
+            slide 10
+            dumps
+            outn
+            exit
+        ";
+        let insts = assembler::parse(src).unwrap();
+        let dump = Shared::default();
+        let mut output = vec![];
+        VM::new(insts.clone(), "".as_bytes(), &mut output)
+            .with_extensions(dump.clone())
+            .run()
+            .unwrap();
+        assert_eq!(b"3", output.as_slice());
+        assert_eq!(
+            "stack: [1, 2, 3]\nheap: {-1: 10, 7: 70}\nstack: [3]\n",
+            String::from_utf8(dump.0.borrow().clone()).unwrap()
+        );
+
+        // 拡張しなければ実行時エラー
+        let err = |src: &str| {
+            let insts = assembler::parse(src).unwrap();
+            let res = VM::new(insts, "".as_bytes(), io::sink()).run();
+            res.unwrap_err().to_string()
+        };
+        assert_eq!(
+            "the extension instruction is not enabled: dumps",
+            err("dumps")
+        );
+        assert_eq!(
+            "cannot copy the -1th item of the stack.",
+            err("push 1\ncopy -1")
+        );
+        assert_eq!(
+            "cannot pop from the empty stack.",
+            err("push 1\npush 2\nslide 2")
+        );
+        assert_eq!(
+            "cannot slide a negative number of items: -1.",
+            err("push 1\nslide -1")
+        );
+    }
+
+    #[test]
+    fn host_calls() {
+        let src = "
+            push 3
+            push 4
+            host 1
+            outn
+            push 32
+            outc
+            outn
+            host 0
+            outn
+            exit
+        ";
+        let insts = assembler::parse(src).unwrap();
+        let mut calls = 0;
+        let vm = |insts| {
+            VM::new(insts, "".as_bytes(), vec![])
+                .with_host_fn(0, 0, move |_| {
+                    calls += 1;
+                    Ok(vec![Number::from(calls)])
+                })
+                .with_host_fn(1, 2, |args| {
+                    let (x, y) = (args[0].to_i64().unwrap(), args[1].to_i64().unwrap());
+                    Ok(vec![Number::from(x - y), Number::from(x * y)])
+                })
+                .with_host_fn(2, 0, |_| Err(anyhow::anyhow!("no such key.")))
+        };
+        let mut ok = vm(insts.clone()).with_history();
+        ok.run().unwrap();
+        assert_eq!(b"12 -11", ok.writer.get_ref().as_slice());
+        let mut jit = vm(insts.clone());
+        jit.run_jit().unwrap();
+        assert_eq!(b"12 -11", jit.writer.get_ref().as_slice());
+        // 取り出した引数も元に戻る
+        while ok.step_back() {
+            if ok.pc == 2 {
+                break;
+            }
+        }
+        assert_eq!(&[Number::from(3), Number::from(4)], ok.stack());
+
+        let err = |src: &str| {
+            let insts = assembler::parse(src).unwrap();
+            vm(insts).run().unwrap_err().to_string()
+        };
+        assert_eq!("host function 3 is not registered.", err("host 3"));
+        assert_eq!(
+            "host function 1 needs 2 argument(s), but the stack has only 1.",
+            err("push 1\nhost 1")
+        );
+        assert_eq!("host function 2 failed", err("host 2"));
+    }
+
+    #[test]
+    fn num_after_char() {
+        let src = "
+            push 0
+            inc
+            push 1
+            inn
+            push 0
+            load
+            outc
+            push 1
+            load
+            outn
+            exit
+        ";
+        let insts = assembler::parse(src).unwrap();
+        assert_eq!("x42", run(insts, "x 42\nrest"));
+    }
+
+    #[test]
+    fn eof_policies() {
+        let src = "
+            push 0
+            push 7
+            store
+            push 0
+            inc
+            push 0
+            load
+            outn
+            push 0
+            inn
+            push 0
+            load
+            outn
+            exit
+        ";
+        let insts = assembler::parse(src).unwrap();
+        assert_eq!("-1-1", run_with(insts.clone(), "", Eof::MinusOne));
+        assert_eq!("00", run_with(insts.clone(), "", Eof::Zero));
+        assert_eq!("77", run_with(insts.clone(), "", Eof::Keep));
+
+        let mut output = vec![];
+        let err = VM::new(insts, io::empty(), &mut output).run().unwrap_err();
+        assert_eq!("reached the end of input.", err.to_string());
+    }
+
+    fn exceed(src: &str, limits: Limits) -> LimitExceeded {
+        let insts = assembler::parse(src).unwrap();
+        let mut output = vec![];
+        let err = VM::new(insts, io::empty(), &mut output)
+            .with_limits(limits)
+            .run()
+            .unwrap_err();
+        *err.downcast_ref::<LimitExceeded>().unwrap()
+    }
+
+    #[test]
+    fn limits() {
+        let forever = "l:\njmp l\n";
+        let limits = Limits {
+            steps: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(LimitExceeded::Steps(100), exceed(forever, limits));
+
+        let limits = Limits {
+            time: Some(std::time::Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        assert!(matches!(exceed(forever, limits), LimitExceeded::Time(_)));
+
+        let limits = Limits {
+            stack: Some(3),
+            ..Limits::default()
+        };
+        assert_eq!(
+            LimitExceeded::Stack(3),
+            exceed("l:\npush 1\njmp l\n", limits)
+        );
+
+        let limits = Limits {
+            call_stack: Some(5),
+            ..Limits::default()
+        };
+        assert_eq!(LimitExceeded::CallStack(5), exceed("l:\ncall l\n", limits));
+
+        let limits = Limits {
+            heap: Some(2),
+            ..Limits::default()
+        };
+        let src = "push 0\ndup\nstore\npush 0\ndup\nstore\npush 1\ndup\nstore\npush 2\ndup\nstore\nexit\n";
+        assert_eq!(LimitExceeded::Heap(2), exceed(src, limits));
 
-    fn resolve_label(&self, label: &str) -> Result<usize> {
-        let pc = self
-            .labels
-            .get(label)
-            .with_context(|| format!("label is not found. label name: {}", label))?;
-        Ok(*pc as usize)
+        let limits = Limits {
+            output: Some(4),
+            ..Limits::default()
+        };
+        let src = "l:\npush 12\noutn\njmp l\n";
+        assert_eq!(LimitExceeded::Output(4), exceed(src, limits));
     }
 }